@@ -0,0 +1,94 @@
+use std::str::FromStr;
+
+use tonic::Status;
+
+use entity::{
+    doc,
+    mongodb::bson::oid::ObjectId,
+    proto::{Message, Room, Space},
+    Entity, EntityContext,
+};
+
+#[allow(clippy::result_large_err)]
+pub fn object_id(id: &str) -> Result<ObjectId, Status> {
+    ObjectId::from_str(id)
+        .map_err(|_| Status::invalid_argument(format!("{} is not a valid id", id)))
+}
+
+async fn room(ctx: &EntityContext, room_id: &str) -> Result<Room, Status> {
+    Room::find_one(ctx, doc! {"_id": object_id(room_id)?}, None)
+        .await
+        .map_err(|err| Status::internal(format!("Failed to find_one room, Report: {:#?}", err)))?
+        .ok_or_else(|| Status::not_found("room not found"))
+}
+
+async fn space(ctx: &EntityContext, space_id: &str) -> Result<Space, Status> {
+    Space::find_one(ctx, doc! {"_id": object_id(space_id)?}, None)
+        .await
+        .map_err(|err| Status::internal(format!("Failed to find_one space, Report: {:#?}", err)))?
+        .ok_or_else(|| Status::not_found("space not found"))
+}
+
+pub async fn message(ctx: &EntityContext, message_id: &str) -> Result<Message, Status> {
+    Message::find_one(ctx, doc! {"_id": object_id(message_id)?}, None)
+        .await
+        .map_err(|err| Status::internal(format!("Failed to find_one message, Report: {:#?}", err)))?
+        .ok_or_else(|| Status::not_found("message not found"))
+}
+
+/// Owner is always a participant, even if not listed in `participants`
+pub async fn room_participant(
+    ctx: &EntityContext,
+    room_id: &str,
+    user_id: &str,
+) -> Result<Room, Status> {
+    let room = room(ctx, room_id).await?;
+    if room.owner != user_id && !room.participants.iter().any(|p| p == user_id) {
+        return Err(Status::permission_denied("Not a participant of the room"));
+    }
+    Ok(room)
+}
+
+pub async fn room_owner(ctx: &EntityContext, room_id: &str, user_id: &str) -> Result<Room, Status> {
+    let room = room(ctx, room_id).await?;
+    if room.owner != user_id {
+        return Err(Status::permission_denied("Not the owner of the room"));
+    }
+    Ok(room)
+}
+
+pub async fn space_participant(
+    ctx: &EntityContext,
+    space_id: &str,
+    user_id: &str,
+) -> Result<Space, Status> {
+    let space = space(ctx, space_id).await?;
+    if space.owner != user_id && !space.participants.iter().any(|p| p == user_id) {
+        return Err(Status::permission_denied("Not a participant of the space"));
+    }
+    Ok(space)
+}
+
+pub async fn space_owner(
+    ctx: &EntityContext,
+    space_id: &str,
+    user_id: &str,
+) -> Result<Space, Status> {
+    let space = space(ctx, space_id).await?;
+    if space.owner != user_id {
+        return Err(Status::permission_denied("Not the owner of the space"));
+    }
+    Ok(space)
+}
+
+pub async fn message_sender(
+    ctx: &EntityContext,
+    message_id: &str,
+    user_id: &str,
+) -> Result<Message, Status> {
+    let message = message(ctx, message_id).await?;
+    if message.sender != user_id {
+        return Err(Status::permission_denied("Not the sender of the message"));
+    }
+    Ok(message)
+}
@@ -0,0 +1,87 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tonic::{Request, Status};
+
+use entity::config::SETTINGS;
+
+lazy_static::lazy_static! {
+    static ref KEYS: Keys = Keys::new(SETTINGS.auth.secret.as_bytes());
+}
+
+struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl Keys {
+    fn new(secret: &[u8]) -> Self {
+        if secret.is_empty() {
+            let mut secret = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            return Self::new(&secret);
+        }
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String, // User id
+    iat: u64,
+    exp: u64,
+}
+
+/// Authenticated caller, injected into request extensions by `check_auth`
+#[derive(Clone)]
+pub struct UserId(pub String);
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Returns the signed access token and its expiration
+pub fn issue_access_token(user_id: &str) -> eyre::Result<(String, u64)> {
+    let iat = now();
+    let exp = iat + SETTINGS.auth.access_ttl;
+    let claims = Claims {
+        sub: user_id.to_string(),
+        iat,
+        exp,
+    };
+    let token = encode(&Header::new(Algorithm::HS256), &claims, &KEYS.encoding)?;
+    Ok((token, exp))
+}
+
+/// Id of the user who made the request, available behind `check_auth`
+#[allow(clippy::result_large_err)]
+pub fn user_id<T>(request: &Request<T>) -> Result<String, Status> {
+    request
+        .extensions()
+        .get::<UserId>()
+        .map(|user| user.0.clone())
+        .ok_or_else(|| Status::unauthenticated("No valid auth token"))
+}
+
+#[allow(clippy::result_large_err)] // Signature is dictated by tonic interceptors
+pub fn check_auth(mut req: Request<()>) -> Result<Request<()>, Status> {
+    let token = req
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("No valid auth token"))?;
+    let claims = decode::<Claims>(token, &KEYS.decoding, &Validation::new(Algorithm::HS256))
+        .map_err(|_| Status::unauthenticated("No valid auth token"))?
+        .claims;
+    req.extensions_mut().insert(UserId(claims.sub));
+    Ok(req)
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, WrapErr};
+use prost::Message as _;
+use rand::RngCore;
+use tonic::transport::{Channel, Endpoint};
+
+use entity::{
+    config::SETTINGS,
+    doc,
+    proto::{
+        bridge_event::Event, bridge_service_client::BridgeServiceClient, Arrival, DeliverRequest,
+        DeliveryStatus, FederatedMessage, Message, MessageEventKind, Room,
+    },
+    Entity, EntityContext,
+};
+
+use crate::{access::object_id, hub::Hub};
+
+/// Delay before subscribing to the node again once its events stream broke
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Bounds the memory spent on deliveries whose status never comes, the oldest are forgotten
+const MAX_PENDING: usize = 65536;
+
+/// Handed over, message and server of every delivery waiting for its status, by packet id
+type Pending = HashMap<Vec<u8>, (Instant, String, String)>;
+
+/// Carries messages between rooms of this server and the participants of other servers
+/// Remote participants are `user@server`, where server is the federation addr of their server
+/// Their rooms are mirrored here, ids are renamed on arrival so that local users lose the suffix
+/// and users of the sending server get it
+#[derive(Clone)]
+pub struct Bridge {
+    ctx: EntityContext,
+    hub: Hub,
+    client: BridgeServiceClient<Channel>,
+    server: String, // Our federation addr, what other servers call us
+    pending: Arc<Mutex<Pending>>,
+    pending_ttl: Duration, // Packets are dropped after it, so are their statuses
+}
+
+impl Bridge {
+    /// Nothing to bridge if federation is disabled
+    pub fn new(ctx: EntityContext, hub: Hub) -> eyre::Result<Option<Self>> {
+        if !SETTINGS.federation.enabled {
+            return Ok(None);
+        }
+        // The node may start after us, the channel connects on first use
+        let channel = Endpoint::from_shared(format!("http://{}", SETTINGS.federation.bridge_addr))
+            .wrap_err("federation.bridge_addr is not a valid endpoint")?
+            .connect_lazy();
+        let server = SETTINGS.federation.addr.to_string();
+        Ok(Some(Self::connect(ctx, hub, channel, server)))
+    }
+
+    /// Bridge to the node on the channel, we are known as `server` to the others
+    pub fn connect(ctx: EntityContext, hub: Hub, channel: Channel, server: String) -> Self {
+        Self {
+            ctx,
+            hub,
+            client: BridgeServiceClient::new(channel),
+            server,
+            pending: Default::default(),
+            pending_ttl: Duration::from_secs(SETTINGS.federation.packet_ttl),
+        }
+    }
+
+    /// Hand the message to the node once for every other server with participants in the room
+    pub async fn deliver(&self, room: &Room, message: &Message) {
+        let servers = std::iter::once(&room.owner)
+            .chain(&room.participants)
+            .filter_map(|user| user.rsplit_once('@'))
+            .map(|(_, server)| server)
+            .filter(|server| *server != self.server)
+            .collect::<HashSet<_>>();
+        if servers.is_empty() {
+            return;
+        }
+        let data = FederatedMessage {
+            message: Some(message.clone()),
+            room: Some(room.clone()),
+        }
+        .encode_to_vec();
+        for server in servers {
+            let mut id = vec![0; 16];
+            rand::thread_rng().fill_bytes(&mut id);
+            self.track(id.clone(), &message.id, server);
+            let request = DeliverRequest {
+                server: server.to_string(),
+                id: id.clone(),
+                data: data.clone(),
+            };
+            if let Err(status) = self.client.clone().deliver(request).await {
+                self.pending.lock().unwrap().remove(&id);
+                eprintln!(
+                    "Failed to deliver message {} to {}, Report: {:#?}",
+                    message.id, server, status
+                );
+            }
+        }
+    }
+
+    /// Wait for the status of the delivery, deliveries nobody answered are forgotten
+    fn track(&self, id: Vec<u8>, message_id: &str, server: &str) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            pending.retain(|_, (sent, _, _)| now.duration_since(*sent) < self.pending_ttl);
+        }
+        if pending.len() >= MAX_PENDING {
+            let oldest = pending
+                .iter()
+                .min_by_key(|(_, (sent, _, _))| *sent)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                pending.remove(&oldest);
+            }
+        }
+        pending.insert(id, (now, message_id.to_string(), server.to_string()));
+    }
+
+    /// Follow the events of the node forever, resubscribing whenever the stream breaks
+    pub async fn run(self) {
+        loop {
+            if let Err(err) = self.follow().await {
+                eprintln!("Failed to follow federation events, Report: {:#?}", err);
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn follow(&self) -> eyre::Result<()> {
+        let mut events = self.client.clone().events(()).await?.into_inner();
+        while let Some(event) = events.message().await? {
+            let result = match event.event {
+                Some(Event::Arrival(arrival)) => self.arrive(arrival).await,
+                Some(Event::Status(status)) => self.delivered(status).await,
+                None => Ok(()),
+            };
+            if let Err(err) = result {
+                eprintln!("Failed to handle federation event, Report: {:#?}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the message in the mirror of its room, the mirror is created on first message
+    /// The node tells the server from the key which signed the packet, servers only send
+    /// messages of their own users
+    pub async fn arrive(&self, arrival: Arrival) -> eyre::Result<()> {
+        let FederatedMessage {
+            message: Some(mut message),
+            room: Some(room),
+        } = FederatedMessage::decode(&*arrival.data).wrap_err("Arrival is not a message")?
+        else {
+            return Err(eyre!("Message from {} is incomplete", arrival.server));
+        };
+        let from = arrival.server.as_str();
+        if from.is_empty() {
+            return Err(eyre!("Message from an unknown server"));
+        }
+        if let Some((_, server)) = message.sender.rsplit_once('@') {
+            if server != from {
+                return Err(eyre!("{} can't send messages of {}", from, message.sender));
+            }
+        }
+        message.sender = self.localize(&message.sender, from);
+        message.id = String::new();
+        message.room_id = self.local_room(room, from, &message.sender).await?;
+        if let Some(thread) = &mut message.thread {
+            thread.participants = thread
+                .participants
+                .iter()
+                .map(|user| self.localize(user, from))
+                .collect();
+        }
+        message.read_by.clear();
+        message.delivered_to.clear();
+        message.id = Message::create(&self.ctx, &message).await?;
+        let sender = message.sender.clone();
+        self.hub.publish(MessageEventKind::Sent, message, &sender);
+        Ok(())
+    }
+
+    /// Id of our room for the one the sending server sent
+    /// Only the server of the room opens its mirror and decides who is in it
+    async fn local_room(&self, room: Room, from: &str, sender: &str) -> eyre::Result<String> {
+        let origin = if room.origin.is_empty() {
+            format!("{}@{}", room.id, from)
+        } else {
+            room.origin.clone()
+        };
+        let participants = room
+            .participants
+            .iter()
+            .map(|user| self.localize(user, from))
+            .collect::<Vec<_>>();
+        let server = origin.rsplit_once('@').map(|(_, server)| server);
+        if server == Some(self.server.as_str()) {
+            // Answer to a room of ours
+            let (room_id, _) = origin.rsplit_once('@').unwrap();
+            let room = Room::find_one(&self.ctx, doc! {"_id": object_id(room_id)?}, None)
+                .await?
+                .ok_or_else(|| eyre!("Room {} is not known", room_id))?;
+            if room.owner != sender && !room.participants.iter().any(|p| p == sender) {
+                return Err(eyre!("{} is not a participant of room {}", sender, room_id));
+            }
+            return Ok(room.id);
+        }
+        if let Some(mirror) = Room::find_one(&self.ctx, doc! {"origin": &origin}, None).await? {
+            // Other servers only post in it, as long as the room server lets their users in
+            if server != Some(from)
+                && mirror.owner != sender
+                && !mirror.participants.iter().any(|p| p == sender)
+            {
+                return Err(eyre!("{} is not a participant of room {}", sender, origin));
+            }
+            if server == Some(from) {
+                Room::update_one(
+                    &self.ctx,
+                    doc! {"_id": object_id(&mirror.id)?},
+                    doc! {"$set": {"participants": participants}},
+                )
+                .await?;
+            }
+            return Ok(mirror.id);
+        }
+        // Nor do they make up a mirror of a room they don't host
+        if server != Some(from) {
+            return Err(eyre!("{} can't open a mirror of room {}", from, origin));
+        }
+        let mirror = Room {
+            name: room.name,
+            owner: self.localize(&room.owner, from),
+            description: room.description,
+            participants,
+            origin,
+            created_at: room.created_at,
+            ..Default::default()
+        };
+        Room::create(&self.ctx, &mirror).await
+    }
+
+    /// Mark the message as delivered to the server and tell the watchers of its room
+    /// The node reports a success only if the receiver signed it
+    async fn delivered(&self, status: DeliveryStatus) -> eyre::Result<()> {
+        let Some((_, message_id, server)) = self.pending.lock().unwrap().remove(&status.id) else {
+            return Ok(());
+        };
+        if !status.success {
+            return Err(eyre!("{} didn't get message {}", server, message_id));
+        }
+        Message::update_one(
+            &self.ctx,
+            doc! {"_id": object_id(&message_id)?},
+            doc! {"$addToSet": {"delivered_to": &server}},
+        )
+        .await?;
+        if let Some(message) =
+            Message::find_one(&self.ctx, doc! {"_id": object_id(&message_id)?}, None).await?
+        {
+            let sender = message.sender.clone();
+            self.hub
+                .publish(MessageEventKind::Delivered, message, &sender);
+        }
+        Ok(())
+    }
+
+    /// User of the sending server as we call it, our own users lose their suffix
+    /// Users of other servers are only taken from room lists, never as senders, see `arrive`
+    fn localize(&self, user: &str, from: &str) -> String {
+        match user.rsplit_once('@') {
+            Some((user, server)) if server == self.server => user.to_string(),
+            Some(_) => user.to_string(),
+            None => format!("{}@{}", user, from),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use entity::proto::{Message, MessageEvent, MessageEventKind};
+
+/// In-process fan-out of message events to `WatchRoom` subscribers
+#[derive(Clone)]
+pub struct Hub {
+    tx: Sender<MessageEvent>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(1024);
+        Self { tx }
+    }
+
+    pub fn publish(&self, kind: MessageEventKind, message: Message, user_id: &str) {
+        // No subscribers is not an error
+        let _ = self.tx.send(MessageEvent {
+            kind: kind.into(),
+            message: Some(message),
+            user_id: user_id.to_string(),
+        });
+    }
+
+    pub fn subscribe(&self) -> Receiver<MessageEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
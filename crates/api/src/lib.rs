@@ -0,0 +1,5 @@
+pub mod access;
+pub mod auth;
+pub mod bridge;
+pub mod hub;
+pub mod services;
@@ -1,6 +1,6 @@
-use tonic::{metadata::MetadataValue, transport::Server, Request, Status};
+use tonic::transport::Server;
 
-pub mod services;
+use api::services;
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -19,12 +19,3 @@ async fn main() -> eyre::Result<()> {
     router.serve(addr).await?;
     Ok(())
 }
-
-fn check_auth(req: Request<()>) -> Result<Request<()>, Status> {
-    let token: MetadataValue<_> = "Bearer some-secret-token".parse().unwrap();
-
-    match req.metadata().get("authorization") {
-        Some(t) if token == t => Ok(req),
-        _ => Err(Status::unauthenticated("No valid auth token")),
-    }
-}
@@ -1,81 +1,114 @@
 use std::{
-    str::FromStr,
+    collections::HashSet,
+    pin::Pin,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use tonic::{codegen::InterceptedService, Request, Response, Status};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    codegen::{futures_core::Stream, InterceptedService},
+    Request, Response, Status,
+};
 
 use entity::{
     doc,
     helpers::{FieldMaskDef, TimestampDef},
     mongodb::bson,
+    pagination::PAGINATOR,
     proto::{
         message,
         message_service_server::{MessageService as IMessageService, MessageServiceServer},
+        MessageEvent, MessageEventKind,
     },
-    Document, Entity, EntityContext, FindOneOptions, FindOptions,
+    Document, Entity, EntityContext,
 };
 
-use crate::check_auth;
+use crate::{
+    access::{self, message_sender, object_id, room_owner, room_participant},
+    auth::{check_auth, user_id},
+    bridge::Bridge,
+    hub::Hub,
+};
 pub struct MessageService {
     ctx: EntityContext,
+    hub: Hub,
+    bridge: Option<Bridge>, // Remote participants get messages through it
 }
 
+type WatchRoomStream = Pin<Box<dyn Stream<Item = Result<MessageEvent, Status>> + Send>>;
+
 impl MessageService {
-    async fn new(ctx: EntityContext) -> Self {
-        Self { ctx }
+    pub async fn new(ctx: EntityContext, hub: Hub, bridge: Option<Bridge>) -> Self {
+        Self { ctx, hub, bridge }
+    }
+
+    /// Shared by message and thread listing, `filter` is narrowed by the request
+    async fn list(
+        &self,
+        user_id: &str,
+        request: entity::proto::ListMessagesRequest,
+        mut filter: Document,
+    ) -> Result<entity::proto::ListMessagesResponse, Status> {
+        room_participant(&self.ctx, &request.room_id, user_id).await?;
+        filter.insert("room_id", &request.room_id);
+        if let Some(from_date) = request.from_date {
+            filter.insert(
+                "created_at.seconds",
+                doc! {"$gte": TimestampDef::from(Some(from_date)).seconds},
+            ); // FUCK YOU MONGO
+        }
+        let page = PAGINATOR
+            .page(
+                entity::proto::Message::COLLECTION,
+                filter,
+                request.page_size,
+                &request.page_token,
+            )
+            .map_err(|err| Status::invalid_argument(format!("{}", err)))?;
+        let projection = Into::<Document>::into(Into::<FieldMaskDef>::into(request.field_mask));
+        let messages =
+            entity::proto::Message::find(&self.ctx, page.filter(), page.options(projection))
+                .await
+                .map_err(|err| {
+                    Status::internal(format!("Failed to find messages, Report: {:#?}", err))
+                })?;
+        let (messages, next_page_token) =
+            page.finish(messages, |message| &message.id)
+                .map_err(|err| {
+                    Status::internal(format!("Failed to paginate messages, Report: {:#?}", err))
+                })?;
+        Ok(entity::proto::ListMessagesResponse {
+            messages,
+            next_page_token,
+        })
     }
 }
 
 #[tonic::async_trait]
 impl IMessageService for MessageService {
+    type WatchRoomStream = WatchRoomStream;
+
     async fn list_messages(
         &self,
         request: Request<entity::proto::ListMessagesRequest>,
     ) -> Result<Response<entity::proto::ListMessagesResponse>, Status> {
-        let body = request.into_inner();
-        let options = FindOptions::builder()
-            .projection(Into::<Document>::into(Into::<FieldMaskDef>::into(
-                body.field_mask,
-            )))
-            .limit(Some(body.page_size as i64))
-            .build();
-        let filter = doc! {"room_id": bson::oid::ObjectId::from_str(&body.room_id).unwrap(),
-        "created_at.seconds": { "$gte": TimestampDef::from(body.from_date).seconds}}; // FUCK YOU MONGO
-        let messages = entity::proto::Message::find(&self.ctx, Some(filter), Some(options))
-            .await
-            .map_err(|err| {
-                Status::internal(format!("Failed to find messages, Report: {:#?}", err))
-            })?;
-        Ok(Response::new(entity::proto::ListMessagesResponse {
-            messages,
-            next_page_token: "".to_string(),
-        }))
+        let user_id = user_id(&request)?;
+        let messages = self.list(&user_id, request.into_inner(), doc! {}).await?;
+        Ok(Response::new(messages))
     }
 
     async fn list_thread_messages(
         &self,
         request: Request<entity::proto::ListThreadMessagesRequest>,
     ) -> Result<Response<entity::proto::ListMessagesResponse>, Status> {
+        let user_id = user_id(&request)?;
         let body = request.into_inner();
         if let Some(req) = body.request {
-            let options = FindOptions::builder()
-                .projection(Into::<Document>::into(Into::<FieldMaskDef>::into(
-                    req.field_mask,
-                )))
-                .limit(Some(req.page_size as i64))
-                .build();
-            let filter = doc! {"room_id": bson::oid::ObjectId::from_str(&req.room_id).unwrap(),
-            "thread_id": bson::oid::ObjectId::from_str(&body.thread_id).unwrap()}; // FUCK YOU MONGO
-            let messages = entity::proto::Message::find(&self.ctx, Some(filter), Some(options))
-                .await
-                .map_err(|err| {
-                    Status::internal(format!("Failed to find messages, Report: {:#?}", err))
-                })?;
-            return Ok(Response::new(entity::proto::ListMessagesResponse {
-                messages,
-                next_page_token: "".to_string(),
-            }));
+            let messages = self
+                .list(&user_id, req, doc! {"thread.id": body.thread_id})
+                .await?;
+            return Ok(Response::new(messages));
         }
         Err(Status::invalid_argument("request is required"))
     }
@@ -84,8 +117,11 @@ impl IMessageService for MessageService {
         &self,
         request: Request<entity::proto::SendMessageRequest>,
     ) -> Result<Response<()>, Status> {
-        if let Some(message) = request.into_inner().message {
-            let message_id = entity::proto::Message::create(&self.ctx, &message)
+        let user_id = user_id(&request)?;
+        if let Some(mut message) = request.into_inner().message {
+            let room = room_participant(&self.ctx, &message.room_id, &user_id).await?;
+            message.sender = user_id;
+            message.id = entity::proto::Message::create(&self.ctx, &message)
                 .await
                 .map_err(|err| {
                     Status::internal(format!(
@@ -96,8 +132,8 @@ impl IMessageService for MessageService {
             if let Some(message::Body::KeysRotation(_)) = message.body {
                 entity::proto::Room::update_one(
                     &self.ctx,
-                    doc! {"_id": bson::oid::ObjectId::from_str(&message.room_id).unwrap()},
-                    doc! {"$push": {"keys_rotation": message_id}},
+                    doc! {"_id": object_id(&message.room_id)?},
+                    doc! {"$push": {"keys_rotation": &message.id}},
                 )
                 .await
                 .map_err(|err| {
@@ -107,6 +143,11 @@ impl IMessageService for MessageService {
                     ))
                 })?;
             }
+            if let Some(bridge) = &self.bridge {
+                bridge.deliver(&room, &message).await;
+            }
+            let sender = message.sender.clone();
+            self.hub.publish(MessageEventKind::Sent, message, &sender);
             return Ok(Response::new(()));
         }
         Err(Status::invalid_argument("message is required"))
@@ -120,28 +161,45 @@ impl IMessageService for MessageService {
         let since_the_epoch = start
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards");
-        let user_id = ""; // TODO: get user_id from context
+        let user_id = user_id(&request)?;
+        let mut message = access::message(&self.ctx, &request.into_inner().message_id).await?;
+        room_participant(&self.ctx, &message.room_id, &user_id).await?;
+        let read_at = since_the_epoch.as_secs() as i64;
         entity::proto::Message::update_one(
             &self.ctx,
-            doc! {"_id": bson::oid::ObjectId::from_str(&request.into_inner().message_id).unwrap()},
-            doc! {"$set": {format!("read_by.{}", user_id): since_the_epoch.as_secs() as i64}},
+            doc! {"_id": object_id(&message.id)?},
+            doc! {"$set": {format!("read_by.{}", user_id): read_at}},
         )
         .await
         .map_err(|err| {
             Status::internal(format!("Failed to acknowledge_message, Report: {:#?}", err))
         })?;
-        Err(Status::invalid_argument("message is required"))
+        message.read_by.insert(user_id.clone(), read_at);
+        self.hub
+            .publish(MessageEventKind::Acknowledged, message, &user_id);
+        Ok(Response::new(()))
     }
 
     async fn update_message(
         &self,
         request: Request<entity::proto::UpdateMessageRequest>,
     ) -> Result<Response<entity::proto::Message>, Status> {
+        let user_id = user_id(&request)?;
         if let Some(message) = request.into_inner().message {
+            let existing = message_sender(&self.ctx, &message.id, &user_id).await?;
+            // Only the content is edited, rotations of the room keys stay as they were sent
+            let (Some(message::Body::Plain(_)), Some(message::Body::Plain(_))) =
+                (&message.body, &existing.body)
+            else {
+                return Err(Status::invalid_argument("Only plain messages are edited"));
+            };
+            let body = bson::to_bson(&message.body).map_err(|err| {
+                Status::internal(format!("Failed to serialize message, Report: {:#?}", err))
+            })?;
             entity::proto::Message::update_one(
                 &self.ctx,
-                doc! {"_id": bson::oid::ObjectId::from_str(&message.id).unwrap()}, // FUCK YOU MONGO
-                doc! {"$set": bson::to_document(&message).unwrap()},
+                doc! {"_id": object_id(&existing.id)?}, // FUCK YOU MONGO
+                doc! {"$set": {"body": body}},
             )
             .await
             .map_err(|err| {
@@ -150,6 +208,12 @@ impl IMessageService for MessageService {
                     message, err
                 ))
             })?;
+            let message = entity::proto::Message {
+                body: message.body,
+                ..existing
+            };
+            self.hub
+                .publish(MessageEventKind::Updated, message.clone(), &user_id);
             return Ok(Response::new(message));
         }
         Err(Status::invalid_argument("message is required"))
@@ -159,23 +223,95 @@ impl IMessageService for MessageService {
         &self,
         request: Request<entity::proto::DeleteMessageRequest>,
     ) -> Result<Response<()>, Status> {
-        let message = entity::proto::Message::delete_one(
-            &self.ctx,
-            doc! {"_id": bson::oid::ObjectId::from_str(&request.into_inner().id).unwrap()},
-        )
-        .await
-        .map_err(|err| Status::internal(format!("Failed to delete_message, Report: {:#?}", err)))?;
-        return Ok(Response::new(message));
+        let user_id = user_id(&request)?;
+        let message = access::message(&self.ctx, &request.into_inner().id).await?;
+        // Room owner moderates the room
+        if message.sender != user_id {
+            room_owner(&self.ctx, &message.room_id, &user_id).await?;
+        }
+        entity::proto::Message::delete_one(&self.ctx, doc! {"_id": object_id(&message.id)?})
+            .await
+            .map_err(|err| {
+                Status::internal(format!("Failed to delete_message, Report: {:#?}", err))
+            })?;
+        self.hub
+            .publish(MessageEventKind::Deleted, message, &user_id);
+        Ok(Response::new(()))
+    }
+
+    async fn watch_room(
+        &self,
+        request: Request<entity::proto::WatchRoomRequest>,
+    ) -> Result<Response<Self::WatchRoomStream>, Status> {
+        let user_id = user_id(&request)?;
+        let room_ids = request.into_inner().room_ids;
+        // Without room ids the user watches every room they are in, including the ones they join later
+        for room_id in &room_ids {
+            room_participant(&self.ctx, room_id, &user_id).await?;
+        }
+        let rooms = room_ids.into_iter().collect::<HashSet<_>>();
+        // Per subscriber stream
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        let mut events = self.hub.subscribe();
+        let ctx = self.ctx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let Some(room_id) = event.message.as_ref().map(|message| &message.room_id)
+                        else {
+                            continue;
+                        };
+                        if !rooms.is_empty() && !rooms.contains(room_id) {
+                            continue;
+                        }
+                        // Membership is checked per event, participants may be removed meanwhile
+                        match room_participant(&ctx, room_id, &user_id).await {
+                            Ok(_) => {
+                                if tx.send(Ok(event)).await.is_err() {
+                                    break; // Subscriber is gone
+                                }
+                            }
+                            // A room the user isn't in, or no more
+                            Err(_) if rooms.is_empty() => {}
+                            Err(status) => {
+                                let _ = tx.send(Err(status)).await;
+                                break;
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Client has to catch up with ListMessages and subscribe again
+                        let _ = tx
+                            .send(Err(Status::data_loss(format!(
+                                "Subscriber lagged behind, {} events skipped",
+                                skipped
+                            ))))
+                            .await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::WatchRoomStream
+        ))
     }
 }
 
 pub async fn svc(
     entity: EntityContext,
+    hub: Hub,
+    bridge: Option<Bridge>,
 ) -> InterceptedService<
     MessageServiceServer<MessageService>,
     fn(Request<()>) -> Result<Request<()>, Status>,
 > {
-    let server = MessageService::new(entity).await;
+    let server = MessageService::new(entity, hub, bridge).await;
 
     MessageServiceServer::with_interceptor(server, check_auth)
 }
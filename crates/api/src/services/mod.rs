@@ -1,11 +1,19 @@
 pub mod message;
 pub mod room;
 pub mod space;
+pub mod user;
 
 pub async fn services(server: &mut tonic::transport::Server) -> tonic::transport::server::Router {
     let ctx = entity::loader::load().await;
+    let hub = crate::hub::Hub::new();
+    let bridge = crate::bridge::Bridge::new(ctx.clone(), hub.clone())
+        .expect("Failed to set up the federation bridge");
+    if let Some(bridge) = &bridge {
+        tokio::spawn(bridge.clone().run());
+    }
     server
         .add_service(room::svc(ctx.clone()).await)
-        .add_service(message::svc(ctx.clone()).await)
-        .add_service(space::svc(ctx).await)
+        .add_service(message::svc(ctx.clone(), hub, bridge).await)
+        .add_service(space::svc(ctx.clone()).await)
+        .add_service(user::svc(ctx).await)
 }
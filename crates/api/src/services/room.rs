@@ -1,22 +1,23 @@
-use std::str::FromStr;
-
 use tonic::{codegen::InterceptedService, Request, Response, Status};
 
 use entity::{
     doc,
     helpers::FieldMaskDef,
-    mongodb::bson,
+    pagination::PAGINATOR,
     proto::room_service_server::{RoomService as IRoomService, RoomServiceServer},
     Document, Entity, EntityContext, FindOneOptions,
 };
 
-use crate::check_auth;
+use crate::{
+    access::{object_id, room_owner, room_participant, space_participant},
+    auth::{check_auth, user_id},
+};
 pub struct RoomService {
     ctx: EntityContext,
 }
 
 impl RoomService {
-    async fn new(ctx: EntityContext) -> Self {
+    pub async fn new(ctx: EntityContext) -> Self {
         Self { ctx }
     }
 }
@@ -27,12 +28,25 @@ impl IRoomService for RoomService {
         &self,
         request: Request<entity::proto::ListRoomsRequest>,
     ) -> Result<Response<entity::proto::ListRoomsResponse>, Status> {
-        let rooms = entity::proto::Room::find(&self.ctx, None, None)
+        let user_id = user_id(&request)?;
+        let body = request.into_inner();
+        let page = PAGINATOR
+            .page(
+                entity::proto::Room::COLLECTION,
+                doc! {"$or": [{"participants": &user_id}, {"owner": &user_id}]},
+                body.page_size,
+                &body.page_token,
+            )
+            .map_err(|err| Status::invalid_argument(format!("{}", err)))?;
+        let rooms = entity::proto::Room::find(&self.ctx, page.filter(), page.options(None))
             .await
             .map_err(|err| Status::internal(format!("Failed to find rooms, Report: {:#?}", err)))?;
+        let (rooms, next_page_token) = page.finish(rooms, |room| &room.id).map_err(|err| {
+            Status::internal(format!("Failed to paginate rooms, Report: {:#?}", err))
+        })?;
         Ok(Response::new(entity::proto::ListRoomsResponse {
             rooms,
-            next_page_token: "".to_string(),
+            next_page_token,
         }))
     }
 
@@ -40,29 +54,48 @@ impl IRoomService for RoomService {
         &self,
         request: Request<entity::proto::GetRoomRequest>,
     ) -> Result<Response<entity::proto::Room>, Status> {
-        // let filter = doc!();
+        let user_id = user_id(&request)?;
+        let body = request.into_inner();
+        let filter = doc! {
+            "_id": object_id(&body.name)?,
+            "$or": [{"participants": &user_id}, {"owner": &user_id}],
+        };
         let options = FindOneOptions::builder()
             .projection(Into::<Document>::into(Into::<FieldMaskDef>::into(
-                request.into_inner().field_mask,
+                body.field_mask,
             )))
             .build();
         // .unwrap().paths;
-        let room = entity::proto::Room::find_one(&self.ctx, None, options)
+        let room = entity::proto::Room::find_one(&self.ctx, filter, options)
             .await
             .map_err(|err| {
                 Status::internal(format!("Failed to find_one room, Report: {:#?}", err))
-            })?
-            .ok_or(Status::not_found("room not found"))?;
-        dbg!(&room);
-        return Ok(Response::new(room));
+            })?;
+        match room {
+            Some(room) => Ok(Response::new(room)),
+            // Tell apart missing room from foreign one
+            None => Err(room_participant(&self.ctx, &body.name, &user_id)
+                .await
+                .err()
+                .unwrap_or_else(|| Status::not_found("room not found"))),
+        }
     }
 
     async fn create_room(
         &self,
         request: Request<entity::proto::CreateRoomRequest>,
     ) -> Result<Response<entity::proto::Room>, Status> {
-        if let Some(room) = request.into_inner().room {
-            entity::proto::Room::create(&self.ctx, &room)
+        let user_id = user_id(&request)?;
+        if let Some(mut room) = request.into_inner().room {
+            if !room.space_id.is_empty() {
+                space_participant(&self.ctx, &room.space_id, &user_id).await?;
+            }
+            if !room.participants.contains(&user_id) {
+                room.participants.push(user_id.clone());
+            }
+            room.owner = user_id;
+            room.origin = String::new(); // Only the federation bridge mirrors rooms
+            room.id = entity::proto::Room::create(&self.ctx, &room)
                 .await
                 .map_err(|err| {
                     Status::internal(format!(
@@ -79,20 +112,41 @@ impl IRoomService for RoomService {
         &self,
         request: Request<entity::proto::UpdateRoomRequest>,
     ) -> Result<Response<entity::proto::Room>, Status> {
+        let user_id = user_id(&request)?;
         if let Some(room) = request.into_inner().room {
+            let existing = room_owner(&self.ctx, &room.id, &user_id).await?;
+            if !room.space_id.is_empty() && room.space_id != existing.space_id {
+                space_participant(&self.ctx, &room.space_id, &user_id).await?;
+            }
+            // Owner, origin and rotations aren't the client's to rewrite
+            let mut update = doc! {
+                "name": &room.name,
+                "description": &room.description,
+                "space_id": &room.space_id,
+            };
+            // Participants of a mirror are the ones of the remote room, the bridge syncs them
+            let participants = if existing.origin.is_empty() {
+                update.insert("participants", &room.participants);
+                room.participants
+            } else {
+                existing.participants
+            };
             entity::proto::Room::update_one(
                 &self.ctx,
-                doc! {"_id": bson::oid::ObjectId::from_str(&room.id).unwrap()}, // FUCK YOU MONGO
-                doc! {"$set": bson::to_document(&room).unwrap()},
+                doc! {"_id": object_id(&room.id)?}, // FUCK YOU MONGO
+                doc! {"$set": update},
             )
             .await
             .map_err(|err| {
-                Status::internal(format!(
-                    "Failed to update room: {:?}, Report: {:#?}",
-                    room, err
-                ))
+                Status::internal(format!("Failed to update room, Report: {:#?}", err))
             })?;
-            return Ok(Response::new(room));
+            return Ok(Response::new(entity::proto::Room {
+                name: room.name,
+                description: room.description,
+                space_id: room.space_id,
+                participants,
+                ..existing
+            }));
         }
         Err(Status::invalid_argument("room is required"))
     }
@@ -101,13 +155,15 @@ impl IRoomService for RoomService {
         &self,
         request: Request<entity::proto::DeleteRoomRequest>,
     ) -> Result<Response<()>, Status> {
-        let room = entity::proto::Room::delete_one(
-            &self.ctx,
-            doc! {"_id": bson::oid::ObjectId::from_str(&request.into_inner().id).unwrap()},
-        )
-        .await
-        .map_err(|err| Status::internal(format!("Failed to find_one room, Report: {:#?}", err)))?;
-        return Ok(Response::new(room));
+        let user_id = user_id(&request)?;
+        let id = request.into_inner().id;
+        room_owner(&self.ctx, &id, &user_id).await?;
+        entity::proto::Room::delete_one(&self.ctx, doc! {"_id": object_id(&id)?})
+            .await
+            .map_err(|err| {
+                Status::internal(format!("Failed to find_one room, Report: {:#?}", err))
+            })?;
+        return Ok(Response::new(()));
     }
 }
 
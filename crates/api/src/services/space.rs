@@ -1,22 +1,23 @@
-use std::str::FromStr;
-
 use tonic::{codegen::InterceptedService, Request, Response, Status};
 
 use entity::{
     doc,
     helpers::FieldMaskDef,
-    mongodb::bson,
+    pagination::PAGINATOR,
     proto::space_service_server::{SpaceService as ISpaceService, SpaceServiceServer},
     Document, Entity, EntityContext, FindOneOptions,
 };
 
-use crate::check_auth;
+use crate::{
+    access::{object_id, space_owner, space_participant},
+    auth::{check_auth, user_id},
+};
 pub struct SpaceService {
     ctx: EntityContext,
 }
 
 impl SpaceService {
-    async fn new(ctx: EntityContext) -> Self {
+    pub async fn new(ctx: EntityContext) -> Self {
         Self { ctx }
     }
 }
@@ -27,14 +28,27 @@ impl ISpaceService for SpaceService {
         &self,
         request: Request<entity::proto::ListSpacesRequest>,
     ) -> Result<Response<entity::proto::ListSpacesResponse>, Status> {
-        let spaces = entity::proto::Space::find(&self.ctx, None, None)
+        let user_id = user_id(&request)?;
+        let body = request.into_inner();
+        let page = PAGINATOR
+            .page(
+                entity::proto::Space::COLLECTION,
+                doc! {"$or": [{"participants": &user_id}, {"owner": &user_id}]},
+                body.page_size,
+                &body.page_token,
+            )
+            .map_err(|err| Status::invalid_argument(format!("{}", err)))?;
+        let spaces = entity::proto::Space::find(&self.ctx, page.filter(), page.options(None))
             .await
             .map_err(|err| {
                 Status::internal(format!("Failed to find spaces, Report: {:#?}", err))
             })?;
+        let (spaces, next_page_token) = page.finish(spaces, |space| &space.id).map_err(|err| {
+            Status::internal(format!("Failed to paginate spaces, Report: {:#?}", err))
+        })?;
         Ok(Response::new(entity::proto::ListSpacesResponse {
             spaces,
-            next_page_token: "".to_string(),
+            next_page_token,
         }))
     }
 
@@ -42,29 +56,44 @@ impl ISpaceService for SpaceService {
         &self,
         request: Request<entity::proto::GetSpaceRequest>,
     ) -> Result<Response<entity::proto::Space>, Status> {
-        // let filter = doc!();
+        let user_id = user_id(&request)?;
+        let body = request.into_inner();
+        let filter = doc! {
+            "_id": object_id(&body.name)?,
+            "$or": [{"participants": &user_id}, {"owner": &user_id}],
+        };
         let options = FindOneOptions::builder()
             .projection(Into::<Document>::into(Into::<FieldMaskDef>::into(
-                request.into_inner().field_mask,
+                body.field_mask,
             )))
             .build();
         // .unwrap().paths;
-        let space = entity::proto::Space::find_one(&self.ctx, None, options)
+        let space = entity::proto::Space::find_one(&self.ctx, filter, options)
             .await
             .map_err(|err| {
                 Status::internal(format!("Failed to find_one space, Report: {:#?}", err))
-            })?
-            .ok_or(Status::not_found("space not found"))?;
-        dbg!(&space);
-        return Ok(Response::new(space));
+            })?;
+        match space {
+            Some(space) => Ok(Response::new(space)),
+            // Tell apart missing space from foreign one
+            None => Err(space_participant(&self.ctx, &body.name, &user_id)
+                .await
+                .err()
+                .unwrap_or_else(|| Status::not_found("space not found"))),
+        }
     }
 
     async fn create_space(
         &self,
         request: Request<entity::proto::CreateSpaceRequest>,
     ) -> Result<Response<entity::proto::Space>, Status> {
-        if let Some(space) = request.into_inner().space {
-            entity::proto::Space::create(&self.ctx, &space)
+        let user_id = user_id(&request)?;
+        if let Some(mut space) = request.into_inner().space {
+            if !space.participants.contains(&user_id) {
+                space.participants.push(user_id.clone());
+            }
+            space.owner = user_id;
+            space.id = entity::proto::Space::create(&self.ctx, &space)
                 .await
                 .map_err(|err| {
                     Status::internal(format!(
@@ -81,20 +110,30 @@ impl ISpaceService for SpaceService {
         &self,
         request: Request<entity::proto::UpdateSpaceRequest>,
     ) -> Result<Response<entity::proto::Space>, Status> {
+        let user_id = user_id(&request)?;
         if let Some(space) = request.into_inner().space {
+            let existing = space_owner(&self.ctx, &space.id, &user_id).await?;
+            // Owner and rooms aren't the client's to rewrite, rooms join through their space_id
+            let update = doc! {
+                "title": &space.title,
+                "description": &space.description,
+                "participants": &space.participants,
+            };
             entity::proto::Space::update_one(
                 &self.ctx,
-                doc! {"_id": bson::oid::ObjectId::from_str(&space.id).unwrap()}, // FUCK YOU MONGO
-                doc! {"$set": bson::to_document(&space).unwrap()},
+                doc! {"_id": object_id(&space.id)?}, // FUCK YOU MONGO
+                doc! {"$set": update},
             )
             .await
             .map_err(|err| {
-                Status::internal(format!(
-                    "Failed to update space: {:?}, Report: {:#?}",
-                    space, err
-                ))
+                Status::internal(format!("Failed to update space, Report: {:#?}", err))
             })?;
-            return Ok(Response::new(space));
+            return Ok(Response::new(entity::proto::Space {
+                title: space.title,
+                description: space.description,
+                participants: space.participants,
+                ..existing
+            }));
         }
         Err(Status::invalid_argument("space is required"))
     }
@@ -103,13 +142,15 @@ impl ISpaceService for SpaceService {
         &self,
         request: Request<entity::proto::DeleteSpaceRequest>,
     ) -> Result<Response<()>, Status> {
-        let space = entity::proto::Space::delete_one(
-            &self.ctx,
-            doc! {"_id": bson::oid::ObjectId::from_str(&request.into_inner().id).unwrap()},
-        )
-        .await
-        .map_err(|err| Status::internal(format!("Failed to find_one space, Report: {:#?}", err)))?;
-        return Ok(Response::new(space));
+        let user_id = user_id(&request)?;
+        let id = request.into_inner().id;
+        space_owner(&self.ctx, &id, &user_id).await?;
+        entity::proto::Space::delete_one(&self.ctx, doc! {"_id": object_id(&id)?})
+            .await
+            .map_err(|err| {
+                Status::internal(format!("Failed to find_one space, Report: {:#?}", err))
+            })?;
+        return Ok(Response::new(()));
     }
 }
 
@@ -0,0 +1,233 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tonic::{Request, Response, Status};
+
+use entity::{
+    doc,
+    proto::{
+        user_service_server::{UserService as IUserService, UserServiceServer},
+        RefreshToken, User,
+    },
+    storage::DuplicateKey,
+    Entity, EntityContext,
+};
+
+use crate::auth::{issue_access_token, now};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+lazy_static::lazy_static! {
+    /// Unknown users are verified against it, so they take as long as wrong passwords
+    static ref DUMMY_HASH: String = Argon2::default()
+        .hash_password(b"not a password", &SaltString::generate(&mut OsRng))
+        .expect("Argon2 hashes with default params")
+        .to_string();
+}
+
+pub struct UserService {
+    ctx: EntityContext,
+}
+
+impl UserService {
+    pub async fn new(ctx: EntityContext) -> Self {
+        Self { ctx }
+    }
+
+    /// Issue an access token and persist a new refresh token for the user
+    async fn session(&self, user_id: String) -> Result<entity::proto::Session, Status> {
+        let (access_token, expires_at) = issue_access_token(&user_id).map_err(|err| {
+            Status::internal(format!("Failed to issue access token, Report: {:#?}", err))
+        })?;
+        // Expired tokens are never accepted again, drop them as sessions come and go
+        RefreshToken::delete_many(
+            &self.ctx,
+            doc! {"expires_at.seconds": {"$lte": now() as i64}},
+        )
+        .await
+        .map_err(|err| {
+            Status::internal(format!(
+                "Failed to purge refresh tokens, Report: {:#?}",
+                err
+            ))
+        })?;
+        let mut refresh_token = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut refresh_token);
+        let refresh_token = URL_SAFE_NO_PAD.encode(refresh_token);
+        RefreshToken::create(
+            &self.ctx,
+            &RefreshToken {
+                user_id: user_id.clone(),
+                token_hash: token_hash(&refresh_token),
+                expires_at: Some(prost_types::Timestamp {
+                    seconds: (now() + entity::config::SETTINGS.auth.refresh_ttl) as i64,
+                    nanos: 0,
+                }),
+            },
+        )
+        .await
+        .map_err(|err| {
+            Status::internal(format!(
+                "Failed to create refresh token, Report: {:#?}",
+                err
+            ))
+        })?;
+        Ok(entity::proto::Session {
+            user_id,
+            access_token,
+            refresh_token,
+            expires_at: Some(prost_types::Timestamp {
+                seconds: expires_at as i64,
+                nanos: 0,
+            }),
+        })
+    }
+}
+
+fn token_hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+#[tonic::async_trait]
+impl IUserService for UserService {
+    async fn register(
+        &self,
+        request: Request<entity::proto::RegisterRequest>,
+    ) -> Result<Response<entity::proto::Session>, Status> {
+        let body = request.into_inner();
+        if body.username.is_empty() {
+            return Err(Status::invalid_argument("username is required"));
+        }
+        if body.password.len() < MIN_PASSWORD_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            )));
+        }
+        // Saves hashing for taken names, the unique index settles concurrent registrations
+        let existing = User::find_one(&self.ctx, doc! {"username": &body.username}, None)
+            .await
+            .map_err(|err| Status::internal(format!("Failed to find user, Report: {:#?}", err)))?;
+        if existing.is_some() {
+            return Err(Status::already_exists("username is taken"));
+        }
+        // Hashing is slow on purpose, keep it away from the runtime threads
+        let password_hash = tokio::task::spawn_blocking(move || {
+            Argon2::default()
+                .hash_password(body.password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .map(|hash| hash.to_string())
+        })
+        .await
+        .map_err(|err| Status::internal(format!("Failed to hash password, Report: {:#?}", err)))?
+        .map_err(|err| Status::internal(format!("Failed to hash password, Report: {:#?}", err)))?;
+        let user_id = User::create(
+            &self.ctx,
+            &User {
+                username: body.username,
+                password_hash,
+                created_at: Some(prost_types::Timestamp {
+                    seconds: now() as i64,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|err| match err.downcast_ref::<DuplicateKey>() {
+            Some(_) => Status::already_exists("username is taken"),
+            None => Status::internal(format!("Failed to create user, Report: {:#?}", err)),
+        })?;
+        Ok(Response::new(self.session(user_id).await?))
+    }
+
+    async fn login(
+        &self,
+        request: Request<entity::proto::LoginRequest>,
+    ) -> Result<Response<entity::proto::Session>, Status> {
+        let body = request.into_inner();
+        let user = User::find_one(&self.ctx, doc! {"username": &body.username}, None)
+            .await
+            .map_err(|err| Status::internal(format!("Failed to find user, Report: {:#?}", err)))?;
+        // Unknown users get a hash too, their answer must not come back sooner
+        let password_hash = match &user {
+            Some(user) => user.password_hash.clone(),
+            None => DUMMY_HASH.clone(),
+        };
+        let valid = tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&password_hash)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(body.password.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false)
+        })
+        .await
+        .map_err(|err| {
+            Status::internal(format!("Failed to verify password, Report: {:#?}", err))
+        })?;
+        let Some(user) = user.filter(|_| valid) else {
+            return Err(Status::unauthenticated("Invalid username or password"));
+        };
+        Ok(Response::new(self.session(user.id).await?))
+    }
+
+    async fn refresh(
+        &self,
+        request: Request<entity::proto::RefreshRequest>,
+    ) -> Result<Response<entity::proto::Session>, Status> {
+        let filter = doc! {"token_hash": token_hash(&request.into_inner().refresh_token)};
+        let token = RefreshToken::find_one(&self.ctx, filter.clone(), None)
+            .await
+            .map_err(|err| {
+                Status::internal(format!("Failed to find refresh token, Report: {:#?}", err))
+            })?
+            .ok_or_else(|| Status::unauthenticated("Invalid refresh token"))?;
+        // Refresh tokens are single use, only the request deleting it gets a session
+        let deleted = RefreshToken::delete_one(&self.ctx, filter)
+            .await
+            .map_err(|err| {
+                Status::internal(format!(
+                    "Failed to revoke refresh token, Report: {:#?}",
+                    err
+                ))
+            })?;
+        if deleted != 1 {
+            return Err(Status::unauthenticated("Invalid refresh token"));
+        }
+        let expires_at = token.expires_at.unwrap_or_default().seconds;
+        if expires_at <= now() as i64 {
+            return Err(Status::unauthenticated("Refresh token expired"));
+        }
+        Ok(Response::new(self.session(token.user_id).await?))
+    }
+
+    async fn logout(
+        &self,
+        request: Request<entity::proto::LogoutRequest>,
+    ) -> Result<Response<()>, Status> {
+        RefreshToken::delete_one(
+            &self.ctx,
+            doc! {"token_hash": token_hash(&request.into_inner().refresh_token)},
+        )
+        .await
+        .map_err(|err| {
+            Status::internal(format!(
+                "Failed to revoke refresh token, Report: {:#?}",
+                err
+            ))
+        })?;
+        Ok(Response::new(()))
+    }
+}
+
+/// Not intercepted, users are not authenticated before login
+pub async fn svc(entity: EntityContext) -> UserServiceServer<UserService> {
+    let server = UserService::new(entity).await;
+
+    UserServiceServer::new(server)
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use prost::Message as _;
+use tonic::transport::Endpoint;
+
+use api::{bridge::Bridge, hub::Hub};
+use entity::{
+    doc,
+    proto::{Arrival, FederatedMessage, Message, Room},
+    Entity, EntityContext,
+};
+
+/// Bridge of `us:1`, the node is never reached
+async fn bridge() -> (Bridge, EntityContext) {
+    let ctx = common::ctx().await;
+    let channel = Endpoint::from_static("http://127.0.0.1:1").connect_lazy();
+    let bridge = Bridge::connect(ctx.clone(), Hub::new(), channel, "us:1".to_string());
+    (bridge, ctx)
+}
+
+/// Message of the sender in the room, as the server sends it
+fn arrival(server: &str, sender: &str, room: &Room) -> Arrival {
+    let message = Message {
+        sender: sender.to_string(),
+        ..Default::default()
+    };
+    Arrival {
+        server: server.to_string(),
+        data: FederatedMessage {
+            message: Some(message),
+            room: Some(room.clone()),
+        }
+        .encode_to_vec(),
+        ..Default::default()
+    }
+}
+
+/// Room `general` of `b:1`, with users of every server in it
+fn room() -> Room {
+    Room {
+        id: "general".to_string(),
+        name: "general".to_string(),
+        owner: "bob".to_string(),
+        participants: vec!["cat@c:1".to_string(), "dan@us:1".to_string()],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn servers_only_send_messages_of_their_users() {
+    let (bridge, ctx) = bridge().await;
+    let room = room();
+    assert!(bridge
+        .arrive(arrival("b:1", "cat@c:1", &room))
+        .await
+        .is_err());
+    assert!(bridge
+        .arrive(arrival("b:1", "dan@us:1", &room))
+        .await
+        .is_err());
+    assert!(bridge.arrive(arrival("", "bob", &room)).await.is_err());
+    assert!(Message::find(&ctx, doc! {}, None).await.unwrap().is_empty());
+
+    bridge.arrive(arrival("b:1", "bob", &room)).await.unwrap();
+    bridge
+        .arrive(arrival("b:1", "bob@b:1", &room))
+        .await
+        .unwrap();
+    let senders = Message::find(&ctx, doc! {}, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|message| message.sender)
+        .collect::<Vec<_>>();
+    assert_eq!(senders, ["bob@b:1", "bob@b:1"]);
+}
+
+#[tokio::test]
+async fn only_the_room_server_changes_a_mirror() {
+    let (bridge, ctx) = bridge().await;
+    // Only `b:1` opens the mirror of its room
+    let mut claimed = room();
+    claimed.origin = "general@b:1".to_string();
+    assert!(bridge
+        .arrive(arrival("c:1", "cat", &claimed))
+        .await
+        .is_err());
+    assert!(Room::find(&ctx, doc! {}, None).await.unwrap().is_empty());
+
+    bridge.arrive(arrival("b:1", "bob", &room())).await.unwrap();
+    let mirror = || async {
+        Room::find_one(&ctx, doc! {"origin": "general@b:1"}, None)
+            .await
+            .unwrap()
+            .unwrap()
+    };
+    let participants = mirror().await.participants;
+    assert_eq!(participants, ["cat@c:1", "dan"]);
+
+    // `c:1` posts for its users in the room, it can't let others in
+    let mut seen_by_c = room();
+    seen_by_c.origin = "general@b:1".to_string();
+    seen_by_c.participants.push("eve@c:1".to_string());
+    assert!(bridge
+        .arrive(arrival("c:1", "eve", &seen_by_c))
+        .await
+        .is_err());
+    bridge
+        .arrive(arrival("c:1", "cat", &seen_by_c))
+        .await
+        .unwrap();
+    assert_eq!(mirror().await.participants, participants);
+
+    let mut changed = room();
+    changed.participants.pop();
+    bridge
+        .arrive(arrival("b:1", "bob", &changed))
+        .await
+        .unwrap();
+    assert_eq!(mirror().await.participants, ["cat@c:1"]);
+}
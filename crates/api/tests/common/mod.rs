@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use tonic::Request;
+
+use api::auth::UserId;
+use entity::{loader, storage::MemoryStorage, EntityContext};
+
+/// Services read the settings on first use, they come from the test config
+pub async fn ctx() -> EntityContext {
+    std::env::set_var(
+        "CONFIG_FILE",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/config.toml"),
+    );
+    let ctx: EntityContext = Arc::new(MemoryStorage::default());
+    loader::create_indexes(&ctx).await.unwrap();
+    ctx
+}
+
+/// Request as `check_auth` lets it through for the user
+#[allow(dead_code)] // Not every test crate sends requests
+pub fn request<T>(user_id: &str, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request.extensions_mut().insert(UserId(user_id.to_string()));
+    request
+}
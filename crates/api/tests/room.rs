@@ -0,0 +1,266 @@
+mod common;
+
+use tokio_stream::StreamExt;
+use tonic::Code;
+
+use api::{
+    hub::Hub,
+    services::{message::MessageService, room::RoomService, space::SpaceService},
+};
+use common::request;
+use entity::{
+    doc,
+    proto::{
+        message, message_service_server::MessageService as _,
+        room_service_server::RoomService as _, space_service_server::SpaceService as _,
+        CreateRoomRequest, CreateSpaceRequest, DeleteMessageRequest, GetRoomRequest, Message,
+        PlainBody, Room, SendMessageRequest, Space, UpdateMessageRequest, UpdateRoomRequest,
+        UpdateSpaceRequest, WatchRoomRequest,
+    },
+    Entity, EntityContext,
+};
+
+/// Room of ann, with bob and cat in it
+async fn room(service: &RoomService) -> Room {
+    let room = Room {
+        name: "general".to_string(),
+        participants: vec!["bob".to_string(), "cat".to_string()],
+        ..Default::default()
+    };
+    service
+        .create_room(request("ann", CreateRoomRequest { room: Some(room) }))
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+async fn send(
+    service: &MessageService,
+    ctx: &EntityContext,
+    room_id: &str,
+    sender: &str,
+) -> Message {
+    let message = Message {
+        room_id: room_id.to_string(),
+        body: Some(message::Body::Plain(PlainBody {
+            content: b"hi".to_vec(),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    service
+        .send_message(request(
+            sender,
+            SendMessageRequest {
+                message: Some(message),
+            },
+        ))
+        .await
+        .unwrap();
+    Message::find(ctx, doc! {"sender": sender}, None)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn only_the_owner_updates_a_room() {
+    let service = RoomService::new(common::ctx().await).await;
+    let mut room = room(&service).await;
+    room.name = "taken over".to_string();
+    let update = UpdateRoomRequest {
+        room: Some(room),
+        field_mask: None,
+    };
+    let denied = service
+        .update_room(request("bob", update))
+        .await
+        .unwrap_err();
+    assert_eq!(denied.code(), Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn owner_and_origin_of_a_room_are_kept() {
+    let service = RoomService::new(common::ctx().await).await;
+    let mut room = room(&service).await;
+    room.name = "renamed".to_string();
+    room.owner = "bob".to_string();
+    room.origin = "remote@elsewhere:50060".to_string();
+    room.participants = vec!["bob".to_string()];
+    let update = UpdateRoomRequest {
+        room: Some(room.clone()),
+        field_mask: None,
+    };
+    let updated = service
+        .update_room(request("ann", update))
+        .await
+        .unwrap()
+        .into_inner();
+    let stored = service
+        .get_room(request(
+            "ann",
+            GetRoomRequest {
+                name: room.id.clone(),
+                field_mask: None,
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+    for room in [updated, stored] {
+        assert_eq!(room.name, "renamed");
+        assert_eq!(room.owner, "ann");
+        assert!(room.origin.is_empty());
+        assert_eq!(room.participants, ["bob"]);
+    }
+}
+
+#[tokio::test]
+async fn only_the_owner_updates_a_space_and_keeps_owning_it() {
+    let service = SpaceService::new(common::ctx().await).await;
+    let space = Space {
+        title: "team".to_string(),
+        participants: vec!["bob".to_string()],
+        ..Default::default()
+    };
+    let mut space = service
+        .create_space(request(
+            "ann",
+            CreateSpaceRequest {
+                space: Some(space),
+                ..Default::default()
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+    space.owner = "bob".to_string();
+    space.title = "bob's".to_string();
+    let update = |user: &str| {
+        service.update_space(request(
+            user,
+            UpdateSpaceRequest {
+                space: Some(space.clone()),
+                field_mask: None,
+            },
+        ))
+    };
+    let denied = update("bob").await.unwrap_err();
+    assert_eq!(denied.code(), Code::PermissionDenied);
+    let updated = update("ann").await.unwrap().into_inner();
+    assert_eq!(updated.title, "bob's");
+    assert_eq!(updated.owner, "ann");
+}
+
+#[tokio::test]
+async fn room_owner_deletes_messages_of_others_participants_dont() {
+    let ctx = common::ctx().await;
+    let room = room(&RoomService::new(ctx.clone()).await).await;
+    let service = MessageService::new(ctx.clone(), Hub::new(), None).await;
+    let message = send(&service, &ctx, &room.id, "bob").await;
+    let delete = |user: &str| {
+        service.delete_message(request(
+            user,
+            DeleteMessageRequest {
+                id: message.id.clone(),
+            },
+        ))
+    };
+    let denied = delete("cat").await.unwrap_err();
+    assert_eq!(denied.code(), Code::PermissionDenied);
+    delete("ann").await.unwrap();
+    assert!(Message::find(&ctx, doc! {}, None).await.unwrap().is_empty());
+
+    // Senders delete their own
+    let message = send(&service, &ctx, &room.id, "cat").await;
+    service
+        .delete_message(request("cat", DeleteMessageRequest { id: message.id }))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn senders_only_edit_the_content_of_messages() {
+    let ctx = common::ctx().await;
+    let room = room(&RoomService::new(ctx.clone()).await).await;
+    let service = MessageService::new(ctx.clone(), Hub::new(), None).await;
+    let sent = send(&service, &ctx, &room.id, "bob").await;
+    let mut edited = sent.clone();
+    edited.body = Some(message::Body::Plain(PlainBody {
+        content: b"bye".to_vec(),
+        ..Default::default()
+    }));
+    edited.room_id = "elsewhere".to_string();
+    edited.read_by.insert("cat".to_string(), 1);
+    edited.delivered_to.push("c:1".to_string());
+    let update = |user: &str, message: &Message| {
+        service.update_message(request(
+            user,
+            UpdateMessageRequest {
+                message: Some(message.clone()),
+                field_mask: None,
+            },
+        ))
+    };
+    assert!(update("cat", &edited).await.is_err());
+    update("bob", &edited).await.unwrap();
+
+    let stored = Message::find_one(&ctx, doc! {}, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.body, edited.body);
+    assert_eq!(
+        Message {
+            body: sent.body.clone(),
+            ..stored
+        },
+        sent
+    );
+}
+
+#[tokio::test]
+async fn watchers_removed_from_a_room_stop_getting_its_messages() {
+    let ctx = common::ctx().await;
+    let rooms = RoomService::new(ctx.clone()).await;
+    let room = room(&rooms).await;
+    let service = MessageService::new(ctx.clone(), Hub::new(), None).await;
+    let watch = |user: &str, room_ids: Vec<String>| {
+        service.watch_room(request(user, WatchRoomRequest { room_ids }))
+    };
+    let mut listed = watch("bob", vec![room.id.clone()])
+        .await
+        .unwrap()
+        .into_inner();
+    let mut every = watch("cat", vec![]).await.unwrap().into_inner();
+
+    send(&service, &ctx, &room.id, "ann").await;
+    assert!(listed.next().await.unwrap().is_ok());
+    assert!(every.next().await.unwrap().is_ok());
+
+    let mut update = room.clone();
+    update.participants = vec![];
+    let update = UpdateRoomRequest {
+        room: Some(update),
+        field_mask: None,
+    };
+    rooms.update_room(request("ann", update)).await.unwrap();
+    send(&service, &ctx, &room.id, "ann").await;
+    let denied = listed.next().await.unwrap().unwrap_err();
+    assert_eq!(denied.code(), Code::PermissionDenied);
+    assert!(listed.next().await.is_none());
+
+    // Watching every room skips the ones the user is no more in, and picks up new ones
+    let mut joined = room.clone();
+    joined.name = "later".to_string();
+    joined.participants = vec!["cat".to_string()];
+    let joined = rooms
+        .create_room(request("ann", CreateRoomRequest { room: Some(joined) }))
+        .await
+        .unwrap()
+        .into_inner();
+    send(&service, &ctx, &joined.id, "ann").await;
+    let event = every.next().await.unwrap().unwrap();
+    assert_eq!(event.message.unwrap().room_id, joined.id);
+}
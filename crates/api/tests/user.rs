@@ -0,0 +1,133 @@
+mod common;
+
+use tonic::{Code, Request};
+
+use api::{auth, services::user::UserService};
+use entity::{
+    doc,
+    proto::{
+        user_service_server::UserService as _, LoginRequest, LogoutRequest, RefreshRequest,
+        RefreshToken, RegisterRequest, Session,
+    },
+    Entity,
+};
+
+async fn register(service: &UserService, username: &str) -> Result<Session, tonic::Status> {
+    service
+        .register(Request::new(RegisterRequest {
+            username: username.to_string(),
+            password: "correct horse".to_string(),
+        }))
+        .await
+        .map(|response| response.into_inner())
+}
+
+async fn refresh(service: &UserService, token: &str) -> Result<Session, tonic::Status> {
+    service
+        .refresh(Request::new(RefreshRequest {
+            refresh_token: token.to_string(),
+        }))
+        .await
+        .map(|response| response.into_inner())
+}
+
+#[tokio::test]
+async fn concurrent_registrations_of_a_name_create_one_user() {
+    let service = UserService::new(common::ctx().await).await;
+    let (a, b) = tokio::join!(register(&service, "ann"), register(&service, "ann"));
+    let codes = [&a, &b].map(|result| result.as_ref().map_err(|status| status.code()).err());
+    assert!(codes.contains(&None) && codes.contains(&Some(Code::AlreadyExists)));
+}
+
+#[tokio::test]
+async fn login_refuses_wrong_passwords_and_unknown_users_alike() {
+    let service = UserService::new(common::ctx().await).await;
+    let session = register(&service, "ann").await.unwrap();
+    let login = |username: &str, password: &str| {
+        service.login(Request::new(LoginRequest {
+            username: username.to_string(),
+            password: password.to_string(),
+        }))
+    };
+    let logged_in = login("ann", "correct horse").await.unwrap().into_inner();
+    assert_eq!(logged_in.user_id, session.user_id);
+
+    let wrong = login("ann", "battery staple").await.unwrap_err();
+    let unknown = login("bob", "correct horse").await.unwrap_err();
+    assert_eq!(wrong.code(), Code::Unauthenticated);
+    assert_eq!(unknown.code(), Code::Unauthenticated);
+    assert_eq!(wrong.message(), unknown.message());
+}
+
+#[tokio::test]
+async fn access_tokens_authenticate_their_user() {
+    let service = UserService::new(common::ctx().await).await;
+    let session = register(&service, "ann").await.unwrap();
+    let mut request = Request::new(());
+    request.metadata_mut().insert(
+        "authorization",
+        format!("Bearer {}", session.access_token).parse().unwrap(),
+    );
+    let request = auth::check_auth(request).unwrap();
+    assert_eq!(auth::user_id(&request).unwrap(), session.user_id);
+
+    let mut forged = Request::new(());
+    let token = format!("Bearer {}x", session.access_token);
+    forged
+        .metadata_mut()
+        .insert("authorization", token.parse().unwrap());
+    assert!(auth::check_auth(forged).is_err());
+    assert!(auth::check_auth(Request::new(())).is_err());
+}
+
+#[tokio::test]
+async fn refresh_tokens_rotate_and_are_single_use() {
+    let service = UserService::new(common::ctx().await).await;
+    let first = register(&service, "ann").await.unwrap();
+    let second = refresh(&service, &first.refresh_token).await.unwrap();
+    assert_eq!(second.user_id, first.user_id);
+    assert_ne!(second.refresh_token, first.refresh_token);
+
+    let reused = refresh(&service, &first.refresh_token).await.unwrap_err();
+    assert_eq!(reused.code(), Code::Unauthenticated);
+
+    service
+        .logout(Request::new(LogoutRequest {
+            refresh_token: second.refresh_token.clone(),
+        }))
+        .await
+        .unwrap();
+    let revoked = refresh(&service, &second.refresh_token).await.unwrap_err();
+    assert_eq!(revoked.code(), Code::Unauthenticated);
+}
+
+#[tokio::test]
+async fn concurrent_refreshes_of_a_token_get_one_session() {
+    let service = UserService::new(common::ctx().await).await;
+    let session = register(&service, "ann").await.unwrap();
+    let (a, b) = tokio::join!(
+        refresh(&service, &session.refresh_token),
+        refresh(&service, &session.refresh_token)
+    );
+    let codes = [&a, &b].map(|result| result.as_ref().map_err(|status| status.code()).err());
+    assert!(codes.contains(&None) && codes.contains(&Some(Code::Unauthenticated)));
+}
+
+#[tokio::test]
+async fn expired_refresh_tokens_are_purged() {
+    let ctx = common::ctx().await;
+    let service = UserService::new(ctx.clone()).await;
+    let expired = RefreshToken {
+        user_id: "bob".to_string(),
+        token_hash: "expired".to_string(),
+        expires_at: Some(prost_types::Timestamp {
+            seconds: 1,
+            nanos: 0,
+        }),
+    };
+    RefreshToken::create(&ctx, &expired).await.unwrap();
+    register(&service, "ann").await.unwrap();
+    let left = RefreshToken::find(&ctx, doc! {}, None).await.unwrap();
+    assert_eq!(left.len(), 1);
+    assert_ne!(left[0].token_hash, "expired");
+}
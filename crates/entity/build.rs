@@ -4,7 +4,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let _ = tonic_build::configure()
         .file_descriptor_set_path(out_dir.join("room_descriptor.bin"))
-        .build_client(false)
+        .build_client(true) // Federation nodes dial each other
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
         .message_attribute(".", "#[serde(default)]")
         // .field_attribute(".", "#[serde(skip_serializing_if = \"crate::helpers::is_default\")]")
@@ -13,9 +13,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .field_attribute("created_at", "#[serde(with = \"crate::helpers::timestamp_ref\")]")
         .field_attribute("updated_at", "#[serde(with = \"crate::helpers::timestamp_ref\")]")
         .field_attribute("from_date", "#[serde(with = \"crate::helpers::timestamp_ref\")]")
+        .field_attribute("expires_at", "#[serde(with = \"crate::helpers::timestamp_ref\")]")
         
         .field_attribute("room.Room.id", 
         "#[serde(rename(deserialize = \"_id\"), deserialize_with = \"mongodb::bson::serde_helpers::deserialize_hex_string_from_object_id\")]")
-        .compile(&["./protos/room.proto", "./protos/federation.proto"], &["./protos", "./protos/lib"]);
+        .field_attribute("room.Message.id", 
+        "#[serde(rename(deserialize = \"_id\"), deserialize_with = \"mongodb::bson::serde_helpers::deserialize_hex_string_from_object_id\")]")
+        .field_attribute("room.Space.id", 
+        "#[serde(rename(deserialize = \"_id\"), deserialize_with = \"mongodb::bson::serde_helpers::deserialize_hex_string_from_object_id\")]")
+        .field_attribute("user.User.id", 
+        "#[serde(rename(deserialize = \"_id\"), deserialize_with = \"mongodb::bson::serde_helpers::deserialize_hex_string_from_object_id\")]")
+        .compile(&["./protos/room.proto", "./protos/user.proto", "./protos/federation.proto"], &["./protos", "./protos/lib"]);
     Ok(())
 }
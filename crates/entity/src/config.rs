@@ -12,12 +12,16 @@ pub struct Config {
     pub database: Database,
     pub api: Api,
     pub federation: Federation,
+    #[serde(default)]
+    pub auth: Auth,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Api {
     pub addr: SocketAddr,
     pub signing_key: String, // For federation
+    #[serde(default)]
+    pub page_token_secret: String, // Random on every start if empty
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,18 +31,163 @@ pub struct Federation {
     pub addr: SocketAddr,
     pub trusted_servers: Vec<String>, // Public keys of trusted servers includs self
     pub trust_iherit: bool,           // Trust all servers recived from trusted_servers
+    #[serde(default = "default_trust_depth")]
+    pub trust_depth: u32, // How many vouching servers can stand between us and the trusted one
+    #[serde(default)]
+    pub previous_signing_key: String, // Seed before api.signing_key, peers move to the new key
+    #[serde(default)]
+    pub revoked_servers: Vec<String>, // Public keys we never trust, peers inheriting from us neither
+    #[serde(default)]
+    pub resign: bool, // Add our signature to every forwarded packet
+    #[serde(default)]
+    pub db_path: String, // Topology store, nothing is persisted if empty
+    #[serde(default = "default_compact_interval")]
+    pub compact_interval: u64, // Seconds between topology snapshots
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64, // Seconds between heartbeats to neighbours
+    #[serde(default = "default_stale_timeout")]
+    pub stale_timeout: u64, // Seconds without heartbeat before routes through a host are dropped
+    #[serde(default)]
+    pub seeds: Vec<String>, // Addrs of servers dialed on startup
+    #[serde(default = "default_gossip_interval")]
+    pub gossip_interval: u64, // Seconds between topology exchanges with a random neighbour
+    #[serde(default = "default_outbox_ttl")]
+    pub outbox_ttl: u64, // Seconds a packet waits for an unreachable hop before it is dropped
+    #[serde(default = "default_outbox_capacity")]
+    pub outbox_capacity: usize, // Packets waiting per hop, the oldest are dropped past it
+    #[serde(default)]
+    pub onion: bool, // Wrap sent packets in one encryption layer per hop, hiding the path
+    #[serde(default = "default_packet_ttl")]
+    pub packet_ttl: u64, // Seconds after which packets are dropped, they can't be replayed later
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u32, // Longest path we send or forward on
+    #[serde(default = "default_bridge_addr")]
+    pub bridge_addr: SocketAddr, // Local api server reaches the node here, never expose it
+    #[serde(default = "default_admin_addr")]
+    pub admin_addr: SocketAddr, // Operator tools listen here, never expose it
+    #[serde(default = "default_peer_packets")]
+    pub peer_packets: u32, // Packets per second a peer may send us, in bursts of a second
+    #[serde(default = "default_peer_bytes")]
+    pub peer_bytes: u64, // Bytes per second a peer may send us
+    #[serde(default = "default_max_data")]
+    pub max_data: usize, // Largest payload we send or forward, in bytes
+    #[serde(default = "default_max_path")]
+    pub max_path: usize, // Peers sending longer paths get a strike, unlike over max_hops
+    #[serde(default = "default_strikes")]
+    pub strikes: u32, // Broken limits and malformed packets within a minute before a ban
+    #[serde(default = "default_strike_ban")]
+    pub strike_ban: u64, // Seconds a peer which ran out of strikes stays banned
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Auth {
+    pub secret: String,   // Signs session tokens, random on every start if empty
+    pub access_ttl: u64,  // Seconds
+    pub refresh_ttl: u64, // Seconds
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Database {
-    pub uri: String,
+    #[serde(default)]
+    pub uri: String, // Only used by mongo backend
+    #[serde(default)]
+    pub backend: Backend,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Mongo,
+    Memory, // Nothing is persisted, handy for tests and local demos
+}
+
+fn default_trust_depth() -> u32 {
+    1
+}
+
+fn default_compact_interval() -> u64 {
+    300
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30
+}
+
+fn default_stale_timeout() -> u64 {
+    90
+}
+
+fn default_gossip_interval() -> u64 {
+    10
+}
+
+fn default_outbox_ttl() -> u64 {
+    3600
+}
+
+fn default_outbox_capacity() -> usize {
+    1024
+}
+
+fn default_packet_ttl() -> u64 {
+    7200
+}
+
+fn default_max_hops() -> u32 {
+    16
+}
+
+fn default_bridge_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 50061))
+}
+
+fn default_admin_addr() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 50062))
+}
+
+fn default_peer_packets() -> u32 {
+    500
+}
+
+fn default_peer_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_max_data() -> usize {
+    64 * 1024
+}
+
+fn default_max_path() -> usize {
+    64
+}
+
+fn default_strikes() -> u32 {
+    10
+}
+
+fn default_strike_ban() -> u64 {
+    600
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            access_ttl: 15 * 60,
+            refresh_ttl: 30 * 24 * 60 * 60,
+        }
+    }
 }
 
+/// Read from `config.toml`, or the file `CONFIG_FILE` points to
 impl Default for Config {
     fn default() -> Self {
+        let path = std::env::var("CONFIG_FILE").unwrap_or_else(|_| "config.toml".to_string());
         MasterConfig::builder()
             .add_source(config::Environment::default().prefix("APP"))
-            .add_source(File::from(Path::new("config.toml")))
+            .add_source(File::from(Path::new(&path)))
             .build()
             .unwrap()
             .try_deserialize()
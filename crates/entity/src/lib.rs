@@ -5,6 +5,10 @@ pub mod proto;
 #[cfg(feature = "server")]
 pub mod config;
 pub mod helpers;
+#[cfg(feature = "server")]
+pub mod pagination;
+#[cfg(feature = "server")]
+pub mod storage;
 
 use eyre::{Result, WrapErr};
 
@@ -17,18 +21,24 @@ pub use mongodb::{
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use futures::stream::TryStreamExt;
-
 #[cfg(feature = "server")]
-pub type EntityContext = std::sync::Arc<futures::lock::Mutex<mongodb::Database>>;
+pub type EntityContext = std::sync::Arc<dyn storage::Storage>;
 
 #[cfg(feature = "server")]
 #[tonic::async_trait]
 pub trait Entity<T: Serialize + DeserializeOwned + Unpin + Send + Sync + Clone> {
     const COLLECTION: &'static str;
 
-    async fn collection(ctx: &EntityContext) -> mongodb::Collection<T> {
-        ctx.lock().await.collection::<T>(Self::COLLECTION)
+    /// Fields no two documents may share, indexed by `create_indexes`
+    const UNIQUE: &'static [&'static str] = &[];
+
+    async fn create_indexes(ctx: &EntityContext) -> Result<()> {
+        for field in Self::UNIQUE {
+            ctx.create_unique_index(Self::COLLECTION, field)
+                .await
+                .with_context(|| format!("Failed to index {}.{}", Self::COLLECTION, field))?;
+        }
+        Ok(())
     }
 
     async fn find<
@@ -39,11 +49,15 @@ pub trait Entity<T: Serialize + DeserializeOwned + Unpin + Send + Sync + Clone>
         filter: F,
         options: O,
     ) -> Result<Vec<T>> {
-        let cursor = Self::collection(ctx).await.find(filter, options).await?;
-        Ok(cursor
-            .try_collect::<Vec<_>>()
+        ctx.find(Self::COLLECTION, filter.into(), options.into())
             .await
-            .with_context(|| format!("Failed to find {}", Self::COLLECTION))?)
+            .with_context(|| format!("Failed to find {}", Self::COLLECTION))?
+            .into_iter()
+            .map(|doc| {
+                mongodb::bson::from_document(doc)
+                    .with_context(|| format!("Failed to deserialize {}", Self::COLLECTION))
+            })
+            .collect()
     }
 
     async fn find_one<
@@ -54,23 +68,28 @@ pub trait Entity<T: Serialize + DeserializeOwned + Unpin + Send + Sync + Clone>
         filter: F,
         options: O,
     ) -> Result<Option<T>> {
-        Self::collection(ctx)
-            .await
-            .find_one(filter, options)
+        ctx.find_one(Self::COLLECTION, filter.into(), options.into())
             .await
-            .with_context(|| format!("Failed to find {}", Self::COLLECTION))
+            .with_context(|| format!("Failed to find {}", Self::COLLECTION))?
+            .map(|doc| {
+                mongodb::bson::from_document(doc)
+                    .with_context(|| format!("Failed to deserialize {}", Self::COLLECTION))
+            })
+            .transpose()
     }
 
     async fn create(ctx: &EntityContext, payload: &T) -> Result<String> {
-        Ok(Self::collection(ctx)
+        let payload = mongodb::bson::to_document(payload)
+            .with_context(|| format!("Failed to serialize {}", Self::COLLECTION))?;
+        let inserted_id = ctx
+            .insert_one(Self::COLLECTION, payload)
             .await
-            .insert_one(payload, None)
-            .await
-            .with_context(|| format!("Failed to create {}", Self::COLLECTION))?
-            .inserted_id
-            .as_object_id()
-            .unwrap()
-            .to_hex())
+            .with_context(|| format!("Failed to create {}", Self::COLLECTION))?;
+        Ok(match inserted_id {
+            mongodb::bson::Bson::ObjectId(id) => id.to_hex(),
+            mongodb::bson::Bson::String(id) => id,
+            id => id.to_string(),
+        })
     }
 
     async fn update_one<F: Into<Document> + std::marker::Send>(
@@ -78,25 +97,31 @@ pub trait Entity<T: Serialize + DeserializeOwned + Unpin + Send + Sync + Clone>
         filter: F,
         payload: F,
     ) -> Result<()> {
-        Self::collection(ctx)
-            .await
-            .update_one(
-                filter.into(),
-                payload.into(), // doc! {"$set": mongodb::bson::to_document(&payload)?},
-                None,
-            )
-            .await?;
-        Ok(())
+        ctx.update_one(
+            Self::COLLECTION,
+            filter.into(),
+            payload.into(), // doc! {"$set": mongodb::bson::to_document(&payload)?},
+        )
+        .await
+        .with_context(|| format!("Failed to update {}", Self::COLLECTION))
     }
 
+    /// Returns how many documents were deleted, zero or one
     async fn delete_one<F: Into<Document> + std::marker::Send>(
         ctx: &EntityContext,
         filter: F,
-    ) -> Result<()> {
-        Self::collection(ctx)
+    ) -> Result<u64> {
+        ctx.delete_one(Self::COLLECTION, filter.into())
             .await
-            .delete_one(filter.into(), None)
-            .await?;
-        Ok(())
+            .with_context(|| format!("Failed to delete {}", Self::COLLECTION))
+    }
+
+    async fn delete_many<F: Into<Document> + std::marker::Send>(
+        ctx: &EntityContext,
+        filter: F,
+    ) -> Result<u64> {
+        ctx.delete_many(Self::COLLECTION, filter.into())
+            .await
+            .with_context(|| format!("Failed to delete {}", Self::COLLECTION))
     }
 }
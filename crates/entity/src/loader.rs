@@ -1,9 +1,29 @@
-use crate::{config::SETTINGS, EntityContext};
+use eyre::Result;
+
+use crate::{
+    config::{Backend, SETTINGS},
+    proto::{RefreshToken, User},
+    storage::{MemoryStorage, MongoStorage},
+    Entity, EntityContext,
+};
 
 pub async fn load() -> EntityContext {
-    let client = mongodb::Client::with_uri_str(&SETTINGS.database.uri)
-        .await
-        .unwrap();
-    let db = client.database("test");
-    std::sync::Arc::new(futures::lock::Mutex::new(db))
+    let ctx: EntityContext = match SETTINGS.database.backend {
+        Backend::Mongo => {
+            let client = mongodb::Client::with_uri_str(&SETTINGS.database.uri)
+                .await
+                .unwrap();
+            let db = client.database("test");
+            std::sync::Arc::new(MongoStorage::new(db))
+        }
+        Backend::Memory => std::sync::Arc::new(MemoryStorage::default()),
+    };
+    create_indexes(&ctx).await.unwrap();
+    ctx
+}
+
+/// Uniqueness the services rely on, checking before writing races with other requests
+pub async fn create_indexes(ctx: &EntityContext) -> Result<()> {
+    User::create_indexes(ctx).await?;
+    RefreshToken::create_indexes(ctx).await
 }
@@ -0,0 +1,147 @@
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use eyre::{eyre, Result, WrapErr};
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::config::SETTINGS;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+lazy_static::lazy_static! {
+    pub static ref PAGINATOR: Paginator = Paginator::new(SETTINGS.api.page_token_secret.as_bytes());
+}
+
+/// Issues and verifies page tokens of list RPCs
+/// Token is `base64(last _id || hmac(query shape || last _id))`, so it is opaque for the client
+/// and can't be tampered or replayed against a different query
+pub struct Paginator {
+    key: Vec<u8>,
+}
+
+/// Single page of a list RPC, built from `page_size` and `page_token` of the request
+pub struct Page<'a> {
+    paginator: &'a Paginator,
+    shape: Vec<u8>,
+    filter: Document,
+    after: Option<ObjectId>,
+    size: i64,
+}
+
+impl Paginator {
+    /// Empty key means tokens are valid only until restart
+    pub fn new(key: &[u8]) -> Self {
+        let key = if key.is_empty() {
+            let mut key = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        } else {
+            key.to_vec()
+        };
+        Self { key }
+    }
+
+    pub fn page(
+        &self,
+        collection: &str,
+        filter: Document,
+        page_size: i32,
+        page_token: &str,
+    ) -> Result<Page<'_>> {
+        let mut shape = collection.as_bytes().to_vec();
+        filter
+            .to_writer(&mut shape)
+            .wrap_err("Failed to encode query shape")?;
+        let after = if page_token.is_empty() {
+            None
+        } else {
+            Some(self.verify(&shape, page_token)?)
+        };
+        let size = match page_size as i64 {
+            size if size <= 0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        };
+        Ok(Page {
+            paginator: self,
+            shape,
+            filter,
+            after,
+            size,
+        })
+    }
+
+    fn sign(&self, shape: &[u8], last: &ObjectId) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("Hmac accepts any key");
+        mac.update(shape);
+        mac.update(&last.bytes());
+        mac
+    }
+
+    fn token(&self, shape: &[u8], last: &ObjectId) -> String {
+        let mut token = last.bytes().to_vec();
+        token.extend(self.sign(shape, last).finalize().into_bytes());
+        URL_SAFE_NO_PAD.encode(token)
+    }
+
+    fn verify(&self, shape: &[u8], token: &str) -> Result<ObjectId> {
+        let token = URL_SAFE_NO_PAD
+            .decode(token)
+            .wrap_err("Page token is malformed")?;
+        if token.len() <= 12 {
+            return Err(eyre!("Page token is malformed"));
+        }
+        let (last, signature) = token.split_at(12);
+        let last = ObjectId::from_bytes(last.try_into()?);
+        self.sign(shape, &last)
+            .verify_slice(signature)
+            .map_err(|_| eyre!("Page token doesn't match the query"))?;
+        Ok(last)
+    }
+}
+
+impl Page<'_> {
+    /// Filter of the request narrowed to items after the cursor
+    pub fn filter(&self) -> Document {
+        match self.after {
+            Some(after) => doc! {"$and": [self.filter.clone(), {"_id": {"$gt": after}}]},
+            None => self.filter.clone(),
+        }
+    }
+
+    /// Stable ordering by `_id`, one extra item is fetched to know if there is a next page
+    pub fn options(&self, projection: impl Into<Option<Document>>) -> FindOptions {
+        FindOptions::builder()
+            .projection(projection.into())
+            .sort(doc! {"_id": 1})
+            .limit(self.size + 1)
+            .build()
+    }
+
+    /// Cut the fetched items to the page size and issue the token of the next page
+    pub fn finish<T>(
+        &self,
+        mut items: Vec<T>,
+        id: impl Fn(&T) -> &str,
+    ) -> Result<(Vec<T>, String)> {
+        if items.len() as i64 <= self.size {
+            return Ok((items, String::new()));
+        }
+        items.truncate(self.size as usize);
+        let next_page_token = match items.last() {
+            Some(last) => {
+                let last =
+                    ObjectId::from_str(id(last)).wrap_err("Item of the page has no valid _id")?;
+                self.paginator.token(&self.shape, &last)
+            }
+            None => String::new(),
+        };
+        Ok((items, next_page_token))
+    }
+}
@@ -3,6 +3,9 @@ use crate::Entity;
 #[cfg(feature = "client")]
 tonic::include_proto!("room");
 
+#[cfg(feature = "client")]
+tonic::include_proto!("user");
+
 #[cfg(feature = "federation")]
 tonic::include_proto!("federation");
 
@@ -23,3 +26,15 @@ impl Entity<Message> for Message {
 impl Entity<Space> for Space {
     const COLLECTION: &'static str = "spaces";
 }
+
+#[cfg(feature = "server")]
+impl Entity<User> for User {
+    const COLLECTION: &'static str = "users";
+    const UNIQUE: &'static [&'static str] = &["username"];
+}
+
+#[cfg(feature = "server")]
+impl Entity<RefreshToken> for RefreshToken {
+    const COLLECTION: &'static str = "refresh_tokens";
+    const UNIQUE: &'static [&'static str] = &["token_hash"];
+}
@@ -0,0 +1,535 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use eyre::{eyre, Result};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, Document},
+    options::{FindOneOptions, FindOptions},
+};
+
+use super::{DuplicateKey, Storage};
+
+/// In-process storage, nothing survives a restart
+/// Understands the subset of the mongo query language our services are using,
+/// so the whole api can run in tests and demos without a database
+#[derive(Default)]
+pub struct MemoryStorage {
+    collections: RwLock<HashMap<String, Vec<Document>>>,
+    unique: RwLock<HashMap<String, HashSet<String>>>, // Indexed fields by collection
+}
+
+struct Query<'a> {
+    filter: Option<&'a Document>,
+    sort: Option<&'a Document>,
+    projection: Option<&'a Document>,
+    skip: u64,
+    limit: Option<i64>,
+}
+
+impl MemoryStorage {
+    /// `_id` and indexed fields must differ from every other document
+    /// Documents without the field don't collide, as if the index was sparse
+    fn check_unique(
+        &self,
+        collection: &str,
+        docs: &[Document],
+        document: &Document,
+        position: Option<usize>, // Of the document itself, when it is updated
+    ) -> Result<()> {
+        let unique = self
+            .unique
+            .read()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?;
+        let fields = unique.get(collection).into_iter().flatten();
+        for field in std::iter::once("_id").chain(fields.map(String::as_str)) {
+            let Some(value) = get_path(document, field) else {
+                continue;
+            };
+            let duplicate = docs
+                .iter()
+                .enumerate()
+                .any(|(other, doc)| Some(other) != position && get_path(doc, field) == Some(value));
+            if duplicate {
+                return Err(DuplicateKey(format!("{}.{}", collection, field)).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn query(&self, collection: &str, query: Query) -> Result<Vec<Document>> {
+        let collections = self
+            .collections
+            .read()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?;
+        let mut found = Vec::new();
+        for doc in collections.get(collection).into_iter().flatten() {
+            if query
+                .filter
+                .map_or(Ok(true), |filter| matches(doc, filter))?
+            {
+                found.push(doc.clone());
+            }
+        }
+        if let Some(sort) = query.sort {
+            found.sort_by(|a, b| compare_by(a, b, sort));
+        }
+        let found = found.into_iter().skip(query.skip as usize);
+        // Negative limit in mongo means "single batch", for us it is the same thing
+        let found: Vec<_> = match query.limit {
+            Some(limit) if limit != 0 => found.take(limit.unsigned_abs() as usize).collect(),
+            _ => found.collect(),
+        };
+        match query.projection {
+            Some(projection) if !projection.is_empty() => {
+                found.iter().map(|doc| project(doc, projection)).collect()
+            }
+            _ => Ok(found),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for MemoryStorage {
+    async fn find(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>> {
+        let options = options.unwrap_or_default();
+        self.query(
+            collection,
+            Query {
+                filter: filter.as_ref(),
+                sort: options.sort.as_ref(),
+                projection: options.projection.as_ref(),
+                skip: options.skip.unwrap_or_default(),
+                limit: options.limit,
+            },
+        )
+    }
+
+    async fn find_one(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOneOptions>,
+    ) -> Result<Option<Document>> {
+        let options = options.unwrap_or_default();
+        Ok(self
+            .query(
+                collection,
+                Query {
+                    filter: filter.as_ref(),
+                    sort: options.sort.as_ref(),
+                    projection: options.projection.as_ref(),
+                    skip: options.skip.unwrap_or_default(),
+                    limit: Some(1),
+                },
+            )?
+            .pop())
+    }
+
+    async fn insert_one(&self, collection: &str, mut payload: Document) -> Result<Bson> {
+        let mut collections = self
+            .collections
+            .write()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?;
+        let docs = collections.entry(collection.to_string()).or_default();
+        let id = payload
+            .remove("_id")
+            .unwrap_or_else(|| Bson::ObjectId(ObjectId::new()));
+        let mut document = doc! {"_id": id.clone()};
+        document.extend(payload);
+        self.check_unique(collection, docs, &document, None)?;
+        docs.push(document);
+        Ok(id)
+    }
+
+    async fn update_one(&self, collection: &str, filter: Document, update: Document) -> Result<()> {
+        let mut collections = self
+            .collections
+            .write()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?;
+        let Some(docs) = collections.get_mut(collection) else {
+            return Ok(());
+        };
+        for position in 0..docs.len() {
+            if matches(&docs[position], &filter)? {
+                // A failing operator leaves the document as it was, like in mongo
+                let mut updated = docs[position].clone();
+                apply_update(&mut updated, &update)?;
+                self.check_unique(collection, docs, &updated, Some(position))?;
+                docs[position] = updated;
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64> {
+        let mut collections = self
+            .collections
+            .write()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?;
+        let Some(docs) = collections.get_mut(collection) else {
+            return Ok(0);
+        };
+        for position in 0..docs.len() {
+            if matches(&docs[position], &filter)? {
+                docs.remove(position);
+                return Ok(1);
+            }
+        }
+        Ok(0)
+    }
+
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64> {
+        let mut collections = self
+            .collections
+            .write()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?;
+        let Some(docs) = collections.get_mut(collection) else {
+            return Ok(0);
+        };
+        // Match everything first, a failing filter deletes nothing
+        let mut deleted = Vec::new();
+        for (position, doc) in docs.iter().enumerate() {
+            if matches(doc, &filter)? {
+                deleted.push(position);
+            }
+        }
+        for position in deleted.iter().rev() {
+            docs.remove(*position);
+        }
+        Ok(deleted.len() as u64)
+    }
+
+    async fn create_unique_index(&self, collection: &str, field: &str) -> Result<()> {
+        self.unique
+            .write()
+            .map_err(|_| eyre!("Memory storage lock is poisoned"))?
+            .entry(collection.to_string())
+            .or_default()
+            .insert(field.to_string());
+        Ok(())
+    }
+}
+
+/// Operators we don't understand are an error, silently matching nothing hides the bug
+fn matches(doc: &Document, filter: &Document) -> Result<bool> {
+    for (key, condition) in filter {
+        let matched = match key.as_str() {
+            "$and" => sub_filters(doc, condition)?.iter().all(|matched| *matched),
+            "$or" => sub_filters(doc, condition)?.iter().any(|matched| *matched),
+            "$nor" => !sub_filters(doc, condition)?.iter().any(|matched| *matched),
+            key if key.starts_with('$') => {
+                return Err(eyre!("Unsupported query operator {}", key));
+            }
+            _ => matches_value(get_path(doc, key), condition)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn sub_filters(doc: &Document, condition: &Bson) -> Result<Vec<bool>> {
+    condition
+        .as_array()
+        .ok_or_else(|| eyre!("Logical operators expect an array of filters"))?
+        .iter()
+        .map(|filter| match filter {
+            Bson::Document(filter) => matches(doc, filter),
+            _ => Err(eyre!("Logical operators expect an array of filters")),
+        })
+        .collect()
+}
+
+fn matches_value(value: Option<&Bson>, condition: &Bson) -> Result<bool> {
+    let operators = match condition {
+        Bson::Document(operators) if operators.keys().any(|key| key.starts_with('$')) => operators,
+        _ => return Ok(equals(value, condition)),
+    };
+    for (operator, operand) in operators {
+        let matched = match operator.as_str() {
+            "$eq" => equals(value, operand),
+            "$ne" => !equals(value, operand),
+            "$gt" => compare_value(value, operand, Ordering::is_gt),
+            "$gte" => compare_value(value, operand, Ordering::is_ge),
+            "$lt" => compare_value(value, operand, Ordering::is_lt),
+            "$lte" => compare_value(value, operand, Ordering::is_le),
+            "$in" => items(operator, operand)?
+                .iter()
+                .any(|item| equals(value, item)),
+            "$nin" => !items(operator, operand)?
+                .iter()
+                .any(|item| equals(value, item)),
+            "$exists" => value.is_some() == truthy(operand),
+            _ => return Err(eyre!("Unsupported query operator {}", operator)),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn items<'a>(operator: &str, operand: &'a Bson) -> Result<&'a Vec<Bson>> {
+    operand
+        .as_array()
+        .ok_or_else(|| eyre!("{} expects an array", operator))
+}
+
+/// Mongo equality, arrays match if any of their items is equal
+fn equals(value: Option<&Bson>, expected: &Bson) -> bool {
+    match value {
+        None => matches!(expected, Bson::Null),
+        Some(Bson::Array(items)) if !matches!(expected, Bson::Array(_)) => items
+            .iter()
+            .any(|item| compare(item, expected) == Some(Ordering::Equal)),
+        Some(value) => compare(value, expected) == Some(Ordering::Equal),
+    }
+}
+
+fn compare_value(value: Option<&Bson>, operand: &Bson, accept: fn(Ordering) -> bool) -> bool {
+    match value {
+        Some(Bson::Array(items)) => items
+            .iter()
+            .any(|item| compare(item, operand).is_some_and(accept)),
+        Some(value) => compare(value, operand).is_some_and(accept),
+        None => false,
+    }
+}
+
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        (a, b) => number(a)?.partial_cmp(&number(b)?),
+    }
+}
+
+fn compare_by(a: &Document, b: &Document, sort: &Document) -> Ordering {
+    sort.iter()
+        .map(|(path, direction)| {
+            let ordering = match (get_path(a, path), get_path(b, path)) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Less,
+                (Some(_), None) => Ordering::Greater,
+                (Some(a), Some(b)) => compare(a, b).unwrap_or(Ordering::Equal),
+            };
+            if number(direction).is_some_and(|direction| direction < 0.0) {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(value) => Some(*value as f64),
+        Bson::Int64(value) => Some(*value as f64),
+        Bson::Double(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(value) => *value,
+        value => number(value).is_none_or(|value| value != 0.0),
+    }
+}
+
+fn project(doc: &Document, projection: &Document) -> Result<Document> {
+    let inclusive = projection
+        .iter()
+        .any(|(path, flag)| path != "_id" && truthy(flag));
+    let mut projected = if inclusive {
+        let mut projected = Document::new();
+        if let Some(id) = doc.get("_id") {
+            projected.insert("_id", id.clone());
+        }
+        projected
+    } else {
+        doc.clone()
+    };
+    for (path, flag) in projection {
+        match (inclusive, truthy(flag)) {
+            (true, true) => {
+                if let Some(value) = get_path(doc, path) {
+                    set_path(&mut projected, path, value.clone())?;
+                }
+            }
+            (_, false) => remove_path(&mut projected, path),
+            (false, true) => {}
+        }
+    }
+    Ok(projected)
+}
+
+fn apply_update(doc: &mut Document, update: &Document) -> Result<()> {
+    if !update.keys().any(|key| key.starts_with('$')) {
+        // Replacement document, only `_id` survives
+        let id = doc.get("_id").cloned();
+        *doc = update.clone();
+        if let Some(id) = id {
+            doc.insert("_id", id);
+        }
+        return Ok(());
+    }
+    for (operator, fields) in update {
+        let fields = fields
+            .as_document()
+            .ok_or_else(|| eyre!("{} expects a document", operator))?;
+        for (path, value) in fields {
+            match operator.as_str() {
+                "$set" => set_path(doc, path, value.clone())?,
+                "$unset" => remove_path(doc, path),
+                "$push" => push(doc, path, value.clone(), false)?,
+                "$addToSet" => push(doc, path, value.clone(), true)?,
+                "$pull" => {
+                    if let Some(Bson::Array(items)) = get_path_mut(doc, path) {
+                        let pulled = items
+                            .iter()
+                            .map(|item| matches_value(Some(item), value))
+                            .collect::<Result<Vec<_>>>()?;
+                        let mut pulled = pulled.into_iter();
+                        items.retain(|_| !pulled.next().unwrap_or_default());
+                    }
+                }
+                _ => return Err(eyre!("Unsupported update operator {}", operator)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn push(doc: &mut Document, path: &str, value: Bson, unique: bool) -> Result<()> {
+    match get_path_mut(doc, path) {
+        Some(Bson::Array(items)) => {
+            if !unique || !items.contains(&value) {
+                items.push(value);
+            }
+        }
+        Some(_) => return Err(eyre!("Field {} is not an array", path)),
+        None => set_path(doc, path, Bson::Array(vec![value]))?,
+    }
+    Ok(())
+}
+
+fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Bson::Document(doc) => doc.get(part)?,
+            Bson::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn get_path_mut<'a>(doc: &'a mut Document, path: &str) -> Option<&'a mut Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get_mut(parts.next()?)?;
+    for part in parts {
+        value = match value {
+            Bson::Document(doc) => doc.get_mut(part)?,
+            Bson::Array(items) => items.get_mut(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Missing parents are created as documents, like `get_path` array items are indexed by number
+fn set_path(doc: &mut Document, path: &str, value: Bson) -> Result<()> {
+    let Some((head, rest)) = path.split_once('.') else {
+        doc.insert(path, value);
+        return Ok(());
+    };
+    let parent = doc
+        .entry(head.to_string())
+        .or_insert_with(|| Bson::Document(Document::new()));
+    set_in(parent, rest, value)
+}
+
+/// Arrays are padded with nulls up to the index, as mongo does
+fn set_in(parent: &mut Bson, path: &str, value: Bson) -> Result<()> {
+    let items = match parent {
+        Bson::Array(items) => items,
+        Bson::Document(doc) => return set_path(doc, path, value),
+        _ => {
+            return Err(eyre!(
+                "Can't create field {} in a {:?}",
+                path,
+                parent.element_type()
+            ))
+        }
+    };
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+    let index = head
+        .parse::<usize>()
+        .map_err(|_| eyre!("Field {} of an array is not an index", head))?;
+    if items.len() <= index {
+        items.resize(index + 1, Bson::Null);
+    }
+    let Some(rest) = rest else {
+        items[index] = value;
+        return Ok(());
+    };
+    if items[index] == Bson::Null {
+        items[index] = Bson::Document(Document::new());
+    }
+    set_in(&mut items[index], rest, value)
+}
+
+fn remove_path(doc: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(child) = doc.get_mut(head) {
+                remove_in(child, rest);
+            }
+        }
+        None => {
+            doc.remove(path);
+        }
+    }
+}
+
+/// Array items are nulled rather than removed, so the other items keep their index
+fn remove_in(parent: &mut Bson, path: &str) {
+    match parent {
+        Bson::Document(doc) => remove_path(doc, path),
+        Bson::Array(items) => {
+            let (head, rest) = match path.split_once('.') {
+                Some((head, rest)) => (head, Some(rest)),
+                None => (path, None),
+            };
+            let Some(item) = head.parse::<usize>().ok().and_then(|i| items.get_mut(i)) else {
+                return;
+            };
+            match rest {
+                Some(rest) => remove_in(item, rest),
+                None => *item = Bson::Null,
+            }
+        }
+        _ => {}
+    }
+}
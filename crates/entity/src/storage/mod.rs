@@ -0,0 +1,56 @@
+mod memory;
+mod mongo;
+
+pub use memory::MemoryStorage;
+pub use mongo::MongoStorage;
+
+use eyre::Result;
+use mongodb::{
+    bson::{Bson, Document},
+    options::{FindOneOptions, FindOptions},
+};
+
+/// Backend which actually keeps the documents of every `Entity`
+/// Everything is passed around as raw bson documents, (de)serialization happens in `Entity`
+#[tonic::async_trait]
+pub trait Storage: Send + Sync {
+    async fn find(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>>;
+
+    async fn find_one(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOneOptions>,
+    ) -> Result<Option<Document>>;
+
+    /// Returns the `_id` of inserted document
+    async fn insert_one(&self, collection: &str, payload: Document) -> Result<Bson>;
+
+    async fn update_one(&self, collection: &str, filter: Document, update: Document) -> Result<()>;
+
+    /// Returns how many documents were deleted, zero or one
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64>;
+
+    /// Returns how many documents were deleted
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64>;
+
+    /// Writes giving two documents the same value of the field fail with `DuplicateKey`
+    async fn create_unique_index(&self, collection: &str, field: &str) -> Result<()>;
+}
+
+/// A unique index refused the write, tell it apart with `eyre::Report::downcast_ref`
+#[derive(Debug)]
+pub struct DuplicateKey(pub String); // Collection and field, for the message
+
+impl std::fmt::Display for DuplicateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Duplicate key in {}", self.0)
+    }
+}
+
+impl std::error::Error for DuplicateKey {}
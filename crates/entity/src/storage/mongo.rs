@@ -0,0 +1,112 @@
+use eyre::Result;
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::{Error, ErrorKind, WriteFailure},
+    options::{FindOneOptions, FindOptions, IndexOptions},
+    Database, IndexModel,
+};
+
+use super::{DuplicateKey, Storage};
+
+/// Server error code of writes refused by a unique index
+const DUPLICATE_KEY: i32 = 11000;
+
+pub struct MongoStorage {
+    db: Database,
+}
+
+impl MongoStorage {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for MongoStorage {
+    async fn find(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>> {
+        let cursor = self
+            .db
+            .collection::<Document>(collection)
+            .find(filter, options)
+            .await?;
+        Ok(cursor.try_collect::<Vec<_>>().await?)
+    }
+
+    async fn find_one(
+        &self,
+        collection: &str,
+        filter: Option<Document>,
+        options: Option<FindOneOptions>,
+    ) -> Result<Option<Document>> {
+        Ok(self
+            .db
+            .collection::<Document>(collection)
+            .find_one(filter, options)
+            .await?)
+    }
+
+    async fn insert_one(&self, collection: &str, payload: Document) -> Result<Bson> {
+        Ok(self
+            .db
+            .collection::<Document>(collection)
+            .insert_one(payload, None)
+            .await
+            .map_err(|err| write_error(collection, err))?
+            .inserted_id)
+    }
+
+    async fn update_one(&self, collection: &str, filter: Document, update: Document) -> Result<()> {
+        self.db
+            .collection::<Document>(collection)
+            .update_one(filter, update, None)
+            .await
+            .map_err(|err| write_error(collection, err))?;
+        Ok(())
+    }
+
+    async fn delete_one(&self, collection: &str, filter: Document) -> Result<u64> {
+        Ok(self
+            .db
+            .collection::<Document>(collection)
+            .delete_one(filter, None)
+            .await?
+            .deleted_count)
+    }
+
+    async fn delete_many(&self, collection: &str, filter: Document) -> Result<u64> {
+        Ok(self
+            .db
+            .collection::<Document>(collection)
+            .delete_many(filter, None)
+            .await?
+            .deleted_count)
+    }
+
+    async fn create_unique_index(&self, collection: &str, field: &str) -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {field: 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.db
+            .collection::<Document>(collection)
+            .create_index(index, None)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Duplicates become `DuplicateKey`, so callers don't have to know mongo error codes
+fn write_error(collection: &str, err: Error) -> eyre::Report {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write)) if write.code == DUPLICATE_KEY => {
+            DuplicateKey(collection.to_string()).into()
+        }
+        _ => err.into(),
+    }
+}
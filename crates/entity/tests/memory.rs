@@ -0,0 +1,293 @@
+use entity::{
+    doc,
+    mongodb::bson::{Bson, Document},
+    storage::{MemoryStorage, Storage},
+    FindOptions,
+};
+
+const PEOPLE: &str = "people";
+
+/// Three people, each with a name, an age, tags and a nested address
+async fn people() -> MemoryStorage {
+    let storage = MemoryStorage::default();
+    for (name, age, tags, city) in [
+        ("ann", 31, vec!["admin", "ops"], "oslo"),
+        ("bob", 25, vec!["ops"], "rome"),
+        ("cat", 42, vec![], "oslo"),
+    ] {
+        storage
+            .insert_one(
+                PEOPLE,
+                doc! {"name": name, "age": age, "tags": tags, "address": {"city": city}},
+            )
+            .await
+            .unwrap();
+    }
+    storage
+}
+
+async fn names(storage: &MemoryStorage, filter: Document) -> Vec<String> {
+    let options = FindOptions::builder().sort(doc! {"name": 1}).build();
+    storage
+        .find(PEOPLE, Some(filter), Some(options))
+        .await
+        .unwrap()
+        .iter()
+        .map(|doc| doc.get_str("name").unwrap().to_string())
+        .collect()
+}
+
+async fn person(storage: &MemoryStorage, name: &str) -> Document {
+    storage
+        .find_one(PEOPLE, Some(doc! {"name": name}), None)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn comparison_operators() {
+    let storage = people().await;
+    assert_eq!(names(&storage, doc! {"age": 25}).await, ["bob"]);
+    assert_eq!(names(&storage, doc! {"age": {"$eq": 31}}).await, ["ann"]);
+    assert_eq!(
+        names(&storage, doc! {"age": {"$ne": 31}}).await,
+        ["bob", "cat"]
+    );
+    assert_eq!(names(&storage, doc! {"age": {"$gt": 31}}).await, ["cat"]);
+    assert_eq!(
+        names(&storage, doc! {"age": {"$gte": 31}}).await,
+        ["ann", "cat"]
+    );
+    assert_eq!(names(&storage, doc! {"age": {"$lt": 31}}).await, ["bob"]);
+    assert_eq!(
+        names(&storage, doc! {"age": {"$lte": 31}}).await,
+        ["ann", "bob"]
+    );
+    assert_eq!(
+        names(&storage, doc! {"age": {"$gt": 20, "$lt": 40}}).await,
+        ["ann", "bob"]
+    );
+    // Numbers compare across their bson types
+    assert_eq!(names(&storage, doc! {"age": 25.0}).await, ["bob"]);
+}
+
+#[tokio::test]
+async fn set_and_array_operators() {
+    let storage = people().await;
+    assert_eq!(
+        names(&storage, doc! {"name": {"$in": ["ann", "cat", "dan"]}}).await,
+        ["ann", "cat"]
+    );
+    assert_eq!(
+        names(&storage, doc! {"name": {"$nin": ["ann"]}}).await,
+        ["bob", "cat"]
+    );
+    // Arrays match if any of their items does
+    assert_eq!(names(&storage, doc! {"tags": "ops"}).await, ["ann", "bob"]);
+    assert_eq!(names(&storage, doc! {"tags.0": "admin"}).await, ["ann"]);
+    assert_eq!(
+        names(&storage, doc! {"tags.0": {"$exists": false}}).await,
+        ["cat"]
+    );
+    assert!(names(&storage, doc! {"nickname": {"$exists": true}})
+        .await
+        .is_empty());
+    assert_eq!(
+        names(&storage, doc! {"nickname": Bson::Null}).await,
+        ["ann", "bob", "cat"]
+    );
+}
+
+#[tokio::test]
+async fn logical_operators_and_nested_paths() {
+    let storage = people().await;
+    assert_eq!(
+        names(&storage, doc! {"address.city": "oslo"}).await,
+        ["ann", "cat"]
+    );
+    assert_eq!(
+        names(
+            &storage,
+            doc! {"$and": [{"address.city": "oslo"}, {"age": {"$lt": 40}}]}
+        )
+        .await,
+        ["ann"]
+    );
+    assert_eq!(
+        names(&storage, doc! {"$or": [{"name": "bob"}, {"age": 42}]}).await,
+        ["bob", "cat"]
+    );
+    assert_eq!(
+        names(&storage, doc! {"$nor": [{"name": "bob"}, {"age": 42}]}).await,
+        ["ann"]
+    );
+}
+
+#[tokio::test]
+async fn unknown_operators_are_errors() {
+    let storage = people().await;
+    for filter in [
+        doc! {"name": {"$regex": "^a"}},
+        doc! {"$where": "true"},
+        doc! {"$or": "name"},
+    ] {
+        assert!(storage.find(PEOPLE, Some(filter), None).await.is_err());
+    }
+    let update = doc! {"$rename": {"name": "nick"}};
+    assert!(storage
+        .update_one(PEOPLE, doc! {"name": "ann"}, update)
+        .await
+        .is_err());
+    assert!(storage
+        .delete_one(PEOPLE, doc! {"age": {"$mod": [2, 0]}})
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn sort_skip_limit_and_projection() {
+    let storage = people().await;
+    let options = FindOptions::builder()
+        .sort(doc! {"age": -1})
+        .skip(1)
+        .limit(1)
+        .projection(doc! {"name": 1, "address.city": 1})
+        .build();
+    let found = storage.find(PEOPLE, None, Some(options)).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].get_str("name").unwrap(), "ann");
+    assert_eq!(
+        found[0].get_document("address").unwrap(),
+        &doc! {"city": "oslo"}
+    );
+    assert!(found[0].get("age").is_none() && found[0].get("_id").is_some());
+
+    let options = FindOptions::builder().projection(doc! {"tags": 0}).build();
+    let found = storage.find(PEOPLE, None, Some(options)).await.unwrap();
+    assert!(found.iter().all(|doc| doc.get("tags").is_none()));
+}
+
+#[tokio::test]
+async fn update_operators() {
+    let storage = people().await;
+    let update = doc! {
+        "$set": {"age": 32, "address.zip": "0150"},
+        "$unset": {"address.city": ""},
+        "$push": {"tags": "dev", "langs": "rust"},
+    };
+    storage
+        .update_one(PEOPLE, doc! {"name": "ann"}, update)
+        .await
+        .unwrap();
+    let ann = person(&storage, "ann").await;
+    assert_eq!(ann.get_i32("age").unwrap(), 32);
+    assert_eq!(ann.get_document("address").unwrap(), &doc! {"zip": "0150"});
+    assert_eq!(ann.get_array("tags").unwrap().len(), 3);
+    assert_eq!(ann.get_array("langs").unwrap(), &vec![Bson::from("rust")]);
+
+    let update = doc! {
+        "$addToSet": {"tags": "dev"},
+        "$pull": {"tags": {"$in": ["ops", "admin"]}},
+    };
+    storage
+        .update_one(PEOPLE, doc! {"name": "ann"}, update)
+        .await
+        .unwrap();
+    let ann = person(&storage, "ann").await;
+    assert_eq!(ann.get_array("tags").unwrap(), &vec![Bson::from("dev")]);
+
+    // Replacement keeps the id only
+    let id = ann.get("_id").unwrap().clone();
+    storage
+        .update_one(PEOPLE, doc! {"name": "ann"}, doc! {"name": "ann", "age": 1})
+        .await
+        .unwrap();
+    assert_eq!(
+        person(&storage, "ann").await,
+        doc! {"_id": id, "name": "ann", "age": 1}
+    );
+}
+
+#[tokio::test]
+async fn update_paths_index_arrays_like_lookups() {
+    let storage = people().await;
+    // Pushing into a string item fails, nothing of the update is applied
+    let update = doc! {"$set": {"tags.1": "root"}, "$push": {"tags.0.x": 1}};
+    assert!(storage
+        .update_one(PEOPLE, doc! {"name": "ann"}, update)
+        .await
+        .is_err());
+    assert_eq!(names(&storage, doc! {"tags.1": "ops"}).await, ["ann"]);
+
+    let update = doc! {"$set": {"tags.1": "root", "tags.3": "late"}};
+    storage
+        .update_one(PEOPLE, doc! {"name": "ann"}, update)
+        .await
+        .unwrap();
+    assert_eq!(names(&storage, doc! {"tags.1": "root"}).await, ["ann"]);
+    let ann = person(&storage, "ann").await;
+    assert_eq!(
+        ann.get_array("tags").unwrap(),
+        &vec![
+            Bson::from("admin"),
+            Bson::from("root"),
+            Bson::Null,
+            Bson::from("late")
+        ]
+    );
+
+    storage
+        .update_one(
+            PEOPLE,
+            doc! {"name": "ann"},
+            doc! {"$unset": {"tags.0": ""}},
+        )
+        .await
+        .unwrap();
+    assert_eq!(names(&storage, doc! {"tags.1": "root"}).await, ["ann"]);
+    assert_eq!(
+        names(&storage, doc! {"tags.0": Bson::Null}).await,
+        ["ann", "cat"]
+    );
+}
+
+#[tokio::test]
+async fn delete_removes_the_first_match_only() {
+    let storage = people().await;
+    let deleted = storage
+        .delete_one(PEOPLE, doc! {"address.city": "oslo"})
+        .await
+        .unwrap();
+    assert_eq!(deleted, 1);
+    assert_eq!(names(&storage, doc! {}).await, ["bob", "cat"]);
+    let deleted = storage
+        .delete_one(PEOPLE, doc! {"name": "ann"})
+        .await
+        .unwrap();
+    assert_eq!(deleted, 0);
+    assert!(storage
+        .insert_one(PEOPLE, doc! {"_id": 1, "name": "dan"})
+        .await
+        .is_ok());
+    assert!(storage
+        .insert_one(PEOPLE, doc! {"_id": 1, "name": "eve"})
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn delete_many_removes_every_match() {
+    let storage = people().await;
+    let deleted = storage
+        .delete_many(PEOPLE, doc! {"age": {"$gt": 30}})
+        .await
+        .unwrap();
+    assert_eq!(deleted, 2);
+    assert_eq!(names(&storage, doc! {}).await, ["bob"]);
+    assert!(storage
+        .delete_many(PEOPLE, doc! {"age": {"$mod": [2, 0]}})
+        .await
+        .is_err());
+    assert_eq!(names(&storage, doc! {}).await, ["bob"]);
+}
@@ -0,0 +1,115 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use entity::{
+    doc,
+    mongodb::bson::Document,
+    pagination::Paginator,
+    storage::{MemoryStorage, Storage},
+};
+
+const ROOMS: &str = "rooms";
+
+async fn rooms(count: usize) -> MemoryStorage {
+    let storage = MemoryStorage::default();
+    for index in 0..count {
+        storage
+            .insert_one(
+                ROOMS,
+                doc! {"index": index as i32, "public": index % 2 == 0},
+            )
+            .await
+            .unwrap();
+    }
+    storage
+}
+
+/// One page of the filter, its indexes and the token of the next one
+async fn page(
+    paginator: &Paginator,
+    storage: &MemoryStorage,
+    filter: Document,
+    token: &str,
+) -> (Vec<i32>, String) {
+    let page = paginator.page(ROOMS, filter, 2, token).unwrap();
+    let found = storage
+        .find(ROOMS, Some(page.filter()), Some(page.options(None)))
+        .await
+        .unwrap();
+    let ids = found
+        .iter()
+        .map(|doc| doc.get_object_id("_id").unwrap().to_hex())
+        .collect::<Vec<_>>();
+    let (ids, token) = page.finish(ids, |id| id).unwrap();
+    let indexes = ids
+        .iter()
+        .map(|id| {
+            found
+                .iter()
+                .find(|doc| doc.get_object_id("_id").unwrap().to_hex() == *id)
+                .unwrap()
+                .get_i32("index")
+                .unwrap()
+        })
+        .collect();
+    (indexes, token)
+}
+
+#[tokio::test]
+async fn pages_walk_every_item_once() {
+    let paginator = Paginator::new(b"secret");
+    let storage = rooms(5).await;
+    let (first, token) = page(&paginator, &storage, doc! {}, "").await;
+    assert_eq!(first, [0, 1]);
+    let (second, token) = page(&paginator, &storage, doc! {}, &token).await;
+    assert_eq!(second, [2, 3]);
+    // The last page has no next one
+    let (last, token) = page(&paginator, &storage, doc! {}, &token).await;
+    assert_eq!(last, [4]);
+    assert!(token.is_empty());
+}
+
+#[tokio::test]
+async fn full_last_page_has_no_token() {
+    let paginator = Paginator::new(b"secret");
+    let storage = rooms(4).await;
+    let (_, token) = page(&paginator, &storage, doc! {}, "").await;
+    let (last, token) = page(&paginator, &storage, doc! {}, &token).await;
+    assert_eq!(last, [2, 3]);
+    assert!(token.is_empty());
+}
+
+#[tokio::test]
+async fn tampered_tokens_are_refused() {
+    let paginator = Paginator::new(b"secret");
+    let storage = rooms(5).await;
+    let (_, token) = page(&paginator, &storage, doc! {}, "").await;
+    let mut bytes = URL_SAFE_NO_PAD.decode(&token).unwrap();
+    // Moving the cursor
+    bytes[11] ^= 1;
+    let moved = URL_SAFE_NO_PAD.encode(&bytes);
+    assert!(paginator.page(ROOMS, doc! {}, 2, &moved).is_err());
+    // Cut signature, garbage and tokens of another key
+    assert!(paginator.page(ROOMS, doc! {}, 2, &token[..20]).is_err());
+    assert!(paginator.page(ROOMS, doc! {}, 2, "not a token!").is_err());
+    let other = Paginator::new(b"other secret");
+    assert!(other.page(ROOMS, doc! {}, 2, &token).is_err());
+    assert!(paginator.page(ROOMS, doc! {}, 2, &token).is_ok());
+}
+
+#[tokio::test]
+async fn tokens_are_bound_to_their_query() {
+    let paginator = Paginator::new(b"secret");
+    let storage = rooms(5).await;
+    let (_, token) = page(&paginator, &storage, doc! {"public": true}, "").await;
+    assert!(paginator
+        .page("spaces", doc! {"public": true}, 2, &token)
+        .is_err());
+    assert!(paginator
+        .page(ROOMS, doc! {"public": false}, 2, &token)
+        .is_err());
+    assert!(paginator.page(ROOMS, doc! {}, 2, &token).is_err());
+    // Page size isn't part of the query, clients may change it between pages
+    assert!(paginator
+        .page(ROOMS, doc! {"public": true}, 3, &token)
+        .is_ok());
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use tonic::{Request, Response, Status};
+
+use entity::proto::{
+    admin_service_server::AdminService as IAdminService, BanRequest, ExportFormat, ExportRequest,
+    PeerList, PeerRequest, QueuedPackets, RevokeRequest, TopologyExport, TopologySnapshot,
+    TrustStatus,
+};
+
+use crate::{client::FederationClient, export, service::FederationService};
+
+/// Operator side of the node, to look into it and steer it by hand
+/// Listens on `federation.admin_addr`, anyone reaching it controls the node
+pub struct Admin {
+    service: FederationService,
+    client: FederationClient,
+}
+
+impl Admin {
+    pub fn new(service: FederationService, client: FederationClient) -> Self {
+        Self { service, client }
+    }
+}
+
+#[tonic::async_trait]
+impl IAdminService for Admin {
+    async fn topology(&self, _: Request<()>) -> Result<Response<TopologySnapshot>, Status> {
+        Ok(Response::new(self.service.topology().await))
+    }
+
+    /// Peers with their links, queues and packet counters, along with what was dropped
+    async fn peers(&self, _: Request<()>) -> Result<Response<PeerList>, Status> {
+        Ok(Response::new(PeerList {
+            peers: self.service.peers().await,
+            drops: Some(self.service.drop_stats()),
+        }))
+    }
+
+    async fn queue(&self, _: Request<()>) -> Result<Response<QueuedPackets>, Status> {
+        Ok(Response::new(QueuedPackets {
+            packets: self.service.queued(),
+        }))
+    }
+
+    async fn trust(&self, _: Request<()>) -> Result<Response<TrustStatus>, Status> {
+        Ok(Response::new(self.service.trust_status().await))
+    }
+
+    /// Introduce ourself to the peer and keep a link to it, like to a seed
+    async fn add_peer(&self, request: Request<PeerRequest>) -> Result<Response<()>, Status> {
+        let addr = request.into_inner().addr;
+        if self.service.is_banned(&addr) {
+            return Err(Status::failed_precondition("Host is banned"));
+        }
+        self.client.add_peer(&addr).await.map_err(|err| {
+            Status::unavailable(format!("Failed to add peer {}, Report: {:#?}", addr, err))
+        })?;
+        Ok(Response::new(()))
+    }
+
+    /// Forget the peer and stop dialing it
+    async fn remove_peer(&self, request: Request<PeerRequest>) -> Result<Response<()>, Status> {
+        let addr = request.into_inner().addr;
+        self.client.forget(&addr);
+        let known = self.service.remove_peer(&addr).await.map_err(|err| {
+            Status::internal(format!("Failed to remove peer, Report: {:#?}", err))
+        })?;
+        if !known {
+            return Err(Status::not_found("Host is not known"));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn ban(&self, request: Request<BanRequest>) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        if request.addr == self.service.addr() {
+            return Err(Status::invalid_argument("Can't ban this server"));
+        }
+        let duration = match request.duration {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+        self.service
+            .ban(&request.addr, duration)
+            .await
+            .map_err(|err| Status::internal(format!("Failed to ban host, Report: {:#?}", err)))?;
+        self.client.forget(&request.addr);
+        Ok(Response::new(()))
+    }
+
+    async fn unban(&self, request: Request<PeerRequest>) -> Result<Response<()>, Status> {
+        if !self.service.unban(&request.into_inner().addr) {
+            return Err(Status::not_found("Host is not banned"));
+        }
+        Ok(Response::new(()))
+    }
+
+    /// Withdraw our trust from the server, until the restart, see `TrustStore::revoke`
+    async fn revoke(&self, request: Request<RevokeRequest>) -> Result<Response<()>, Status> {
+        let public_key = request.into_inner().public_key;
+        if public_key.len() != 32 {
+            return Err(Status::invalid_argument("Public key must be 32 bytes"));
+        }
+        self.service
+            .revoke(public_key)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        Ok(Response::new(()))
+    }
+
+    /// Paths are computed again on next use, from the topology as it is now
+    async fn recompute(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        self.service.recompute();
+        Ok(Response::new(()))
+    }
+
+    /// Topology as Graphviz DOT or JSON, see `export`
+    async fn export(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<TopologyExport>, Status> {
+        let snapshot = self.service.topology().await;
+        let content = match request.into_inner().format() {
+            ExportFormat::Dot => export::dot(&snapshot),
+            ExportFormat::Json => export::json(&snapshot).map_err(|err| {
+                Status::internal(format!("Failed to export topology, Report: {:#?}", err))
+            })?,
+        };
+        Ok(Response::new(TopologyExport { content }))
+    }
+}
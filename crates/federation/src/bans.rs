@@ -0,0 +1,76 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+/// When the ban is lifted, never if none, and the key the host had
+type Ban = (Option<Instant>, Vec<u8>);
+
+/// Hosts we refuse to talk to, by addr, along with the key they had if we knew it
+/// Banned hosts are dropped from the graph, their introductions, streams, heartbeats and
+/// gossip are refused and gossip about them is ignored, so no path goes through them
+/// Streams proving a banned key are refused too, whatever addr they claim
+/// Peers with no bound addr are banned by where they connect from, see `handshake`
+#[derive(Default)]
+pub struct Bans {
+    banned: RwLock<HashMap<String, Ban>>,
+}
+
+impl Bans {
+    /// A new ban replaces the one the host already has
+    pub fn ban(&self, addr: &str, key: Vec<u8>, duration: Option<Duration>) {
+        let until = duration.map(|duration| Instant::now() + duration);
+        self.banned
+            .write()
+            .unwrap()
+            .insert(addr.to_string(), (until, key));
+    }
+
+    /// Returns false if the host wasn't banned
+    pub fn unban(&self, addr: &str) -> bool {
+        self.banned.write().unwrap().remove(addr).is_some()
+    }
+
+    /// Bans running out are lifted here, the host comes back when it introduces itself again
+    pub fn is_banned(&self, addr: &str) -> bool {
+        match self.banned.read().unwrap().get(addr) {
+            None => return false,
+            Some((None, _)) => return true,
+            Some((Some(until), _)) if *until > Instant::now() => return true,
+            Some((Some(_), _)) => {}
+        }
+        self.unban(addr);
+        false
+    }
+
+    /// Whether a running ban was put on the host with the key
+    pub fn is_key_banned(&self, key: &[u8]) -> bool {
+        let now = Instant::now();
+        !key.is_empty()
+            && self.banned.read().unwrap().values().any(|(until, banned)| {
+                banned.as_slice() == key && until.is_none_or(|until| until > now)
+            })
+    }
+
+    /// Drop the bans which ran out, whether or not the host came back
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.banned
+            .write()
+            .unwrap()
+            .retain(|_, (until, _)| until.is_none_or(|until| until > now));
+    }
+
+    /// Banned hosts and how long their ban still runs, none for bans until lifted
+    pub fn list(&self) -> Vec<(String, Option<Duration>)> {
+        let now = Instant::now();
+        self.banned
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, (until, _))| until.is_none_or(|until| until > now))
+            .map(|(addr, (until, _))| (addr.clone(), until.map(|until| until - now)))
+            .collect()
+    }
+}
@@ -0,0 +1,97 @@
+use std::pin::Pin;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{codegen::futures_core::Stream, Request, Response, Status};
+
+use entity::proto::{
+    bridge_event::Event, bridge_service_server::BridgeService as IBridgeService,
+    packet::Packet as PacketType, Arrival, BridgeEvent, DeliverRequest, DeliveryStatus,
+};
+
+use crate::{crypto, service::FederationService};
+
+type EventsStream = Pin<Box<dyn Stream<Item = Result<BridgeEvent, Status>> + Send>>;
+
+/// Local api server side of the node, it hands us data for other servers
+/// and gets what they delivered to us along with the status of its own deliveries
+pub struct Bridge {
+    service: FederationService,
+}
+
+impl Bridge {
+    pub fn new(service: FederationService) -> Self {
+        Self { service }
+    }
+}
+
+#[tonic::async_trait]
+impl IBridgeService for Bridge {
+    type EventsStream = EventsStream;
+
+    /// Send the data along the lowest latency path to the server
+    async fn deliver(&self, request: Request<DeliverRequest>) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let path = self.service.path_to(&request.server).await?;
+        self.service
+            .send(request.id, path, request.data)
+            .await
+            .map_err(|err| Status::internal(format!("Failed to send data, Report: {:#?}", err)))?;
+        Ok(Response::new(()))
+    }
+
+    /// Everything which reaches our inbox from now on
+    async fn events(&self, _: Request<()>) -> Result<Response<Self::EventsStream>, Status> {
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        let mut inbox = self.service.inbox();
+        let service = self.service.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let event = match inbox.recv().await {
+                    Ok(packet) => match packet.packet {
+                        Some(PacketType::Forward(forward)) => {
+                            // The path is only what the origin claims, its verified key tells
+                            // who sent it, onion packets come without path anyway
+                            let Some(server) = service.addr_of(&forward.origin).await else {
+                                eprintln!(
+                                    "Failed to bridge packet, Report: its origin has no host"
+                                );
+                                continue;
+                            };
+                            Event::Arrival(Arrival {
+                                server,
+                                origin: forward.origin,
+                                data: forward.data,
+                            })
+                        }
+                        // Only the receiver can tell the data arrived
+                        Some(PacketType::Acknowledge(ack)) => Event::Status(DeliveryStatus {
+                            success: ack.success && crypto::verify_receipt(&ack),
+                            id: ack.forward.map(|forward| forward.id).unwrap_or_default(),
+                        }),
+                        _ => continue,
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Statuses of skipped deliveries are lost, so is what arrived
+                        let _ = tx
+                            .send(Err(Status::data_loss(format!(
+                                "Bridge lagged behind, {} packets skipped",
+                                skipped
+                            ))))
+                            .await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let event = BridgeEvent { event: Some(event) };
+                if tx.send(Ok(event)).await.is_err() {
+                    break; // Api server is gone
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(Box::pin(output_stream) as Self::EventsStream))
+    }
+}
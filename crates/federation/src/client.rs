@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::WrapErr;
+use tokio::task::AbortHandle;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{transport::Channel, Request};
+
+use entity::proto::{
+    federation_service_client::FederationServiceClient, packet::Packet as PacketType, Packet,
+};
+
+use crate::{
+    protocol,
+    service::{FederationService, HANDSHAKE_TIMEOUT},
+};
+
+/// Metadata of `Forward` streams with the listening addr of the dialing server
+/// Only a hint, the server proves it with the key of the host, see `Proof`
+pub const PEER_ADDR_HEADER: &str = "x-federation-addr";
+
+/// Dials other servers, the service itself only ever answers
+/// Seeds are acknowledged on startup, then a `Forward` stream is kept open to every neighbour
+/// Hops with queued packets are dialed too, backing off while they stay unreachable
+#[derive(Clone)]
+pub struct FederationClient {
+    service: FederationService,
+    retry: Duration, // Delay before dialing a peer again
+    kept: Arc<Mutex<HashMap<String, AbortHandle>>>, // Links kept open, by peer
+}
+
+impl FederationClient {
+    pub fn new(service: FederationService, retry: Duration) -> Self {
+        Self {
+            service,
+            retry,
+            kept: Default::default(),
+        }
+    }
+
+    pub async fn run(self, seeds: Vec<String>) {
+        for seed in &seeds {
+            if let Err(err) = self.bootstrap(seed).await {
+                eprintln!("Failed to bootstrap from {}, Report: {:#?}", seed, err);
+            }
+        }
+        for peer in self.service.neighbours().await {
+            self.keep(peer);
+        }
+        self.deliver_queued().await;
+    }
+
+    /// Dial hops packets are waiting for and expire what waited too long, runs forever
+    async fn deliver_queued(self) {
+        let mut interval = tokio::time::interval(self.retry);
+        loop {
+            interval.tick().await;
+            self.service.expire_queued();
+            for peer in self.service.due_peers() {
+                let client = self.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = client.link(&peer).await {
+                        eprintln!("Failed to deliver to {}, Report: {:#?}", peer, err);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Introduce ourself to the seed and learn everything it knows
+    pub async fn bootstrap(&self, seed: &str) -> eyre::Result<()> {
+        let mut client = connect(seed).await?;
+        let hosts = client
+            .acknowledge(self.service.introduction().await)
+            .await
+            .wrap_err("Seed refused the introduction")?
+            .into_inner();
+        // The seed is among the hosts it knows, we refuse it like it would refuse us
+        if let Some(host) = hosts.hosts.iter().find(|host| host.addr == seed) {
+            protocol::negotiate(host)?;
+        }
+        self.service.merge(seed, hosts).await
+    }
+
+    /// Bootstrap from a peer the operator added and keep a link to it
+    pub async fn add_peer(&self, peer: &str) -> eyre::Result<()> {
+        self.bootstrap(peer).await?;
+        self.keep(peer.to_string());
+        Ok(())
+    }
+
+    /// Keep a link to the peer in the background, once per peer
+    pub fn keep(&self, peer: String) {
+        let mut kept = self.kept.lock().unwrap();
+        if kept.get(&peer).is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+        let task = tokio::spawn(self.clone().keep_link(peer.clone()));
+        kept.insert(peer, task.abort_handle());
+    }
+
+    /// Stop keeping a link to the peer, the open one is dropped with it
+    pub fn forget(&self, peer: &str) {
+        if let Some(task) = self.kept.lock().unwrap().remove(peer) {
+            task.abort();
+        }
+    }
+
+    /// Keep a stream open to the peer, reconnecting whenever it drops, until it is banned
+    pub async fn keep_link(self, peer: String) {
+        while !self.service.is_banned(&peer) {
+            if let Err(err) = self.link(&peer).await {
+                eprintln!("Failed to link with {}, Report: {:#?}", peer, err);
+            }
+            tokio::time::sleep(self.retry).await;
+        }
+    }
+
+    async fn link(&self, peer: &str) -> eyre::Result<()> {
+        if self.service.is_banned(peer) {
+            return Err(eyre::eyre!("{} is banned", peer));
+        }
+        let mut client = connect(peer).await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        // Our side of the stream ends with the first rejection, like the peer one does
+        let mut request = Request::new(ReceiverStream::new(rx).map_while(Result::ok));
+        request
+            .metadata_mut()
+            .insert(PEER_ADDR_HEADER, self.service.addr().parse()?);
+        let mut incoming = client.forward(request).await?.into_inner();
+        // The peer takes nothing from us before we proved we are the host we claim to be
+        let challenge = tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.message())
+            .await
+            .wrap_err_with(|| format!("{} sent no challenge", peer))??;
+        let Some(Packet {
+            packet: Some(PacketType::Challenge(challenge)),
+        }) = challenge
+        else {
+            return Err(eyre::eyre!("{} didn't start with a challenge", peer));
+        };
+        let proof = Packet {
+            packet: Some(PacketType::Proof(
+                self.service.prove(&challenge.nonce, peer),
+            )),
+        };
+        tx.send(Ok(proof)).await?;
+        self.service
+            .clone()
+            .run_link(peer.to_string(), peer.to_string(), incoming, tx)
+            .await;
+        Ok(())
+    }
+}
+
+async fn connect(addr: &str) -> eyre::Result<FederationServiceClient<Channel>> {
+    FederationServiceClient::connect(format!("http://{}", addr))
+        .await
+        .wrap_err_with(|| format!("Failed to connect to {}", addr))
+}
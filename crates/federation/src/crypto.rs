@@ -0,0 +1,426 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use eyre::{eyre, Result, WrapErr};
+use hkdf::Hkdf;
+use prost::Message;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use entity::proto::{
+    AcknowledgePacket, Adjacency, Attestation, ForwardPacket, HopSignature, OnionAck, OnionLayer,
+    Proof,
+};
+
+/// Separates payload keys from any other key derived from the same exchange
+const PAYLOAD_INFO: &[u8] = b"federation payload";
+
+/// Separates link proofs from anything else we sign
+const PROOF_CONTEXT: &[u8] = b"federation link";
+
+/// Separates proofs of listening on an addr, asked for by anyone, from link proofs
+const ADDR_PROOF_CONTEXT: &[u8] = b"federation addr";
+
+/// Separates key changes from anything else we sign
+const REKEY_CONTEXT: &[u8] = b"federation rekey";
+
+/// Onion acks don't carry the packet, the id they stand for is only known to both ends
+const ONION_ACK_CONTEXT: &[u8] = b"federation onion ack";
+
+/// Ed25519 key of this server, `api.signing_key` in the config
+/// Its X25519 counterpart decrypts payloads sealed to us
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    /// Base64 of the 32 bytes secret seed
+    pub fn from_config(encoded: &str) -> Result<Self> {
+        let seed: [u8; 32] = STANDARD
+            .decode(encoded)
+            .wrap_err("Signing key is not valid base64")?
+            .try_into()
+            .map_err(|_| eyre!("Signing key must be 32 bytes"))?;
+        Ok(Self {
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key.verifying_key().to_bytes().to_vec()
+    }
+
+    /// Sign the packet as its origin, must be done before sending the packet to the first hop
+    pub fn sign(&self, packet: &mut ForwardPacket) {
+        packet.origin = self.public_key();
+        packet.signature = self.key.sign(&signed_payload(packet)).to_bytes().to_vec();
+    }
+
+    /// Vouch for the packet as a forwarder, receiver can see which hops it went through
+    pub fn endorse(&self, packet: &mut ForwardPacket) {
+        let signature = self.key.sign(&packet.signature).to_bytes().to_vec();
+        packet.hop_signatures.push(HopSignature {
+            key: self.public_key(),
+            signature,
+        });
+    }
+
+    /// Vouch for the outcome as the receiver, so forwarders can't turn a nack into a success
+    pub fn sign_ack(&self, ack: &mut AcknowledgePacket) {
+        ack.signature = match &ack.forward {
+            Some(forward) => self
+                .key
+                .sign(&signed_ack(forward, ack.success))
+                .to_bytes()
+                .to_vec(),
+            None => vec![],
+        };
+    }
+
+    /// Acknowledge the onion packet with the id as its last hop
+    pub fn sign_onion_ack(&self, id: &[u8], ack: &mut OnionAck) {
+        ack.signature = self
+            .key
+            .sign(&onion_ack_payload(id, ack.success))
+            .to_bytes()
+            .to_vec();
+    }
+
+    /// Answer the challenge of the server we dialed, as the host listening on `addr`
+    pub fn prove(&self, nonce: &[u8], dialed: &str, addr: &str) -> Proof {
+        Proof {
+            public_key: self.public_key(),
+            signature: self
+                .key
+                .sign(&proof_payload(nonce, dialed, addr))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// Answer the server checking our introduction, as the host listening on `addr`
+    pub fn prove_addr(&self, nonce: &[u8], asker: &str, addr: &str) -> Proof {
+        Proof {
+            public_key: self.public_key(),
+            signature: self
+                .key
+                .sign(&addr_proof_payload(nonce, asker, addr))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// Hand the host over to the new key, as its previous key
+    /// Peers keep the first key they learned of a host unless this comes with the new one
+    pub fn rekey(&self, addr: &str, public_key: &[u8]) -> Vec<u8> {
+        self.key
+            .sign(&rekey_payload(addr, public_key))
+            .to_bytes()
+            .to_vec()
+    }
+
+    /// Publish the adjacency as its host, nobody else can tell our links
+    pub fn sign_adjacency(&self, adjacency: &mut Adjacency) {
+        adjacency.signature = vec![];
+        adjacency.signature = self
+            .key
+            .sign(&adjacency.encode_to_vec())
+            .to_bytes()
+            .to_vec();
+    }
+
+    /// Decrypt the data the origin sealed to our key, see `seal`
+    pub fn open(&self, packet: &ForwardPacket) -> Result<Vec<u8>> {
+        self.decrypt(&packet.ephemeral, &packet.nonce, &packet.data)
+    }
+
+    /// Sign the last onion layer as its origin, the last hop checks it like a forward packet
+    pub fn sign_layer(&self, layer: &mut OnionLayer) {
+        layer.origin = self.public_key();
+        layer.signature = self.key.sign(&signed_layer(layer)).to_bytes().to_vec();
+    }
+
+    /// Counterpart of `encrypt`
+    pub fn decrypt(&self, ephemeral: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let ephemeral: [u8; 32] = ephemeral
+            .try_into()
+            .map_err(|_| eyre!("Ephemeral key must be 32 bytes"))?;
+        if nonce.len() != 12 {
+            return Err(eyre!("Nonce must be 12 bytes"));
+        }
+        let secret = StaticSecret::from(self.key.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&PublicKey::from(ephemeral));
+        if !shared.was_contributory() {
+            return Err(eyre!("Ephemeral key is of low order"));
+        }
+        let recipient = self.key.verifying_key().to_montgomery().to_bytes();
+        payload_cipher(shared.as_bytes(), &ephemeral, &recipient)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| eyre!("Data can't be decrypted with our key"))
+    }
+
+    /// State that we trust (or not anymore) the subject, so peers can inherit our trust
+    pub fn attest(&self, subject: Vec<u8>, revoked: bool) -> Attestation {
+        let mut attestation = Attestation {
+            voucher: self.public_key(),
+            subject,
+            revoked,
+            issued_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs(),
+            signature: vec![],
+        };
+        attestation.signature = self
+            .key
+            .sign(&attestation.encode_to_vec())
+            .to_bytes()
+            .to_vec();
+        attestation
+    }
+}
+
+/// Encrypt the data to the ed25519 key of the recipient, forwarders only see ciphertext
+/// Must be done before signing, the signature covers the encryption parameters
+pub fn seal(packet: &mut ForwardPacket, recipient: &[u8]) -> Result<()> {
+    let (ephemeral, nonce, data) = encrypt(recipient, &packet.data)?;
+    packet.data = data;
+    packet.ephemeral = ephemeral;
+    packet.nonce = nonce;
+    Ok(())
+}
+
+/// Encrypt to the ed25519 key of the recipient with a fresh X25519 key
+/// Returns that key, the nonce and the ciphertext
+pub fn encrypt(recipient: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let recipient: [u8; 32] = recipient
+        .try_into()
+        .map_err(|_| eyre!("Public key must be 32 bytes"))?;
+    let recipient = VerifyingKey::from_bytes(&recipient)
+        .wrap_err("Public key is not a valid ed25519 key")?
+        .to_montgomery()
+        .to_bytes();
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(recipient));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = payload_cipher(shared.as_bytes(), &ephemeral, &recipient)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| eyre!("Failed to encrypt data"))?;
+    Ok((ephemeral.to_vec(), nonce.to_vec(), ciphertext))
+}
+
+/// Both X25519 keys salt the derivation, a key is only ever used for one exchange
+fn payload_cipher(shared: &[u8], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> ChaCha20Poly1305 {
+    let salt = [ephemeral.as_slice(), recipient.as_slice()].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(PAYLOAD_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    ChaCha20Poly1305::new(&key.into())
+}
+
+/// Hop and hop signatures are mutated on the way, everything else is signed
+fn signed_payload(packet: &ForwardPacket) -> Vec<u8> {
+    ForwardPacket {
+        path: packet.path.clone(),
+        data: packet.data.clone(),
+        ephemeral: packet.ephemeral.clone(),
+        nonce: packet.nonce.clone(),
+        id: packet.id.clone(),
+        sent_at: packet.sent_at,
+        max_hops: packet.max_hops,
+        ..Default::default()
+    }
+    .encode_to_vec()
+}
+
+/// Both addrs are signed, so a challenge can't be answered by someone dialed in between
+fn proof_payload(nonce: &[u8], dialed: &str, addr: &str) -> Vec<u8> {
+    [
+        PROOF_CONTEXT,
+        nonce,
+        &(dialed.len() as u64).to_be_bytes(),
+        dialed.as_bytes(),
+        addr.as_bytes(),
+    ]
+    .concat()
+}
+
+/// The asker picks the nonce, every field but the last is length prefixed so none can spill over
+fn addr_proof_payload(nonce: &[u8], asker: &str, addr: &str) -> Vec<u8> {
+    [
+        ADDR_PROOF_CONTEXT,
+        &(nonce.len() as u64).to_be_bytes(),
+        nonce,
+        &(asker.len() as u64).to_be_bytes(),
+        asker.as_bytes(),
+        addr.as_bytes(),
+    ]
+    .concat()
+}
+
+fn rekey_payload(addr: &str, public_key: &[u8]) -> Vec<u8> {
+    [
+        REKEY_CONTEXT,
+        &(addr.len() as u64).to_be_bytes(),
+        addr.as_bytes(),
+        public_key,
+    ]
+    .concat()
+}
+
+fn onion_ack_payload(id: &[u8], success: bool) -> Vec<u8> {
+    [
+        ONION_ACK_CONTEXT,
+        &(id.len() as u64).to_be_bytes(),
+        id,
+        &[success as u8],
+    ]
+    .concat()
+}
+
+/// Origin signature stands for the whole packet
+fn signed_ack(forward: &ForwardPacket, success: bool) -> Vec<u8> {
+    [forward.signature.as_slice(), &[success as u8]].concat()
+}
+
+fn signed_layer(layer: &OnionLayer) -> Vec<u8> {
+    OnionLayer {
+        data: layer.data.clone(),
+        id: layer.id.clone(),
+        sent_at: layer.sent_at,
+        ..Default::default()
+    }
+    .encode_to_vec()
+}
+
+pub fn verify(key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(key) = <[u8; 32]>::try_from(key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    VerifyingKey::from_bytes(&key)
+        .map(|key| key.verify(message, &signature).is_ok())
+        .unwrap_or(false)
+}
+
+pub fn verify_origin(packet: &ForwardPacket) -> bool {
+    verify(&packet.origin, &signed_payload(packet), &packet.signature)
+}
+
+pub fn verify_layer(layer: &OnionLayer) -> bool {
+    verify(&layer.origin, &signed_layer(layer), &layer.signature)
+}
+
+/// Onion ack of the packet with the id, signed by the last hop of its path
+pub fn verify_onion_ack(receiver: &[u8], id: &[u8], ack: &OnionAck) -> bool {
+    verify(
+        receiver,
+        &onion_ack_payload(id, ack.success),
+        &ack.signature,
+    )
+}
+
+/// Ack the receiver signed, on a plain path or through an onion circuit, see `onion::acknowledge`
+pub fn verify_receipt(ack: &AcknowledgePacket) -> bool {
+    let Some(forward) = &ack.forward else {
+        return false;
+    };
+    let Some(receiver) = forward.path.last() else {
+        return false;
+    };
+    let onion = OnionAck {
+        success: ack.success,
+        signature: ack.signature.clone(),
+        ..Default::default()
+    };
+    verify_ack(ack) || verify_onion_ack(&receiver.public_key, &forward.id, &onion)
+}
+
+pub fn verify_ack(ack: &AcknowledgePacket) -> bool {
+    let Some(forward) = &ack.forward else {
+        return false;
+    };
+    let Some(receiver) = forward.path.last() else {
+        return false;
+    };
+    verify(
+        &receiver.public_key,
+        &signed_ack(forward, ack.success),
+        &ack.signature,
+    )
+}
+
+/// Proof of the dialing server which claims to listen on `addr`, see `Identity::prove`
+pub fn verify_proof(proof: &Proof, nonce: &[u8], dialed: &str, addr: &str) -> bool {
+    verify(
+        &proof.public_key,
+        &proof_payload(nonce, dialed, addr),
+        &proof.signature,
+    )
+}
+
+/// Proof of the host listening on `addr` to the asker, see `Identity::prove_addr`
+pub fn verify_addr_proof(proof: &Proof, nonce: &[u8], asker: &str, addr: &str) -> bool {
+    verify(
+        &proof.public_key,
+        &addr_proof_payload(nonce, asker, addr),
+        &proof.signature,
+    )
+}
+
+/// Previous key of the host signed the new one, see `Identity::rekey`
+pub fn verify_rekey(previous: &[u8], addr: &str, public_key: &[u8], signature: &[u8]) -> bool {
+    verify(previous, &rekey_payload(addr, public_key), signature)
+}
+
+/// Adjacency signed with the key, the one we know for its host
+pub fn verify_adjacency(key: &[u8], adjacency: &Adjacency) -> bool {
+    let payload = Adjacency {
+        signature: vec![],
+        ..adjacency.clone()
+    }
+    .encode_to_vec();
+    verify(key, &payload, &adjacency.signature)
+}
+
+pub fn verify_attestation(attestation: &Attestation) -> bool {
+    let payload = Attestation {
+        signature: vec![],
+        ..attestation.clone()
+    }
+    .encode_to_vec();
+    verify(&attestation.voucher, &payload, &attestation.signature)
+}
+
+pub fn verify_hop(packet: &ForwardPacket, hop: &HopSignature) -> bool {
+    verify(&hop.key, &packet.signature, &hop.signature)
+}
+
+pub fn decode_key(encoded: &str) -> Result<Vec<u8>> {
+    let key = STANDARD
+        .decode(encoded)
+        .wrap_err_with(|| format!("Public key {} is not valid base64", encoded))?;
+    if key.len() != 32 {
+        return Err(eyre!("Public key {} must be 32 bytes", encoded));
+    }
+    Ok(key)
+}
+
+pub fn encode_key(key: &[u8]) -> String {
+    STANDARD.encode(key)
+}
@@ -0,0 +1,99 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use entity::proto::{Edge, Host, TopologySnapshot};
+
+use crate::routing::UNMEASURED;
+
+/// Host as exported, keys and capabilities are left out
+#[derive(Serialize)]
+struct ExportedHost<'a> {
+    addr: &'a str,
+    forwarder: bool,
+    last_seen: Option<i64>, // Unix seconds
+}
+
+/// Edge as exported, the weight is missing until the link was measured
+#[derive(Serialize)]
+struct ExportedEdge<'a> {
+    from: &'a str,
+    to: &'a str,
+    weight: Option<u32>, // Round-trip milliseconds
+}
+
+#[derive(Serialize)]
+struct Exported<'a> {
+    hosts: Vec<ExportedHost<'a>>,
+    edges: Vec<ExportedEdge<'a>>,
+}
+
+/// Graphviz digraph of the topology, unmeasured links are dashed
+/// Hosts and edges are sorted, so exports of two nodes can be diffed
+pub fn dot(snapshot: &TopologySnapshot) -> String {
+    let (hosts, edges) = sorted(snapshot);
+    let mut dot = String::from("digraph federation {\n");
+    for host in hosts {
+        let addr = escape(&host.addr);
+        let mut label = addr.clone();
+        if host.forwarder {
+            label.push_str("\\nforwarder");
+        }
+        if let Some(last_seen) = &host.last_seen {
+            let _ = write!(label, "\\nseen {}", last_seen.seconds);
+        }
+        let _ = writeln!(dot, "    \"{}\" [label=\"{}\"];", addr, label);
+    }
+    for edge in edges {
+        let attributes = match edge.weight {
+            UNMEASURED => "style=dashed".to_string(),
+            weight => format!("label=\"{}ms\"", weight),
+        };
+        let _ = writeln!(
+            dot,
+            "    \"{}\" -> \"{}\" [{}];",
+            escape(&edge.from),
+            escape(&edge.to),
+            attributes
+        );
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Hosts and edges as pretty JSON, sorted like `dot`
+pub fn json(snapshot: &TopologySnapshot) -> eyre::Result<String> {
+    let (hosts, edges) = sorted(snapshot);
+    let exported = Exported {
+        hosts: hosts
+            .iter()
+            .map(|host| ExportedHost {
+                addr: &host.addr,
+                forwarder: host.forwarder,
+                last_seen: host.last_seen.as_ref().map(|seen| seen.seconds),
+            })
+            .collect(),
+        edges: edges
+            .iter()
+            .map(|edge| ExportedEdge {
+                from: &edge.from,
+                to: &edge.to,
+                weight: (edge.weight != UNMEASURED).then_some(edge.weight),
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string_pretty(&exported)?)
+}
+
+fn sorted(snapshot: &TopologySnapshot) -> (Vec<&Host>, Vec<&Edge>) {
+    let mut hosts = snapshot.hosts.iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| a.addr.cmp(&b.addr));
+    let mut edges = snapshot.edges.iter().collect::<Vec<_>>();
+    edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+    (hosts, edges)
+}
+
+/// Inside of a DOT string, addrs come from peers so they are escaped
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{eyre, WrapErr};
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+use rand::{seq::IteratorRandom, RngCore};
+use tokio::sync::RwLock;
+use tonic::transport::Channel;
+
+use entity::proto::{
+    federation_service_client::FederationServiceClient, Adjacency, Challenge, Edge, GossipPacket,
+    Host,
+};
+
+use crate::{
+    bans::Bans,
+    crypto::{self, Identity},
+    graph::HostGraph,
+    routing::{PathCache, MAX_RTT, UNMEASURED},
+};
+
+/// Milliseconds a version may be ahead of our clock, clocks of servers drift
+/// Versions further ahead would keep the adjacency from ever being replaced
+const MAX_SKEW: u64 = 60_000;
+
+/// Hosts gossip adds to the graph, and adjacencies we keep, at most
+/// Anyone can gossip to us, they can't grow either without bound
+pub const MAX_HOSTS: usize = 10_000;
+
+/// Latest adjacency of every host we heard of, ours included
+/// Only the host itself publishes its adjacency, so the newest version always wins
+/// Adjacencies are signed by their host, and checked against the key we know for it
+#[derive(Default)]
+pub struct Adjacencies {
+    known: HashMap<String, Adjacency>,
+}
+
+impl Adjacencies {
+    /// Publish our links, the version is bumped only if they changed
+    pub fn refresh(&mut self, graph: &HostGraph, me: NodeIndex, identity: &Identity) {
+        let host = graph[me].clone();
+        let mut edges = graph
+            .edges_directed(me, Direction::Outgoing)
+            .map(|edge| Edge {
+                from: host.addr.clone(),
+                to: graph[edge.target()].addr.clone(),
+                weight: *edge.weight(),
+            })
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| a.to.cmp(&b.to));
+        let previous = self.known.get(&host.addr);
+        if previous.is_some_and(|previous| previous.edges == edges) {
+            return;
+        }
+        // Wall clock keeps versions growing across restarts, the counter when it doesn't
+        let version = previous
+            .map_or(0, |previous| previous.version.saturating_add(1))
+            .max(now_millis());
+        let mut adjacency = Adjacency {
+            host: Some(host.clone()),
+            version,
+            edges,
+            ..Default::default()
+        };
+        identity.sign_adjacency(&mut adjacency);
+        self.known.insert(host.addr, adjacency);
+    }
+
+    /// Versions we know, by addr
+    pub fn digest(&self) -> HashMap<String, u64> {
+        self.known
+            .iter()
+            .map(|(addr, adjacency)| (addr.clone(), adjacency.version))
+            .collect()
+    }
+
+    /// Adjacencies the owner of the digest is missing
+    pub fn newer_than(&self, digest: &HashMap<String, u64>) -> Vec<Adjacency> {
+        self.known
+            .iter()
+            .filter(|(addr, adjacency)| {
+                digest
+                    .get(*addr)
+                    .is_none_or(|version| *version < adjacency.version)
+            })
+            .map(|(_, adjacency)| adjacency.clone())
+            .collect()
+    }
+
+    /// Replace the links of every host we got a newer adjacency of, returns if anything changed
+    /// Adjacencies which aren't signed by the key of their host or are from the future are
+    /// dropped, hosts we know no key of are taken with the one they tell only if `vouched`,
+    /// when a neighbour which proved its key sent them
+    pub fn apply(
+        &mut self,
+        graph: &mut HostGraph,
+        me: NodeIndex,
+        received: Vec<Adjacency>,
+        vouched: bool,
+    ) -> bool {
+        let mut changed = false;
+        let bound = now_millis().saturating_add(MAX_SKEW);
+        for adjacency in received {
+            let Some(host) = adjacency.host.clone() else {
+                continue;
+            };
+            let addr = host.addr.clone();
+            let key = match graph
+                .find(&addr)
+                .map(|node| &graph[node].public_key)
+                .filter(|key| !key.is_empty())
+            {
+                Some(key) => key,
+                None if vouched => &host.public_key,
+                None => continue,
+            };
+            if adjacency.version > bound || !crypto::verify_adjacency(key, &adjacency) {
+                continue;
+            }
+            match self.known.get(&addr) {
+                Some(known) if known.version >= adjacency.version => continue,
+                None if self.known.len() >= MAX_HOSTS => continue,
+                _ => {}
+            }
+            if graph.find(&addr).is_none() && graph.node_count() >= MAX_HOSTS {
+                continue;
+            }
+            if addr == graph[me].addr {
+                // Our own from before a restart, the next refresh has to outrun it
+                if let Some(ours) = self.known.get_mut(&addr) {
+                    ours.version = adjacency.version;
+                    ours.edges.clear();
+                }
+                continue;
+            }
+            // Signed with the key of the host, it may lower what it speaks
+            let from = graph.upsert(host.clone());
+            graph.restate(from, &host);
+            let links = adjacency
+                .edges
+                .iter()
+                .filter(|edge| edge.from == addr)
+                .filter_map(|edge| {
+                    let to = match graph.find(&edge.to) {
+                        Some(to) => to,
+                        None if graph.node_count() < MAX_HOSTS => graph.upsert(Host {
+                            addr: edge.to.clone(),
+                            ..Default::default()
+                        }),
+                        None => return None,
+                    };
+                    let weight = if edge.weight == 0 {
+                        UNMEASURED
+                    } else {
+                        edge.weight.min(MAX_RTT)
+                    };
+                    Some((to, weight))
+                })
+                .collect::<Vec<_>>();
+            graph.replace_links(from, &links);
+            self.known.insert(addr, adjacency);
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Periodic push-pull with a random neighbour, so every node converges to the full topology
+/// Adjacencies of banned hosts are passed on untouched, the hosts just don't enter our graph
+pub struct Gossip {
+    graph: Arc<RwLock<HostGraph>>,
+    paths: Arc<PathCache>,
+    me: NodeIndex,
+    adjacencies: Mutex<Adjacencies>,
+    bans: Arc<Bans>,
+    identity: Arc<Identity>,
+}
+
+impl Gossip {
+    pub fn new(
+        graph: Arc<RwLock<HostGraph>>,
+        paths: Arc<PathCache>,
+        me: NodeIndex,
+        bans: Arc<Bans>,
+        identity: Arc<Identity>,
+    ) -> Self {
+        Self {
+            graph,
+            paths,
+            me,
+            adjacencies: Default::default(),
+            bans,
+            identity,
+        }
+    }
+
+    /// Banned hosts come back with the adjacencies of their peers, drop them again
+    fn drop_banned(&self, graph: &mut HostGraph) {
+        for (addr, _) in self.bans.list() {
+            if let Some(node) = graph.find(&addr) {
+                graph.remove(node);
+            }
+        }
+    }
+
+    /// Take what the peer sent and answer with what it is missing
+    /// Whoever calls is unproven, keys we don't know yet are only learned pulling, see `round`
+    pub async fn receive(&self, packet: GossipPacket) -> GossipPacket {
+        let mut graph = self.graph.write().await;
+        let mut adjacencies = self.adjacencies.lock().unwrap();
+        adjacencies.refresh(&graph, self.me, &self.identity);
+        if adjacencies.apply(&mut graph, self.me, packet.adjacencies, false) {
+            self.drop_banned(&mut graph);
+            self.paths.invalidate();
+        }
+        GossipPacket {
+            adjacencies: adjacencies.newer_than(&packet.versions),
+            versions: adjacencies.digest(),
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.round().await {
+                eprintln!("Failed to gossip, Report: {:#?}", err);
+            }
+        }
+    }
+
+    /// Pull what the neighbour knows better, then push what it is missing
+    async fn round(&self) -> eyre::Result<()> {
+        let (me, peer, key, digest) = {
+            let graph = self.graph.read().await;
+            let Some(node) = graph.neighbors(self.me).choose(&mut rand::thread_rng()) else {
+                return Ok(());
+            };
+            let mut adjacencies = self.adjacencies.lock().unwrap();
+            adjacencies.refresh(&graph, self.me, &self.identity);
+            (
+                graph[self.me].addr.clone(),
+                graph[node].addr.clone(),
+                graph[node].public_key.clone(),
+                adjacencies.digest(),
+            )
+        };
+        let mut client = FederationServiceClient::connect(format!("http://{}", peer))
+            .await
+            .wrap_err_with(|| format!("Failed to connect to {}", peer))?;
+        let vouched = proves(&mut client, &me, &peer, &key).await;
+        let answer = client
+            .gossip(GossipPacket {
+                adjacencies: vec![],
+                versions: digest,
+            })
+            .await
+            .map_err(|status| eyre!("{} refused gossip: {}", peer, status))?
+            .into_inner();
+        let push = {
+            let mut graph = self.graph.write().await;
+            let mut adjacencies = self.adjacencies.lock().unwrap();
+            if adjacencies.apply(&mut graph, self.me, answer.adjacencies, vouched) {
+                self.drop_banned(&mut graph);
+                self.paths.invalidate();
+            }
+            GossipPacket {
+                adjacencies: adjacencies.newer_than(&answer.versions),
+                versions: adjacencies.digest(),
+            }
+        };
+        if !push.adjacencies.is_empty() {
+            client
+                .gossip(push)
+                .await
+                .map_err(|status| eyre!("{} refused gossip: {}", peer, status))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the neighbour listening on the addr holds the key we know for it
+async fn proves(
+    client: &mut FederationServiceClient<Channel>,
+    me: &str,
+    peer: &str,
+    key: &[u8],
+) -> bool {
+    if key.is_empty() {
+        return false;
+    }
+    let mut nonce = vec![0; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let challenge = Challenge {
+        nonce: nonce.clone(),
+        addr: me.to_string(),
+    };
+    match client.prove(challenge).await {
+        Ok(proof) => {
+            let proof = proof.into_inner();
+            proof.public_key == key && crypto::verify_addr_proof(&proof, &nonce, me, peer)
+        }
+        Err(_) => false,
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, ops::Deref};
+
+use petgraph::{
+    stable_graph::{NodeIndex, StableGraph},
+    visit::{EdgeRef, IntoEdgeReferences},
+    Direction,
+};
+use prost_types::Timestamp;
+
+use entity::proto::{Edge, Host};
+
+use crate::{protocol, routing::UNMEASURED};
+
+/// Known hosts and the links between them, every host is in the graph once
+/// Hosts are indexed by addr and removing one keeps the other indexes valid
+/// Reads go through `Deref`, changes only through the methods below so the index can't drift
+#[derive(Default)]
+pub struct HostGraph {
+    graph: StableGraph<Host, u32>,
+    index: HashMap<String, NodeIndex>,
+}
+
+impl HostGraph {
+    pub fn find(&self, addr: &str) -> Option<NodeIndex> {
+        self.index.get(addr).copied()
+    }
+
+    /// Add the host or merge it into the known one
+    /// Peers only ever tell what they know, so newer `last_seen` and told versions win,
+    /// unless they are lower than the known ones, see `restate`
+    /// The first key we learn stays, anyone can tell us about a host, see `rekey`
+    pub fn upsert(&mut self, host: Host) -> NodeIndex {
+        let Some(node) = self.find(&host.addr) else {
+            let addr = host.addr.clone();
+            let node = self.graph.add_node(host);
+            self.index.insert(addr, node);
+            return node;
+        };
+        let known = &mut self.graph[node];
+        known.forwarder = host.forwarder;
+        if host.version != 0 && !protocol::downgrades(known, &host) {
+            known.version = host.version;
+            known.min_version = host.min_version;
+            known.capabilities = host.capabilities;
+        }
+        if known.public_key.is_empty() {
+            known.public_key = host.public_key;
+        }
+        let newer = match (&known.last_seen, &host.last_seen) {
+            (Some(known), Some(seen)) => (seen.seconds, seen.nanos) > (known.seconds, known.nanos),
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        if newer {
+            known.last_seen = host.last_seen;
+        }
+        node
+    }
+
+    /// Take the version and capabilities the host told, lower ones too
+    /// The caller checked the host signed them with its bound key
+    pub fn restate(&mut self, node: NodeIndex, host: &Host) {
+        if let Some(known) = self.graph.node_weight_mut(node) {
+            known.version = host.version;
+            known.min_version = host.min_version;
+            known.capabilities = host.capabilities.clone();
+        }
+    }
+
+    /// Replace the key of the host, the caller made sure the change is genuine
+    pub fn rekey(&mut self, node: NodeIndex, public_key: Vec<u8>) {
+        if let Some(host) = self.graph.node_weight_mut(node) {
+            host.public_key = public_key;
+        }
+    }
+
+    pub fn remove(&mut self, node: NodeIndex) -> Option<Host> {
+        let host = self.graph.remove_node(node)?;
+        self.index.remove(&host.addr);
+        Some(host)
+    }
+
+    pub fn seen(&mut self, node: NodeIndex, at: Timestamp) {
+        if let Some(host) = self.graph.node_weight_mut(node) {
+            host.last_seen = Some(at);
+        }
+    }
+
+    /// Link the hosts if they aren't yet, a known weight is kept
+    pub fn link(&mut self, from: NodeIndex, to: NodeIndex) {
+        if self.graph.find_edge(from, to).is_none() {
+            self.graph.add_edge(from, to, UNMEASURED);
+        }
+    }
+
+    pub fn set_weight(&mut self, from: NodeIndex, to: NodeIndex, weight: u32) {
+        self.graph.update_edge(from, to, weight);
+    }
+
+    /// Make the given links the only outgoing ones of the host
+    pub fn replace_links(&mut self, from: NodeIndex, links: &[(NodeIndex, u32)]) {
+        let stale = self
+            .graph
+            .edges_directed(from, Direction::Outgoing)
+            .filter(|edge| !links.iter().any(|(to, _)| *to == edge.target()))
+            .map(|edge| edge.id())
+            .collect::<Vec<_>>();
+        for edge in stale {
+            self.graph.remove_edge(edge);
+        }
+        for (to, weight) in links {
+            self.graph.update_edge(from, *to, *weight);
+        }
+    }
+
+    /// Drop every link of the host, the host itself stays known
+    pub fn isolate(&mut self, node: NodeIndex) {
+        let edges = self
+            .graph
+            .edges_directed(node, Direction::Incoming)
+            .chain(self.graph.edges_directed(node, Direction::Outgoing))
+            .map(|edge| edge.id())
+            .collect::<Vec<_>>();
+        for edge in edges {
+            self.graph.remove_edge(edge);
+        }
+    }
+
+    pub fn hosts(&self) -> Vec<Host> {
+        self.graph.node_weights().cloned().collect()
+    }
+
+    /// Every link we know about, shared with neighbours on heartbeat
+    pub fn links(&self) -> Vec<Edge> {
+        (&self.graph)
+            .edge_references()
+            .map(|edge| Edge {
+                from: self.graph[edge.source()].addr.clone(),
+                to: self.graph[edge.target()].addr.clone(),
+                weight: *edge.weight(),
+            })
+            .collect()
+    }
+}
+
+impl Deref for HostGraph {
+    type Target = StableGraph<Host, u32>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.graph
+    }
+}
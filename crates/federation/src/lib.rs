@@ -0,0 +1,20 @@
+pub mod admin;
+pub mod bans;
+pub mod bridge;
+pub mod client;
+pub mod crypto;
+pub mod export;
+pub mod gossip;
+pub mod graph;
+pub mod limits;
+pub mod link;
+pub mod liveness;
+pub mod onion;
+pub mod outbox;
+pub mod protocol;
+pub mod replay;
+pub mod routing;
+pub mod service;
+pub mod stats;
+pub mod topology;
+pub mod trust;
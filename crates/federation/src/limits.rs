@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use prost::Message;
+use tonic::Status;
+
+use entity::proto::{packet::Packet as PacketType, Packet};
+
+/// Strikes older than this are forgotten, a peer has to keep misbehaving to be banned
+/// Peers idle for as long have full buckets and no strikes, they are forgotten too
+pub const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Poly1305 tag the data grows by when it is sealed
+const SEAL_OVERHEAD: usize = 16;
+
+/// Room for one onion layer around the data, keys, nonce, tag and the next hop
+const LAYER_OVERHEAD: usize = 512;
+
+/// What a single peer may send us, from the `federation` section of the config
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub packets: u32,    // Per second, bursts of a second's worth are let through
+    pub bytes: u64,      // Per second, counted on encoded packets
+    pub max_data: usize, // Bytes of payload, before it is sealed
+    pub max_path: usize, // Hosts, longer paths are abuse rather than a lower `max_hops`
+    pub strikes: u32,    // Violations and malformed packets within a minute before a ban
+    pub ban: Duration,   // How long a peer which ran out of strikes stays banned
+}
+
+/// Why a packet of the peer was refused before it was even verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    TooFast,  // Dropped, the stream stays open for the packets after it
+    TooLarge, // Limits differ between servers, it isn't counted as a strike
+    PathTooLong,
+}
+
+impl From<Violation> for Status {
+    fn from(value: Violation) -> Self {
+        match value {
+            Violation::TooFast => Status::resource_exhausted("Too many packets"),
+            Violation::TooLarge => Status::invalid_argument("Packet data is too large"),
+            Violation::PathTooLong => Status::invalid_argument("Packet path is too long"),
+        }
+    }
+}
+
+/// Token buckets and strikes of every peer, by what its link is accounted to, see `handshake`
+/// Peers have their own buckets, so one flooding us doesn't starve the others
+pub struct RateLimiter {
+    limits: Limits,
+    peers: Mutex<HashMap<String, Peer>>,
+    pruned: Mutex<Instant>,
+}
+
+struct Peer {
+    packets: Bucket,
+    bytes: Bucket,
+    strikes: VecDeque<Instant>, // Oldest first
+}
+
+impl Peer {
+    fn is_idle(&self, now: Instant) -> bool {
+        let last = self
+            .strikes
+            .back()
+            .map_or(self.packets.at, |strike| (*strike).max(self.packets.at));
+        now.saturating_duration_since(last) > STRIKE_WINDOW
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    rate: f64, // Tokens per second, also the capacity
+    at: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            tokens: rate,
+            rate,
+            at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.at = now;
+        self.tokens
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            peers: Default::default(),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Take the share of the packet from the buckets of the peer, unless it breaks a limit
+    /// Oversized packets are refused before the buckets, they aren't let through anyway
+    /// Packets larger than the bytes bucket can ever hold are too, they would never fit
+    pub fn check(&self, peer: &str, packet: &Packet) -> Result<(), Violation> {
+        self.check_size(packet)?;
+        let size = packet.encoded_len() as f64;
+        if size > self.limits.bytes as f64 {
+            return Err(Violation::TooLarge);
+        }
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(peer.to_string()).or_insert_with(|| self.peer());
+        if peer.packets.refill(now) < 1.0 || peer.bytes.refill(now) < size {
+            return Err(Violation::TooFast);
+        }
+        peer.packets.tokens -= 1.0;
+        peer.bytes.tokens -= size;
+        Ok(())
+    }
+
+    /// Count a violation or a malformed packet of the peer
+    /// Returns true once it ran out of strikes, it starts over with a clean slate
+    pub fn strike(&self, peer: &str) -> bool {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        let state = peers.entry(peer.to_string()).or_insert_with(|| self.peer());
+        state.strikes.push_back(now);
+        while state
+            .strikes
+            .front()
+            .is_some_and(|strike| now.saturating_duration_since(*strike) > STRIKE_WINDOW)
+        {
+            state.strikes.pop_front();
+        }
+        if state.strikes.len() < self.limits.strikes as usize {
+            return false;
+        }
+        peers.remove(peer);
+        true
+    }
+
+    /// Forget the peers idle for the strike window, at most once per window
+    /// Returns false if the window didn't pass yet, what peers left elsewhere can stay too
+    pub fn prune(&self) -> bool {
+        let now = Instant::now();
+        let mut pruned = self.pruned.lock().unwrap();
+        if now.saturating_duration_since(*pruned) < STRIKE_WINDOW {
+            return false;
+        }
+        *pruned = now;
+        self.peers
+            .lock()
+            .unwrap()
+            .retain(|_, peer| !peer.is_idle(now));
+        true
+    }
+
+    /// Onion layers hide the path, their data is allowed a layer per hop on top
+    fn check_size(&self, packet: &Packet) -> Result<(), Violation> {
+        let (path, data, max_data) = match &packet.packet {
+            Some(PacketType::Forward(forward)) => (
+                forward.path.len(),
+                forward.data.len(),
+                self.limits.max_data + SEAL_OVERHEAD,
+            ),
+            Some(PacketType::Acknowledge(ack)) => match &ack.forward {
+                Some(forward) => (
+                    forward.path.len(),
+                    forward.data.len(),
+                    self.limits.max_data + SEAL_OVERHEAD,
+                ),
+                None => return Ok(()),
+            },
+            Some(PacketType::Onion(onion)) => (
+                0,
+                onion.layer.len(),
+                self.limits.max_data + LAYER_OVERHEAD * self.limits.max_path,
+            ),
+            Some(PacketType::OnionAck(_))
+            | Some(PacketType::Challenge(_))
+            | Some(PacketType::Proof(_))
+            | None => return Ok(()),
+        };
+        if path > self.limits.max_path {
+            return Err(Violation::PathTooLong);
+        }
+        if data > max_data {
+            return Err(Violation::TooLarge);
+        }
+        Ok(())
+    }
+
+    fn peer(&self) -> Peer {
+        Peer {
+            packets: Bucket::new(self.limits.packets as f64),
+            bytes: Bucket::new(self.limits.bytes as f64),
+            strikes: VecDeque::new(),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::sync::Arc;
+
+use tokio::sync::{broadcast::Sender, mpsc, RwLock};
+use tonic::{Status, Streaming};
+
+use entity::proto::{
+    packet::Packet as PacketType, AcknowledgePacket, ForwardPacket, OnionAck, OnionPacket, Packet,
+};
+
+use crate::{
+    crypto::{self, Identity},
+    graph::HostGraph,
+    limits::{RateLimiter, Violation},
+    onion::{self, Back, Circuits},
+    outbox::Outbox,
+    replay::Replay,
+    stats::PeerStats,
+    trust::{Rejection, TrustStore},
+};
+
+/// Packets between this server and one peer, over a `Forward` stream either side has opened
+/// Incoming packets are checked and dispatched through the outbox, which routes each one
+/// to the link of its next hop only
+/// Packets for hops without a link wait in the outbox, the link flushes them when it opens
+pub struct Link {
+    pub(crate) peer: String, // Listening addr the peer proved, as it is in paths, see `handshake`
+    pub(crate) account: String, // What limits, stats and bans go by, see `handshake`
+    pub(crate) addr: String, // Our addr
+    pub(crate) inbox: Sender<Packet>,
+    pub(crate) identity: Arc<Identity>,
+    pub(crate) trust: Arc<TrustStore>,
+    pub(crate) graph: Arc<RwLock<HostGraph>>,
+    pub(crate) outbox: Arc<Outbox>,
+    pub(crate) circuits: Arc<Circuits>,
+    pub(crate) replay: Arc<Replay>,
+    pub(crate) stats: Arc<PeerStats>,
+    pub(crate) limiter: Arc<RateLimiter>,
+    pub(crate) resign: bool,
+}
+
+impl Link {
+    /// Runs until either side of the stream is closed or the peer sends a rejected packet
+    /// Returns true if the peer ran out of strikes, see `RateLimiter`, the caller bans its account
+    pub async fn run(
+        self,
+        mut incoming: Streaming<Packet>,
+        outgoing: mpsc::Sender<Result<Packet, Status>>,
+    ) -> bool {
+        let (id, mut routed) = self.outbox.connect(&self.peer);
+        let mut banned = false;
+        tokio::select! {
+            _ = async {
+                while let Ok(Some(packet)) = incoming.message().await {
+                    let status: Status = match self.limiter.check(&self.account, &packet) {
+                        Ok(()) => match self.receive(packet).await {
+                            Ok(()) => {
+                                self.stats.received(&self.account);
+                                continue;
+                            }
+                            Err(rejection) => {
+                                self.stats.rejected(&self.account);
+                                // Trust differs between servers, untrusted packets aren't abuse
+                                if !matches!(rejection, Rejection::Untrusted(_)) {
+                                    banned = self.limiter.strike(&self.account);
+                                }
+                                rejection.into()
+                            }
+                        },
+                        // Dropped, the packets after it may fit the limits again
+                        Err(Violation::TooFast) => {
+                            self.stats.limited(&self.account);
+                            banned = self.limiter.strike(&self.account);
+                            if !banned {
+                                continue;
+                            }
+                            Violation::TooFast.into()
+                        }
+                        // Refused once, our limits may just be lower than the ones of the origin
+                        Err(Violation::TooLarge) => {
+                            self.stats.rejected(&self.account);
+                            Violation::TooLarge.into()
+                        }
+                        Err(violation) => {
+                            self.stats.rejected(&self.account);
+                            banned = self.limiter.strike(&self.account);
+                            violation.into()
+                        }
+                    };
+                    let _ = outgoing.send(Err(status)).await;
+                    break;
+                }
+            } => {}
+            _ = async {
+                while let Some(packet) = routed.recv().await {
+                    if !self.pass(&outgoing, packet).await {
+                        break;
+                    }
+                }
+            } => {}
+        }
+        // Nothing is routed to us anymore, what is left goes to another link or the queue
+        self.outbox.disconnect(&self.peer, id);
+        routed.close();
+        while let Some(packet) = routed.recv().await {
+            self.outbox.requeue(&self.peer, packet);
+        }
+        eprintln!("Link with {} closed", self.peer);
+        banned
+    }
+
+    /// Send the packet to the peer, returns false once the stream is closed
+    /// The packet goes back to the outbox if it couldn't be sent
+    async fn pass(&self, outgoing: &mpsc::Sender<Result<Packet, Status>>, packet: Packet) -> bool {
+        let Some(routed) = route(packet.clone()) else {
+            return true;
+        };
+        if outgoing.send(Ok(routed)).await.is_err() {
+            self.outbox.requeue(&self.peer, packet);
+            return false;
+        }
+        self.stats.sent(&self.account);
+        true
+    }
+
+    /// Every hop checks the packet, so untrusted traffic dies on the first one
+    /// Replayed, looping and expired packets are dropped quietly, see `Replay`
+    /// Packets reaching their end are decrypted into the inbox, the rest is passed on
+    async fn receive(&self, mut packet: Packet) -> Result<(), Rejection> {
+        self.trust.verify_packet(&packet)?;
+        match &mut packet.packet {
+            Some(PacketType::Forward(forward)) => {
+                if !self.is_current_hop(forward) || self.replay.forward(forward).is_err() {
+                    return Ok(());
+                }
+                if self.resign {
+                    self.identity.endorse(forward);
+                }
+                if forward.hop as usize + 1 == forward.path.len() {
+                    // Receiver acknowledges, the ack walks the path back to the origin
+                    // It carries the ciphertext, hops on the way back can't read it either
+                    let data = self.identity.open(forward);
+                    let mut ack = AcknowledgePacket {
+                        success: data.is_ok(),
+                        forward: Some(forward.clone()),
+                        ..Default::default()
+                    };
+                    self.identity.sign_ack(&mut ack);
+                    let ack = Packet {
+                        packet: Some(PacketType::Acknowledge(ack)),
+                    };
+                    match data {
+                        Ok(data) => {
+                            forward.data = data;
+                            let _ = self.inbox.send(packet);
+                        }
+                        Err(err) => eprintln!("Failed to open packet, Report: {:#?}", err),
+                    }
+                    self.outbox.dispatch(ack);
+                    return Ok(());
+                }
+            }
+            Some(PacketType::Acknowledge(ack)) => {
+                // Verified above, forward is always there
+                let forward = ack.forward.as_ref().unwrap();
+                if !self.is_current_hop(forward) || self.replay.acknowledge(forward).is_err() {
+                    return Ok(());
+                }
+                if forward.hop == 0 {
+                    let _ = self.inbox.send(packet);
+                    return Ok(());
+                }
+            }
+            Some(PacketType::Onion(onion)) => {
+                let onion = std::mem::take(onion);
+                return self.peel(onion).await;
+            }
+            Some(PacketType::OnionAck(ack)) => {
+                if let Some(back) = self.circuits.close(&self.peer, &ack.circuit) {
+                    onion::acknowledge(back, std::mem::take(ack), &self.outbox, &self.inbox);
+                }
+                return Ok(());
+            }
+            // Refused by the trust store above, the handshake came before the link
+            Some(PacketType::Challenge(_)) | Some(PacketType::Proof(_)) => return Ok(()),
+            None => {}
+        }
+        // Just forward this packet to the next hop
+        self.outbox.dispatch(packet);
+        Ok(())
+    }
+
+    /// Relay the inner packet to the hop our layer names, or deliver it if we are the last
+    /// Hops can't check the origin on the way, so they take onions from trusted peers only and
+    /// relay them to trusted hosts only, untrusted traffic still dies on the first hop
+    /// The peer can't check what it relays, so a bad last layer is nacked rather than rejected
+    async fn peel(&self, onion: OnionPacket) -> Result<(), Rejection> {
+        let peer = self.key_of(&self.peer).await.unwrap_or_default();
+        if !self.trust.is_trusted(&peer) {
+            return Err(Rejection::Untrusted(peer));
+        }
+        if self.replay.circuit(&onion.circuit).is_err() {
+            return Ok(());
+        }
+        let layer = onion::peel(&self.identity, &onion)
+            .map_err(|_| Rejection::Malformed("Onion layer can't be opened"))?;
+        let back = Back::Relay {
+            peer: self.peer.clone(),
+            circuit: onion.circuit,
+        };
+        if layer.next_hop.is_empty() {
+            // Verified first, a forged layer must not burn the id of its claimed origin
+            let mut ack = OnionAck::default();
+            match self.trust.verify_layer(&layer) {
+                Ok(()) if self.replay.layer(&layer).is_err() => return Ok(()),
+                Ok(()) => {
+                    ack.success = true;
+                    self.identity.sign_onion_ack(&layer.id, &mut ack);
+                    // The path stays unknown to us, only who signed the data
+                    let delivered = ForwardPacket {
+                        data: layer.data,
+                        origin: layer.origin,
+                        signature: layer.signature,
+                        id: layer.id,
+                        sent_at: layer.sent_at,
+                        ..Default::default()
+                    };
+                    let _ = self.inbox.send(Packet {
+                        packet: Some(PacketType::Forward(delivered)),
+                    });
+                }
+                Err(rejection) => {
+                    eprintln!("Failed to deliver onion packet, Report: {:#?}", rejection);
+                }
+            }
+            onion::acknowledge(back, ack, &self.outbox, &self.inbox);
+            return Ok(());
+        }
+        let inner = layer
+            .inner
+            .ok_or(Rejection::Malformed("Onion layer without next packet"))?;
+        let next_hop = self.key_of(&layer.next_hop).await;
+        if !next_hop.is_some_and(|key| self.trust.is_trusted(&key)) {
+            eprintln!(
+                "Failed to relay onion packet, Report: next hop {} is unknown or untrusted",
+                layer.next_hop
+            );
+            onion::acknowledge(back, OnionAck::default(), &self.outbox, &self.inbox);
+            return Ok(());
+        }
+        if let Err(back) = self
+            .circuits
+            .open(&layer.next_hop, inner.circuit.clone(), back)
+        {
+            eprintln!("Failed to relay onion packet, Report: too many open circuits");
+            onion::acknowledge(back, OnionAck::default(), &self.outbox, &self.inbox);
+            return Ok(());
+        }
+        self.outbox.dispatch_to(
+            &layer.next_hop,
+            Packet {
+                packet: Some(PacketType::Onion(inner)),
+            },
+        );
+        Ok(())
+    }
+
+    /// Key bound to the host, peers we know no host of are known by their key, see `handshake`
+    async fn key_of(&self, peer: &str) -> Option<Vec<u8>> {
+        if let Some(key) = peer.strip_prefix("key:") {
+            return crypto::decode_key(key).ok();
+        }
+        let graph = self.graph.read().await;
+        graph
+            .find(peer)
+            .map(|node| graph[node].public_key.clone())
+            .filter(|key| !key.is_empty())
+    }
+
+    fn is_current_hop(&self, packet: &ForwardPacket) -> bool {
+        packet
+            .path
+            .get(packet.hop as usize)
+            .is_some_and(|host| host.addr == self.addr)
+    }
+}
+
+/// Move the packet one hop, forwards go up the path and acks go down
+fn route(packet: Packet) -> Option<Packet> {
+    match packet.packet? {
+        PacketType::Forward(mut packet) => {
+            packet.hop += 1;
+            Some(Packet {
+                packet: Some(PacketType::Forward(packet)),
+            })
+        }
+        PacketType::Acknowledge(mut ack_packet) => {
+            // IDK why prost have option on required field, hate this spec so much
+            let mut packet = ack_packet.forward.take()?;
+            packet.hop = packet.hop.checked_sub(1)?;
+            ack_packet.forward = Some(packet);
+            Some(Packet {
+                packet: Some(PacketType::Acknowledge(ack_packet)),
+            })
+        }
+        // Layers are peeled on receive, nothing to move
+        packet @ (PacketType::Onion(_) | PacketType::OnionAck(_)) => Some(Packet {
+            packet: Some(packet),
+        }),
+        PacketType::Challenge(_) | PacketType::Proof(_) => None,
+    }
+}
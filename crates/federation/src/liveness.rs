@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::join_all;
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+use prost_types::Timestamp;
+use tokio::sync::RwLock;
+
+use tonic::transport::Channel;
+
+use entity::proto::{federation_service_client::FederationServiceClient, HeartbeatPacket, Host};
+
+use crate::{
+    graph::HostGraph,
+    routing::{self, PathCache, UNMEASURED},
+};
+
+/// Percent a link weight has to move, from when paths were last computed, to reroute
+const REROUTE_PERCENT: u32 = 20;
+
+/// Heartbeats our neighbours and keeps `Host.last_seen` fresh
+/// Round-trip times become the weights of our edges, peers learn them through gossip
+/// Edges of a host not seen for `stale_timeout` are dropped so paths avoid it,
+/// it is still probed and its edge comes back with the first answered heartbeat
+/// Cached paths are dropped when a host goes stale or comes back, or a weight moved enough
+pub struct Liveness {
+    graph: Arc<RwLock<HostGraph>>,
+    paths: Arc<PathCache>,
+    me: NodeIndex,
+    interval: Duration,
+    stale_timeout: Duration,
+    stale: HashSet<NodeIndex>, // Former neighbours we still probe
+    missed_since: HashMap<NodeIndex, i64>, // First missed heartbeat of hosts never seen
+    routed: HashMap<NodeIndex, u32>, // Weights of our edges when paths were dropped
+    clients: HashMap<String, FederationServiceClient<Channel>>, // Kept between heartbeats
+}
+
+impl Liveness {
+    pub fn new(
+        graph: Arc<RwLock<HostGraph>>,
+        paths: Arc<PathCache>,
+        me: NodeIndex,
+        interval: Duration,
+        stale_timeout: Duration,
+    ) -> Self {
+        Self {
+            graph,
+            paths,
+            me,
+            interval,
+            stale_timeout,
+            stale: HashSet::new(),
+            missed_since: HashMap::new(),
+            routed: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            self.beat().await;
+        }
+    }
+
+    async fn beat(&mut self) {
+        let (heartbeat, targets) = {
+            let graph = self.graph.read().await;
+            // Hosts removed by the operator aren't probed anymore
+            self.stale.retain(|node| graph.contains_node(*node));
+            self.missed_since
+                .retain(|node, _| graph.contains_node(*node));
+            self.routed.retain(|node, _| graph.contains_node(*node));
+            let targets = graph
+                .neighbors_directed(self.me, Direction::Outgoing)
+                .chain(self.stale.iter().copied())
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .map(|node| (node, graph[node].addr.clone()))
+                .collect::<Vec<_>>();
+            self.clients
+                .retain(|addr, _| targets.iter().any(|(_, target)| target == addr));
+            let heartbeat = HeartbeatPacket {
+                host: Some(graph[self.me].clone()),
+            };
+            (heartbeat, targets)
+        };
+        let answers = join_all(targets.iter().map(|(_, addr)| {
+            let client = self.clients.remove(addr);
+            beat(addr, client, heartbeat.clone(), self.interval)
+        }))
+        .await;
+
+        let now = now();
+        let graph = self.graph.clone();
+        let mut graph = graph.write().await;
+        let mut reroute = false;
+        for ((node, addr), (answer, client)) in targets.into_iter().zip(answers) {
+            if !graph.contains_node(node) {
+                continue; // Removed while we waited for the answers
+            }
+            if let Some(rtt) = answer {
+                self.missed_since.remove(&node);
+                reroute |= self.stale.remove(&node);
+                graph.seen(node, now.clone());
+                routing::record_rtt(&mut graph, self.me, node, rtt);
+            } else if !self.stale.contains(&node) && self.is_stale(node, &graph[node], &now) {
+                graph.isolate(node);
+                self.stale.insert(node);
+                reroute = true;
+            }
+            if let Some(client) = client {
+                self.clients.insert(addr, client);
+            }
+        }
+        let weights = graph
+            .edges_directed(self.me, Direction::Outgoing)
+            .map(|edge| (edge.target(), *edge.weight()))
+            .collect::<HashMap<_, _>>();
+        reroute |= weights.iter().any(|(node, weight)| {
+            self.routed
+                .get(node)
+                .is_none_or(|routed| moved(*routed, *weight))
+        });
+        if reroute {
+            self.paths.invalidate();
+            self.routed = weights;
+        }
+    }
+
+    /// Hosts never seen get a full timeout from the first missed heartbeat
+    fn is_stale(&mut self, node: NodeIndex, host: &Host, now: &Timestamp) -> bool {
+        let last_seen = match &host.last_seen {
+            Some(last_seen) => last_seen.seconds,
+            None => *self.missed_since.entry(node).or_insert(now.seconds),
+        };
+        now.seconds - last_seen >= self.stale_timeout.as_secs() as i64
+    }
+}
+
+/// Whether paths computed with the old weight may not be the lowest latency ones anymore
+fn moved(old: u32, new: u32) -> bool {
+    if old == UNMEASURED || new == UNMEASURED {
+        return old != new;
+    }
+    old.abs_diff(new).saturating_mul(100) >= old.saturating_mul(REROUTE_PERCENT)
+}
+
+/// Round-trip time of the heartbeat, connecting is not measured
+/// The connection is handed back for the next heartbeat, unless the heartbeat failed
+async fn beat(
+    addr: &str,
+    client: Option<FederationServiceClient<Channel>>,
+    heartbeat: HeartbeatPacket,
+    timeout: Duration,
+) -> (Option<Duration>, Option<FederationServiceClient<Channel>>) {
+    let call = async {
+        let mut client = match client {
+            Some(client) => client,
+            None => FederationServiceClient::connect(format!("http://{}", addr)).await?,
+        };
+        let sent = Instant::now();
+        client.heartbeat(heartbeat).await?;
+        eyre::Ok((sent.elapsed(), client))
+    };
+    match tokio::time::timeout(timeout, call).await {
+        Ok(Ok((rtt, client))) => (Some(rtt), Some(client)),
+        _ => (None, None),
+    }
+}
+
+pub fn now() -> Timestamp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    Timestamp {
+        seconds: now.as_secs() as i64,
+        nanos: now.subsec_nanos() as i32,
+    }
+}
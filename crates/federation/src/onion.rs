@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use eyre::eyre;
+use prost::Message;
+use rand::RngCore;
+use tokio::sync::broadcast::Sender;
+
+use entity::proto::{
+    packet::Packet as PacketType, AcknowledgePacket, ForwardPacket, Host, OnionAck, OnionLayer,
+    OnionPacket, Packet,
+};
+
+use crate::{
+    crypto::{self, Identity},
+    outbox::{unix_now, Outbox},
+};
+
+/// Bounds the memory peers can make us spend on circuits they never acknowledge
+const MAX_CIRCUITS: usize = 65536;
+
+/// A packet can wait the outbox TTL on every hop, circuits outlive the longest sane path
+const MAX_HOPS: u32 = 16;
+
+/// Back leg and when it was opened, by next hop and circuit id
+type Routes = HashMap<(String, Vec<u8>), (Instant, Back)>;
+
+/// Where the acks of a circuit go once they come back to us
+pub enum Back {
+    Relay { peer: String, circuit: Vec<u8> }, // Previous hop and its id of the circuit
+    Origin(Box<ForwardPacket>),               // We sent it, the ack goes to the inbox
+}
+
+/// Return leg of every circuit going through us, keyed by the next hop and its circuit id
+/// Ids are chosen by the origin for every link, so hops can't tie them to each other
+pub struct Circuits {
+    routes: Mutex<Routes>,
+    ttl: Duration,
+}
+
+impl Circuits {
+    pub fn new(outbox_ttl: Duration) -> Self {
+        Self {
+            routes: Default::default(),
+            ttl: outbox_ttl * MAX_HOPS,
+        }
+    }
+
+    /// Remember where acks coming from the next hop go
+    /// The back leg is returned if there are too many circuits already
+    pub fn open(&self, next_hop: &str, circuit: Vec<u8>, back: Back) -> Result<(), Back> {
+        let mut routes = self.routes.lock().unwrap();
+        if routes.len() >= MAX_CIRCUITS {
+            let now = Instant::now();
+            routes.retain(|_, (opened, _)| now.duration_since(*opened) < self.ttl);
+            if routes.len() >= MAX_CIRCUITS {
+                return Err(back);
+            }
+        }
+        routes.insert((next_hop.to_string(), circuit), (Instant::now(), back));
+        Ok(())
+    }
+
+    /// Every circuit carries a single packet, it is gone once acknowledged
+    pub fn close(&self, next_hop: &str, circuit: &[u8]) -> Option<Back> {
+        self.routes
+            .lock()
+            .unwrap()
+            .remove(&(next_hop.to_string(), circuit.to_vec()))
+            .map(|(_, back)| back)
+    }
+}
+
+/// Wrap the data in one layer per hop after us, the path must start with us
+/// Every hop needs a known key, returns the packet for the first hop
+pub fn wrap(
+    identity: &Identity,
+    path: &[Host],
+    id: Vec<u8>,
+    data: Vec<u8>,
+) -> eyre::Result<OnionPacket> {
+    let mut layer = OnionLayer {
+        data,
+        id,
+        sent_at: unix_now(),
+        ..Default::default()
+    };
+    identity.sign_layer(&mut layer);
+    let mut packet = None;
+    for (at, hop) in path.iter().enumerate().skip(1).rev() {
+        if hop.public_key.is_empty() {
+            return Err(eyre!("Public key of {} is not known", hop.addr));
+        }
+        if let Some(inner) = packet.take() {
+            layer = OnionLayer {
+                next_hop: path[at + 1].addr.clone(),
+                inner: Some(inner),
+                ..Default::default()
+            };
+        }
+        let (ephemeral, nonce, sealed) = crypto::encrypt(&hop.public_key, &layer.encode_to_vec())?;
+        let mut circuit = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut circuit);
+        packet = Some(OnionPacket {
+            circuit,
+            ephemeral,
+            nonce,
+            layer: sealed,
+        });
+    }
+    packet.ok_or_else(|| eyre!("Path must have a hop after this server"))
+}
+
+/// Open the layer sealed to us
+pub fn peel(identity: &Identity, packet: &OnionPacket) -> eyre::Result<OnionLayer> {
+    let layer = identity.decrypt(&packet.ephemeral, &packet.nonce, &packet.layer)?;
+    Ok(OnionLayer::decode(&*layer)?)
+}
+
+/// Send the ack one hop back through the circuit, or hand it to the inbox if we sent the packet
+/// Hops pass the signature of the last hop on, a success counts only if it verifies at the origin
+pub fn acknowledge(back: Back, ack: OnionAck, outbox: &Outbox, inbox: &Sender<Packet>) {
+    match back {
+        Back::Relay { peer, circuit } => outbox.dispatch_to(
+            &peer,
+            Packet {
+                packet: Some(PacketType::OnionAck(OnionAck { circuit, ..ack })),
+            },
+        ),
+        Back::Origin(forward) => {
+            let success = ack.success
+                && forward.path.last().is_some_and(|receiver| {
+                    crypto::verify_onion_ack(&receiver.public_key, &forward.id, &ack)
+                });
+            let _ = inbox.send(Packet {
+                packet: Some(PacketType::Acknowledge(AcknowledgePacket {
+                    success,
+                    forward: Some(*forward),
+                    signature: if success { ack.signature } else { vec![] },
+                })),
+            });
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use prost::Message;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use entity::proto::{packet::Packet as PacketType, Packet, QueuedPacket};
+
+/// Longest wait between two dials of an unreachable hop, in multiples of the base retry
+const MAX_BACKOFF: u32 = 32;
+
+/// Routing table of the open links and packets waiting for their next hop to connect
+/// Every packet we pass on goes through `dispatch`: a connected hop gets it on the channel
+/// of its own link only, the others get it queued until they connect or the TTL runs out
+/// Queues are persisted when there is a topology store, so a restart doesn't lose them
+/// Queues and link channels hold `capacity` packets, a full queue drops its oldest packet
+/// and a full channel the new one, their origins get a negative ack with the next `expire`
+pub struct Outbox {
+    state: Mutex<State>,
+    tree: Option<sled::Tree>,
+    ttl: Duration,
+    retry: Duration,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct State {
+    routes: HashMap<String, Vec<(u64, Sender<Packet>)>>, // Open links by peer, newest last
+    queues: HashMap<String, VecDeque<(Vec<u8>, QueuedPacket)>>, // Store key and packet
+    backoff: HashMap<String, (Instant, Duration)>,       // Next dial and current delay
+    dropped: Vec<(String, Packet)>,                      // Pushed out by a full queue or channel
+    seq: u64,
+    links: u64, // Id of the next registered link
+}
+
+impl Outbox {
+    /// Queued packets of the previous run are restored from the tree
+    pub fn new(
+        tree: Option<sled::Tree>,
+        ttl: Duration,
+        retry: Duration,
+        capacity: usize,
+    ) -> Result<Self> {
+        let capacity = capacity.max(1);
+        let mut state = State::default();
+        if let Some(tree) = &tree {
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                let queued =
+                    QueuedPacket::decode(&*value).wrap_err("Queued packet is corrupted")?;
+                if let Some(seq) = key.len().checked_sub(8).map(|at| &key[at..]) {
+                    state.seq = state.seq.max(u64::from_be_bytes(seq.try_into()?) + 1);
+                }
+                state
+                    .queues
+                    .entry(queued.next_hop.clone())
+                    .or_default()
+                    .push_back((key.to_vec(), queued));
+            }
+        }
+        let outbox = Self {
+            state: Mutex::new(state),
+            tree,
+            ttl,
+            retry,
+            capacity,
+        };
+        // The capacity may have shrunk since the previous run
+        let mut state = outbox.state.lock().unwrap();
+        for queue in state.queues.values_mut() {
+            while queue.len() > capacity {
+                let (key, _) = queue.pop_front().unwrap();
+                outbox.forget(&key);
+            }
+        }
+        drop(state);
+        Ok(outbox)
+    }
+
+    /// Pass the packet on to the link of its next hop, or queue it if the hop isn't connected
+    pub fn dispatch(&self, packet: Packet) {
+        let Some(next_hop) = next_hop(&packet) else {
+            return;
+        };
+        // Locked while sending, so a hop connecting meanwhile can't miss the packet
+        let mut state = self.state.lock().unwrap();
+        self.route(&mut state, next_hop, packet);
+    }
+
+    /// Pass the packet on to the link of the hop, for packets which don't tell their next hop
+    pub fn dispatch_to(&self, next_hop: &str, packet: Packet) {
+        let mut state = self.state.lock().unwrap();
+        self.route(&mut state, next_hop.to_string(), packet);
+    }
+
+    /// Packet routed to the peer couldn't be written to its stream
+    /// Another link to the peer gets it if there is one, otherwise it waits for the next
+    pub fn requeue(&self, peer: &str, packet: Packet) {
+        self.dispatch_to(peer, packet);
+    }
+
+    /// Register a link to the peer, the channel starts with everything queued for it
+    /// Returns the id to disconnect with
+    pub fn connect(&self, peer: &str) -> (u64, Receiver<Packet>) {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        let mut state = self.state.lock().unwrap();
+        // Queues never hold more than the channel does
+        for (key, queued) in state.queues.remove(peer).unwrap_or_default() {
+            self.forget(&key);
+            if let Some(packet) = queued.packet {
+                let _ = tx.try_send(packet);
+            }
+        }
+        state.backoff.remove(peer);
+        let id = state.links;
+        state.links += 1;
+        state
+            .routes
+            .entry(peer.to_string())
+            .or_default()
+            .push((id, tx));
+        (id, rx)
+    }
+
+    /// Remove the link from the table, packets still on its channel have to be requeued
+    pub fn disconnect(&self, peer: &str, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(routes) = state.routes.get_mut(peer) {
+            routes.retain(|(link, _)| *link != id);
+            if routes.is_empty() {
+                state.routes.remove(peer);
+            }
+        }
+    }
+
+    /// Unreachable hops which have packets waiting and are due for a dial
+    /// Every call pushes the next dial of the returned hops further away
+    pub fn due(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let peers = state
+            .queues
+            .keys()
+            .filter(|peer| !state.routes.contains_key(*peer))
+            .filter(|peer| {
+                state
+                    .backoff
+                    .get(*peer)
+                    .is_none_or(|(next, _)| *next <= now)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for peer in &peers {
+            let delay = match state.backoff.get(peer) {
+                Some((_, delay)) => (*delay * 2).min(self.retry * MAX_BACKOFF),
+                None => self.retry,
+            };
+            state.backoff.insert(peer.clone(), (now + delay, delay));
+        }
+        peers
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Every packet waiting for its hop, oldest first for every hop
+    pub fn queued(&self) -> Vec<QueuedPacket> {
+        let state = self.state.lock().unwrap();
+        state
+            .queues
+            .values()
+            .flatten()
+            .map(|(_, queued)| queued.clone())
+            .collect()
+    }
+
+    /// Open links and queued packets, by peer
+    pub fn status(&self) -> HashMap<String, (usize, usize)> {
+        let state = self.state.lock().unwrap();
+        let mut status = HashMap::<_, (usize, usize)>::new();
+        for (peer, routes) in &state.routes {
+            status.entry(peer.clone()).or_default().0 = routes.len();
+        }
+        for (peer, queue) in &state.queues {
+            status.entry(peer.clone()).or_default().1 = queue.len();
+        }
+        status
+    }
+
+    /// Close every link of the peer and return the packets queued for it
+    /// Links end once their channel is gone, what they still had is queued again
+    pub fn close(&self, peer: &str) -> Vec<(String, Packet)> {
+        let mut state = self.state.lock().unwrap();
+        state.routes.remove(peer);
+        state.backoff.remove(peer);
+        let mut closed = vec![];
+        for (key, queued) in state.queues.remove(peer).unwrap_or_default() {
+            self.forget(&key);
+            closed.extend(queued.packet.map(|packet| (queued.next_hop, packet)));
+        }
+        closed
+    }
+
+    /// Drop the packets which waited longer than the TTL and return them with their next hop
+    /// Packets dropped meanwhile for a full queue or channel are returned too
+    pub fn expire(&self) -> Vec<(String, Packet)> {
+        let deadline = unix_now().saturating_sub(self.ttl.as_secs());
+        let mut state = self.state.lock().unwrap();
+        let mut expired = std::mem::take(&mut state.dropped);
+        for queue in state.queues.values_mut() {
+            while queue
+                .front()
+                .is_some_and(|(_, queued)| queued.queued_at <= deadline)
+            {
+                let (key, queued) = queue.pop_front().unwrap();
+                self.forget(&key);
+                expired.extend(queued.packet.map(|packet| (queued.next_hop, packet)));
+            }
+        }
+        state.queues.retain(|_, queue| !queue.is_empty());
+        let State {
+            queues, backoff, ..
+        } = &mut *state;
+        backoff.retain(|peer, _| queues.contains_key(peer));
+        expired
+    }
+
+    /// Send on the newest link of the hop, closed links are dropped on the way
+    /// A link which can't keep up drops the packet rather than buffering without end
+    fn route(&self, state: &mut State, next_hop: String, mut packet: Packet) {
+        if let Some(routes) = state.routes.get_mut(&next_hop) {
+            while let Some((_, tx)) = routes.last() {
+                match tx.try_send(packet) {
+                    Ok(()) => return,
+                    Err(TrySendError::Full(returned)) => {
+                        state.dropped.push((next_hop, returned));
+                        return;
+                    }
+                    Err(TrySendError::Closed(returned)) => {
+                        packet = returned;
+                        routes.pop();
+                    }
+                }
+            }
+            state.routes.remove(&next_hop);
+        }
+        self.queue(state, next_hop, packet);
+    }
+
+    fn queue(&self, state: &mut State, next_hop: String, packet: Packet) {
+        let mut key = next_hop.as_bytes().to_vec();
+        key.push(0);
+        key.extend(state.seq.to_be_bytes());
+        state.seq += 1;
+        let queued = QueuedPacket {
+            next_hop: next_hop.clone(),
+            packet: Some(packet),
+            queued_at: unix_now(),
+        };
+        if let Some(tree) = &self.tree {
+            if let Err(err) = tree.insert(&key, queued.encode_to_vec()) {
+                eprintln!("Failed to persist queued packet, Report: {:#?}", err);
+            }
+        }
+        let State {
+            queues, dropped, ..
+        } = state;
+        let queue = queues.entry(next_hop).or_default();
+        queue.push_back((key, queued));
+        while queue.len() > self.capacity {
+            let (key, oldest) = queue.pop_front().unwrap();
+            self.forget(&key);
+            dropped.extend(oldest.packet.map(|packet| (oldest.next_hop, packet)));
+        }
+    }
+
+    fn forget(&self, key: &[u8]) {
+        if let Some(tree) = &self.tree {
+            if let Err(err) = tree.remove(key) {
+                eprintln!("Failed to remove queued packet, Report: {:#?}", err);
+            }
+        }
+    }
+}
+
+/// Forwards go up the path and acks go down, onion packets don't tell
+pub fn next_hop(packet: &Packet) -> Option<String> {
+    let (forward, next) = match packet.packet.as_ref()? {
+        PacketType::Forward(forward) => (forward, forward.hop.checked_add(1)?),
+        PacketType::Acknowledge(ack) => {
+            let forward = ack.forward.as_ref()?;
+            (forward, forward.hop.checked_sub(1)?)
+        }
+        // Only the hop which peeled the layer knows, see `dispatch_to`
+        PacketType::Onion(_) | PacketType::OnionAck(_) => return None,
+        // Only ever on the stream they belong to
+        PacketType::Challenge(_) | PacketType::Proof(_) => return None,
+    };
+    forward
+        .path
+        .get(next as usize)
+        .map(|host| host.addr.clone())
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
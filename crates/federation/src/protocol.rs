@@ -0,0 +1,136 @@
+use eyre::{eyre, Result};
+
+use entity::proto::{Capabilities, Compression, Encryption, Host, PacketKind, Signing};
+
+/// Protocol version this build speaks
+/// 1 is every node from before hosts told their version, 2 added versions and capabilities
+pub const VERSION: u32 = 2;
+
+/// Oldest version we still talk to
+pub const MIN_VERSION: u32 = 1;
+
+/// Nodes which don't tell their version are the first one
+const LEGACY_VERSION: u32 = 1;
+
+/// Every node of this build relays onion packets, whether it sends them or not
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        packets: vec![
+            PacketKind::Forward as i32,
+            PacketKind::Acknowledge as i32,
+            PacketKind::Onion as i32,
+            PacketKind::OnionAck as i32,
+        ],
+        compression: vec![Compression::Uncompressed as i32],
+        encryption: vec![Encryption::X25519Chacha20Poly1305 as i32],
+        signing: vec![Signing::Ed25519 as i32],
+    }
+}
+
+/// What the first nodes did, plain packets which are neither sealed nor signed
+pub fn baseline() -> Capabilities {
+    Capabilities {
+        packets: vec![PacketKind::Forward as i32, PacketKind::Acknowledge as i32],
+        compression: vec![Compression::Uncompressed as i32],
+        encryption: vec![Encryption::Unencrypted as i32],
+        signing: vec![Signing::Unsigned as i32],
+    }
+}
+
+/// Tell peers what we speak through our host
+pub fn stamp(host: &mut Host) {
+    host.version = VERSION;
+    host.min_version = MIN_VERSION;
+    host.capabilities = Some(capabilities());
+}
+
+/// Highest version both sides speak, an error tells why the host can't be talked to
+/// Besides the version, the host has to take forward packets and their acks
+/// Hosts which can't decrypt or sign get plain packets, see `seals` and `signs`
+pub fn negotiate(host: &Host) -> Result<u32> {
+    let (version, min_version) = versions(host);
+    if version < MIN_VERSION {
+        return Err(eyre!(
+            "{} speaks protocol version {}, {} at least is needed",
+            host.addr,
+            version,
+            MIN_VERSION
+        ));
+    }
+    if min_version > VERSION {
+        return Err(eyre!(
+            "{} needs protocol version {} at least, we speak {}",
+            host.addr,
+            min_version,
+            VERSION
+        ));
+    }
+    let capabilities = capabilities_of(host);
+    if !capabilities.packets.contains(&(PacketKind::Forward as i32))
+        || !capabilities
+            .packets
+            .contains(&(PacketKind::Acknowledge as i32))
+    {
+        return Err(eyre!("{} doesn't take forward packets", host.addr));
+    }
+    Ok(version.min(VERSION))
+}
+
+/// Whether the host opens data sealed to its key, see `FederationService::send`
+pub fn seals(host: &Host) -> bool {
+    capabilities_of(host)
+        .encryption
+        .contains(&(Encryption::X25519Chacha20Poly1305 as i32))
+}
+
+/// Whether the host signs what it sends, its success acks are required to be signed then
+pub fn signs(host: &Host) -> bool {
+    capabilities_of(host)
+        .signing
+        .contains(&(Signing::Ed25519 as i32))
+}
+
+/// Whether what is told of the host is less than what we know it speaks
+/// Only the host itself lowers that, with its bound key, see `HostGraph::restate`
+pub fn downgrades(known: &Host, told: &Host) -> bool {
+    let (known_capabilities, told_capabilities) = (capabilities_of(known), capabilities_of(told));
+    // Zero is the plain fallback of each list, leaving it behind is no loss
+    let dropped = |known: &[i32], told: &[i32]| {
+        known
+            .iter()
+            .any(|value| *value != 0 && !told.contains(value))
+    };
+    versions(told).0 < versions(known).0
+        || dropped(&known_capabilities.packets, &told_capabilities.packets)
+        || dropped(
+            &known_capabilities.compression,
+            &told_capabilities.compression,
+        )
+        || dropped(
+            &known_capabilities.encryption,
+            &told_capabilities.encryption,
+        )
+        || dropped(&known_capabilities.signing, &told_capabilities.signing)
+}
+
+/// Whether the host takes packets of the kind, older hosts get simpler packets instead
+pub fn supports(host: &Host, kind: PacketKind) -> bool {
+    capabilities_of(host).packets.contains(&(kind as i32))
+}
+
+/// Hosts which never told their version speak the first one, whose packets were the same
+/// Hosts only known by addr look the same, so both get the least we can assume
+fn versions(host: &Host) -> (u32, u32) {
+    match host.version {
+        0 => (LEGACY_VERSION, LEGACY_VERSION),
+        version => (version, host.min_version),
+    }
+}
+
+/// Hosts which don't tell their capabilities get the baseline
+fn capabilities_of(host: &Host) -> Capabilities {
+    match (&host.capabilities, host.version) {
+        (Some(capabilities), 1..) => capabilities.clone(),
+        _ => baseline(),
+    }
+}
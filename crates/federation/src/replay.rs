@@ -0,0 +1,169 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use entity::proto::{ForwardPacket, OnionLayer};
+
+use crate::outbox::unix_now;
+
+/// Bounds the memory spent on remembering packets, the oldest are forgotten first
+const MAX_SEEN: usize = 65536;
+
+/// Seconds a packet may claim to be from the future, clocks of servers drift
+const MAX_SKEW: u64 = 60;
+
+/// Why a packet was dropped instead of being passed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Duplicate,
+    Looped,
+    Expired,
+    TooManyHops,
+}
+
+/// How many packets were dropped for every reason since the start
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DropCounts {
+    pub duplicate: u64,
+    pub looped: u64,
+    pub expired: u64,
+    pub too_many_hops: u64,
+}
+
+/// Packets we have seen, so replayed, looping and expired ones die on their first hop
+/// Ids are remembered for the packet TTL at most, older packets are dropped as expired anyway
+/// Ids are picked by their origin, they are remembered with its key so nobody else can burn them
+pub struct Replay {
+    seen: Mutex<Seen>,
+    ttl: u64, // Seconds
+    max_hops: u32,
+    duplicate: AtomicU64,
+    looped: AtomicU64,
+    expired: AtomicU64,
+    too_many_hops: AtomicU64,
+}
+
+#[derive(Default)]
+struct Seen {
+    keys: HashSet<Vec<u8>>,
+    order: VecDeque<(u64, Vec<u8>)>, // Unix seconds to forget at, oldest first
+}
+
+impl Replay {
+    pub fn new(ttl: Duration, max_hops: u32) -> Self {
+        Self {
+            seen: Default::default(),
+            ttl: ttl.as_secs(),
+            max_hops,
+            duplicate: Default::default(),
+            looped: Default::default(),
+            expired: Default::default(),
+            too_many_hops: Default::default(),
+        }
+    }
+
+    /// Limit put on the packets we send
+    pub fn max_hops(&self) -> u32 {
+        self.max_hops
+    }
+
+    /// Remember the forward packet, unless it has to be dropped
+    pub fn forward(&self, packet: &ForwardPacket) -> Result<(), DropReason> {
+        self.count(self.check(b'f', packet))
+    }
+
+    /// Acks are remembered apart from their forward packet, every hop sees both once
+    pub fn acknowledge(&self, packet: &ForwardPacket) -> Result<(), DropReason> {
+        self.count(self.check(b'a', packet))
+    }
+
+    /// Every link of an onion circuit has its own id, the timestamp is only in the last layer
+    pub fn circuit(&self, circuit: &[u8]) -> Result<(), DropReason> {
+        self.count(self.remember(key(b'o', &[], circuit)))
+    }
+
+    /// Last layer of an onion packet, its hops couldn't see the id and timestamp
+    pub fn layer(&self, layer: &OnionLayer) -> Result<(), DropReason> {
+        let result = self
+            .fresh(layer.sent_at)
+            .and_then(|_| self.remember(key(b'l', &layer.origin, &layer.id)));
+        self.count(result)
+    }
+
+    pub fn drops(&self) -> DropCounts {
+        DropCounts {
+            duplicate: self.duplicate.load(Ordering::Relaxed),
+            looped: self.looped.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            too_many_hops: self.too_many_hops.load(Ordering::Relaxed),
+        }
+    }
+
+    fn check(&self, kind: u8, packet: &ForwardPacket) -> Result<(), DropReason> {
+        let max_hops = self.max_hops.min(packet.max_hops) as usize;
+        if packet.path.len() > max_hops || packet.hop as usize >= max_hops {
+            return Err(DropReason::TooManyHops);
+        }
+        let mut hosts = HashSet::new();
+        if !packet.path.iter().all(|host| hosts.insert(&host.addr)) {
+            return Err(DropReason::Looped);
+        }
+        self.fresh(packet.sent_at)?;
+        self.remember(key(kind, &packet.origin, &packet.id))
+    }
+
+    fn fresh(&self, sent_at: u64) -> Result<(), DropReason> {
+        let now = unix_now();
+        if sent_at.saturating_add(self.ttl) < now || sent_at > now + MAX_SKEW {
+            return Err(DropReason::Expired);
+        }
+        Ok(())
+    }
+
+    fn remember(&self, key: Vec<u8>) -> Result<(), DropReason> {
+        let now = unix_now();
+        let mut seen = self.seen.lock().unwrap();
+        while seen
+            .order
+            .front()
+            .is_some_and(|(forget_at, _)| *forget_at <= now)
+            || seen.order.len() >= MAX_SEEN
+        {
+            let (_, forgotten) = seen.order.pop_front().unwrap();
+            seen.keys.remove(&forgotten);
+        }
+        if !seen.keys.insert(key.clone()) {
+            return Err(DropReason::Duplicate);
+        }
+        // Packets stay fresh for the TTL after they were sent, at most the skew from now
+        seen.order.push_back((now + self.ttl + MAX_SKEW, key));
+        Ok(())
+    }
+
+    fn count(&self, result: Result<(), DropReason>) -> Result<(), DropReason> {
+        if let Err(reason) = result {
+            let counter = match reason {
+                DropReason::Duplicate => &self.duplicate,
+                DropReason::Looped => &self.looped,
+                DropReason::Expired => &self.expired,
+                DropReason::TooManyHops => &self.too_many_hops,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+fn key(kind: u8, origin: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(origin.len() + id.len() + 9);
+    key.push(kind);
+    key.extend_from_slice(&(origin.len() as u64).to_be_bytes());
+    key.extend_from_slice(origin);
+    key.extend_from_slice(id);
+    key
+}
@@ -0,0 +1,61 @@
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+use petgraph::{algo::astar, stable_graph::NodeIndex};
+
+use entity::proto::Host;
+
+use crate::graph::HostGraph;
+
+/// Weight of links nobody measured yet, a second keeps them as the last resort
+pub const UNMEASURED: u32 = 1_000;
+
+/// Heaviest weight a peer may tell us about, slower links are as good as gone anyway
+pub const MAX_RTT: u32 = 60_000;
+
+/// Edge weights are round-trip times in milliseconds
+/// Measurements are smoothed, so a single slow heartbeat doesn't reroute everything
+pub fn record_rtt(graph: &mut HostGraph, from: NodeIndex, to: NodeIndex, rtt: Duration) {
+    let sample = (rtt.as_millis() as u32).max(1);
+    let weight = match graph.find_edge(from, to).map(|edge| graph[edge]) {
+        Some(weight) if weight != UNMEASURED => (weight * 3 + sample) / 4,
+        _ => sample,
+    };
+    graph.set_weight(from, to, weight);
+}
+
+/// Lowest-latency route, both ends included
+/// Costs are summed in u64, no path of u32 weights can overflow it
+pub fn shortest_path(
+    graph: &HostGraph,
+    from: NodeIndex,
+    to: NodeIndex,
+) -> Option<(u64, Vec<NodeIndex>)> {
+    astar(
+        &**graph,
+        from,
+        |finish| finish == to,
+        |e| u64::from(*e.weight()),
+        |_| 0,
+    )
+}
+
+/// Paths from us by target addr, cleared whenever the topology changes or a weight moved enough
+/// Filled under the graph read lock and cleared under the write lock, so it is never stale
+#[derive(Default)]
+pub struct PathCache {
+    paths: RwLock<HashMap<String, Vec<Host>>>,
+}
+
+impl PathCache {
+    pub fn get(&self, addr: &str) -> Option<Vec<Host>> {
+        self.paths.read().unwrap().get(addr).cloned()
+    }
+
+    pub fn insert(&self, addr: String, path: Vec<Host>) {
+        self.paths.write().unwrap().insert(addr, path);
+    }
+
+    pub fn invalidate(&self) {
+        self.paths.write().unwrap().clear();
+    }
+}
@@ -0,0 +1,862 @@
+use std::{collections::HashSet, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use petgraph::stable_graph::NodeIndex;
+use rand::RngCore;
+
+use tokio::sync::{
+    broadcast::{Receiver, Sender},
+    RwLock,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{codegen::futures_core::Stream, Request, Response, Status, Streaming};
+
+use entity::proto::{
+    federation_service_client::FederationServiceClient,
+    federation_service_server::FederationService as IFederationService,
+    packet::Packet as PacketType, AcknowledgePacket, Challenge, DropStats, ForwardPacket,
+    GossipPacket, HeartbeatPacket, Host, Hosts, Introduction, OnionAck, Packet, PacketKind,
+    PeerState, Proof, QueuedPacket, TopologySnapshot, TrustStatus, TrustedServer,
+};
+
+use crate::{
+    bans::Bans,
+    client::PEER_ADDR_HEADER,
+    crypto::{self, Identity},
+    gossip::Gossip,
+    graph::HostGraph,
+    limits::{self, Limits, RateLimiter},
+    link::Link,
+    liveness::Liveness,
+    onion::{self, Back, Circuits},
+    outbox::{self, Outbox},
+    protocol,
+    replay::{DropCounts, Replay},
+    routing::{self, PathCache},
+    stats::PeerStats,
+    topology::TopologyStore,
+    trust::TrustStore,
+};
+
+/// Longest a dialing server may take to answer our challenge
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct FederationService {
+    graph: Arc<RwLock<HostGraph>>,
+    me: NodeIndex, // Our address
+    addr: String,
+    inbox: Sender<Packet>, // Packets which reached us as their last hop, and acks of ours
+    identity: Arc<Identity>,
+    trust: Arc<TrustStore>,
+    resign: bool, // Endorse every forwarded packet with our key
+    store: Option<Arc<TopologyStore>>,
+    paths: Arc<PathCache>,
+    gossip: Arc<Gossip>,
+    outbox: Arc<Outbox>,
+    circuits: Arc<Circuits>,
+    replay: Arc<Replay>,
+    onion: bool, // Send packets onion routed
+    bans: Arc<Bans>,
+    stats: Arc<PeerStats>,
+    limiter: Arc<RateLimiter>,
+    rekey: Vec<u8>, // Our previous key over the current one, see `Identity::rekey`
+}
+
+impl FederationService {
+    /// Topology known before the restart is loaded from the store
+    /// Our host is stamped with the protocol version and capabilities of this build
+    #[allow(clippy::too_many_arguments)] // Every part is built from its own config section
+    pub fn new(
+        mut host: Host,
+        identity: Identity,
+        trust: TrustStore,
+        resign: bool,
+        onion: bool,
+        store: Option<TopologyStore>,
+        outbox: Outbox,
+        replay: Replay,
+        limits: Limits,
+    ) -> eyre::Result<Self> {
+        let (inbox, _) = tokio::sync::broadcast::channel(128);
+        protocol::stamp(&mut host);
+        let addr = host.addr.clone();
+        let (graph, me) = match &store {
+            Some(store) => store.load(host)?,
+            None => {
+                let mut graph = HostGraph::default();
+                let me = graph.upsert(host);
+                (graph, me)
+            }
+        };
+        let graph = Arc::new(RwLock::new(graph));
+        let paths: Arc<PathCache> = Default::default();
+        let bans: Arc<Bans> = Default::default();
+        let identity = Arc::new(identity);
+        Ok(Self {
+            gossip: Arc::new(Gossip::new(
+                graph.clone(),
+                paths.clone(),
+                me,
+                bans.clone(),
+                identity.clone(),
+            )),
+            me,
+            addr,
+            graph,
+            inbox,
+            identity,
+            trust: Arc::new(trust),
+            resign,
+            store: store.map(Arc::new),
+            paths,
+            circuits: Arc::new(Circuits::new(outbox.ttl())),
+            outbox: Arc::new(outbox),
+            replay: Arc::new(replay),
+            onion,
+            bans,
+            stats: Default::default(),
+            limiter: Arc::new(RateLimiter::new(limits)),
+            rekey: vec![],
+        })
+    }
+
+    /// Peers knowing us by the previous key take the current one from our introductions
+    pub fn rekeyed(mut self, previous: &Identity) -> Self {
+        self.rekey = previous.rekey(&self.addr, &self.identity.public_key());
+        self
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Packets delivered to us and acknowledgments of packets we sent
+    pub fn inbox(&self) -> Receiver<Packet> {
+        self.inbox.subscribe()
+    }
+
+    /// Encrypt the data to the last host, sign it and send it along the path
+    /// In onion mode every hop gets its own layer and only learns the next one,
+    /// unless a hop is too old to relay onions, then the packet goes the plain way
+    /// The path must start with us, keys and versions missing from it are taken from the graph
+    /// Keys in the path must be the ones bound to their host or trusted, data is sealed to them
+    /// The id is chosen by the caller, so the ack can't come back before it knows the id
+    /// If the first hop isn't connected the packet waits in the outbox
+    pub async fn send(&self, id: Vec<u8>, mut path: Vec<Host>, data: Vec<u8>) -> eyre::Result<()> {
+        if path.first().map(|host| &host.addr) != Some(&self.addr) {
+            return Err(eyre::eyre!("Path must start with this server"));
+        }
+        if id.is_empty() {
+            return Err(eyre::eyre!("Packet id must not be empty"));
+        }
+        // The first hop would refuse it and count it against us
+        if data.len() > self.limiter.limits().max_data {
+            return Err(eyre::eyre!("Data is larger than federation.max_data"));
+        }
+        {
+            let graph = self.graph.read().await;
+            for host in path.iter_mut().skip(1) {
+                let bound = graph
+                    .find(&host.addr)
+                    .map(|node| &graph[node].public_key)
+                    .filter(|key| !key.is_empty());
+                if host.public_key.is_empty() {
+                    host.public_key = bound.cloned().unwrap_or_default();
+                } else if bound != Some(&host.public_key)
+                    && !self.trust.is_trusted(&host.public_key)
+                {
+                    return Err(eyre::eyre!(
+                        "Public key of {} is neither the one it is known with nor trusted",
+                        host.addr
+                    ));
+                }
+                let Some(node) = graph.find(&host.addr) else {
+                    continue;
+                };
+                if host.version == 0 {
+                    host.version = graph[node].version;
+                    host.min_version = graph[node].min_version;
+                    host.capabilities = graph[node].capabilities.clone();
+                }
+            }
+        }
+        for host in path.iter().skip(1) {
+            protocol::negotiate(host)?;
+        }
+        let onion = path
+            .iter()
+            .skip(1)
+            .all(|host| protocol::supports(host, PacketKind::Onion));
+        if self.onion && onion {
+            return self.send_onion(id, path, data);
+        }
+        // Hosts from before sealing read the data in the clear, so can every hop
+        // Nothing goes in the clear to a host we know a key of, whatever it is said to speak
+        let recipient = path.last().unwrap();
+        let sealed = protocol::seals(recipient) || !recipient.public_key.is_empty();
+        if sealed && recipient.public_key.is_empty() {
+            return Err(eyre::eyre!("Public key of {} is not known", recipient.addr));
+        }
+        if sealed && !protocol::seals(recipient) {
+            return Err(eyre::eyre!(
+                "{} can't open sealed data, it is not sent in the clear",
+                recipient.addr
+            ));
+        }
+        let recipient = recipient.public_key.clone();
+        if path.len() > self.replay.max_hops() as usize {
+            return Err(eyre::eyre!("Path is longer than federation.max_hops"));
+        }
+        let mut packet = ForwardPacket {
+            path,
+            data,
+            id,
+            sent_at: outbox::unix_now(),
+            max_hops: self.replay.max_hops(),
+            ..Default::default()
+        };
+        if sealed {
+            crypto::seal(&mut packet, &recipient)?;
+        }
+        self.identity.sign(&mut packet);
+        self.outbox.dispatch(Packet {
+            packet: Some(PacketType::Forward(packet)),
+        });
+        Ok(())
+    }
+
+    /// The ack of the circuit comes back with the path and data we sent
+    fn send_onion(&self, id: Vec<u8>, path: Vec<Host>, data: Vec<u8>) -> eyre::Result<()> {
+        let packet = onion::wrap(&self.identity, &path, id.clone(), data.clone())?;
+        let first_hop = path[1].addr.clone();
+        let sent = ForwardPacket {
+            path,
+            data,
+            id,
+            origin: self.identity.public_key(),
+            ..Default::default()
+        };
+        self.circuits
+            .open(
+                &first_hop,
+                packet.circuit.clone(),
+                Back::Origin(Box::new(sent)),
+            )
+            .map_err(|_| eyre::eyre!("Too many open circuits"))?;
+        self.outbox.dispatch_to(
+            &first_hop,
+            Packet {
+                packet: Some(PacketType::Onion(packet)),
+            },
+        );
+        Ok(())
+    }
+
+    /// Lowest latency path from us to the host, paths are cached until the topology changes
+    pub async fn path_to(&self, addr: &str) -> Result<Vec<Host>, Status> {
+        if let Some(hosts) = self.paths.get(addr) {
+            return Ok(hosts);
+        }
+        let graph = self.graph.read().await;
+        let target = graph
+            .find(addr)
+            .ok_or_else(|| Status::not_found("Host is not known"))?;
+        let (_, path) = routing::shortest_path(&graph, self.me, target)
+            .ok_or_else(|| Status::not_found("No path found"))?;
+        let hosts = path
+            .iter()
+            .map(|node| graph[*node].clone())
+            .collect::<Vec<_>>();
+        self.paths.insert(addr.to_string(), hosts.clone());
+        Ok(hosts)
+    }
+
+    /// Addr of the host bound to the public key, the one a verified origin sends from
+    pub async fn addr_of(&self, public_key: &[u8]) -> Option<String> {
+        self.graph
+            .read()
+            .await
+            .node_weights()
+            .find(|host| host.public_key == public_key)
+            .map(|host| host.addr.clone())
+    }
+
+    /// Packets dropped as replayed, looping or expired since the start
+    pub fn drops(&self) -> DropCounts {
+        self.replay.drops()
+    }
+
+    /// Drop packets which waited too long, their origins learn it through a negative ack
+    pub(crate) fn expire_queued(&self) {
+        self.nack(self.outbox.expire());
+    }
+
+    /// Tell the origins of the packets they won't make it
+    fn nack(&self, packets: Vec<(String, Packet)>) {
+        for (next_hop, packet) in packets {
+            let forward = match packet.packet {
+                Some(PacketType::Forward(forward)) => forward,
+                Some(PacketType::Onion(onion)) => {
+                    if let Some(back) = self.circuits.close(&next_hop, &onion.circuit) {
+                        onion::acknowledge(back, OnionAck::default(), &self.outbox, &self.inbox);
+                    }
+                    continue;
+                }
+                // Acks which can't make it back are just dropped
+                _ => continue,
+            };
+            let origin = forward.hop == 0;
+            let nack = Packet {
+                packet: Some(PacketType::Acknowledge(AcknowledgePacket {
+                    success: false,
+                    forward: Some(forward),
+                    ..Default::default()
+                })),
+            };
+            if origin {
+                let _ = self.inbox.send(nack);
+            } else {
+                self.outbox.dispatch(nack);
+            }
+        }
+    }
+
+    /// Hosts and links as we see them now
+    pub async fn topology(&self) -> TopologySnapshot {
+        let graph = self.graph.read().await;
+        TopologySnapshot {
+            hosts: graph.hosts(),
+            edges: graph.links(),
+        }
+    }
+
+    /// State of every host we are linked to, have packets for, exchanged packets with or banned
+    pub async fn peers(&self) -> Vec<PeerState> {
+        let status = self.outbox.status();
+        let counts = self.stats.counts();
+        let banned = self.bans.list();
+        let graph = self.graph.read().await;
+        let neighbours = graph
+            .neighbors(self.me)
+            .map(|node| graph[node].addr.clone())
+            .collect::<HashSet<_>>();
+        let addrs = neighbours
+            .iter()
+            .chain(status.keys())
+            .chain(counts.keys())
+            .chain(banned.iter().map(|(addr, _)| addr))
+            .collect::<HashSet<_>>();
+        addrs
+            .into_iter()
+            .map(|addr| {
+                let node = graph.find(addr);
+                let (links, queued) = status.get(addr).copied().unwrap_or_default();
+                let counts = counts.get(addr).cloned().unwrap_or_default();
+                PeerState {
+                    addr: addr.clone(),
+                    neighbour: neighbours.contains(addr),
+                    weight: node
+                        .and_then(|node| graph.find_edge(self.me, node))
+                        .map(|edge| graph[edge])
+                        .unwrap_or_default(),
+                    last_seen: node.and_then(|node| graph[node].last_seen.clone()),
+                    links: links as u32,
+                    queued: queued as u32,
+                    received: counts.received,
+                    sent: counts.sent,
+                    rejected: counts.rejected,
+                    limited: counts.limited,
+                    banned: banned.iter().any(|(banned, _)| banned == addr),
+                }
+            })
+            .collect()
+    }
+
+    /// Packets dropped before reaching any peer, see `drops`
+    pub fn drop_stats(&self) -> DropStats {
+        let drops = self.drops();
+        DropStats {
+            duplicate: drops.duplicate,
+            looped: drops.looped,
+            expired: drops.expired,
+            too_many_hops: drops.too_many_hops,
+        }
+    }
+
+    /// Packets waiting for their next hop to connect
+    pub fn queued(&self) -> Vec<QueuedPacket> {
+        self.outbox.queued()
+    }
+
+    /// Servers we accept packets from and the attestations we know
+    pub async fn trust_status(&self) -> TrustStatus {
+        let graph = self.graph.read().await;
+        let servers = self
+            .trust
+            .trusted()
+            .into_iter()
+            .map(|(public_key, direct)| TrustedServer {
+                addr: graph
+                    .node_weights()
+                    .find(|host| host.public_key == public_key)
+                    .map(|host| host.addr.clone())
+                    .unwrap_or_default(),
+                public_key,
+                direct,
+            })
+            .collect();
+        TrustStatus {
+            servers,
+            attestations: self.trust.attestations(),
+        }
+    }
+
+    /// Stop trusting the server, peers inheriting our trust learn it with our attestations
+    pub fn revoke(&self, public_key: Vec<u8>) -> eyre::Result<()> {
+        self.trust.revoke(&self.identity, public_key)
+    }
+
+    /// Forget the host and close its links, packets queued for it are nacked
+    /// It comes back if it introduces itself again or gossip tells about it
+    /// Returns false if we knew nothing about it
+    pub async fn remove_peer(&self, addr: &str) -> eyre::Result<bool> {
+        let closed = self.outbox.close(addr);
+        let known = !closed.is_empty();
+        self.nack(closed);
+        let mut graph = self.graph.write().await;
+        let Some(node) = graph.find(addr).filter(|node| *node != self.me) else {
+            return Ok(known);
+        };
+        graph.remove(node);
+        self.paths.invalidate();
+        if let Some(store) = &self.store {
+            store.compact(&graph)?;
+        }
+        Ok(true)
+    }
+
+    /// Refuse the host for the duration, or until lifted, and forget it
+    /// Its key is banned along, links proving it are refused under any addr
+    pub async fn ban(&self, addr: &str, duration: Option<Duration>) -> eyre::Result<()> {
+        if addr == self.addr {
+            return Err(eyre::eyre!("Can't ban this server"));
+        }
+        let key = match addr.strip_prefix("key:") {
+            Some(key) => crypto::decode_key(key).unwrap_or_default(),
+            None => {
+                let graph = self.graph.read().await;
+                graph
+                    .find(addr)
+                    .map(|node| graph[node].public_key.clone())
+                    .unwrap_or_default()
+            }
+        };
+        self.bans.ban(addr, key, duration);
+        self.remove_peer(addr).await?;
+        Ok(())
+    }
+
+    /// Returns false if the host wasn't banned
+    pub fn unban(&self, addr: &str) -> bool {
+        self.bans.unban(addr)
+    }
+
+    pub fn is_banned(&self, addr: &str) -> bool {
+        self.bans.is_banned(addr)
+    }
+
+    /// Drop every cached path, the next packets get freshly computed ones
+    pub fn recompute(&self) {
+        self.paths.invalidate();
+    }
+
+    /// Unreachable hops with packets waiting for them, see `Outbox::due`
+    pub(crate) fn due_peers(&self) -> Vec<String> {
+        self.outbox.due()
+    }
+
+    /// Packet pump for a stream with the peer, accounted to `account`, see `handshake`
+    pub fn link(&self, peer: String, account: String) -> Link {
+        Link {
+            peer,
+            account,
+            addr: self.addr.clone(),
+            inbox: self.inbox.clone(),
+            identity: self.identity.clone(),
+            trust: self.trust.clone(),
+            graph: self.graph.clone(),
+            outbox: self.outbox.clone(),
+            circuits: self.circuits.clone(),
+            replay: self.replay.clone(),
+            stats: self.stats.clone(),
+            limiter: self.limiter.clone(),
+            resign: self.resign,
+        }
+    }
+
+    /// Pump packets with the peer until the stream closes
+    /// Peers which ran out of strikes are banned for `federation.strike_ban`
+    pub async fn run_link(
+        self,
+        peer: String,
+        account: String,
+        incoming: Streaming<Packet>,
+        outgoing: tokio::sync::mpsc::Sender<Result<Packet, Status>>,
+    ) {
+        self.prune();
+        if !self
+            .link(peer.clone(), account.clone())
+            .run(incoming, outgoing)
+            .await
+        {
+            return;
+        }
+        eprintln!("Banning {}, it broke its limits too often", account);
+        let duration = Some(self.limiter.limits().ban);
+        // Unbound peers are banned where they connect from, along with the key they proved
+        if let Some(key) = peer.strip_prefix("key:") {
+            let key = crypto::decode_key(key).unwrap_or_default();
+            self.bans.ban(&account, key, duration);
+            return;
+        }
+        if let Err(err) = self.ban(&peer, duration).await {
+            eprintln!("Failed to ban {}, Report: {:#?}", peer, err);
+        }
+    }
+
+    /// Forget the buckets, counters and bans idle peers left behind
+    /// They only come with links, so they are pruned as links open
+    fn prune(&self) {
+        if self.limiter.prune() {
+            self.stats.prune(limits::STRIKE_WINDOW);
+            self.bans.prune();
+        }
+    }
+
+    /// Answer to the challenge of a peer we dialed, see `handshake`
+    pub(crate) fn prove(&self, nonce: &[u8], peer: &str) -> Proof {
+        self.identity.prove(nonce, peer, &self.addr)
+    }
+
+    /// The dialing server proves it holds the key of the host listening on the addr it claims
+    /// The link is bound to that addr only if the key is the one we know for the host,
+    /// servers we know no key of yet are only known by their key on the link
+    /// Returns the peer and its account, which rate limits, stats and bans go by
+    /// Keys are free to make, so unbound peers are accounted to their remote IP instead,
+    /// or to the connection if we don't know it, see `RateLimiter`
+    async fn handshake(
+        &self,
+        hint: &str,
+        remote: Option<SocketAddr>,
+        nonce: &[u8],
+        incoming: &mut Streaming<Packet>,
+    ) -> Result<(String, String), Status> {
+        let first = tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.message())
+            .await
+            .map_err(|_| Status::deadline_exceeded("No proof of identity in time"))??;
+        let Some(Packet {
+            packet: Some(PacketType::Proof(proof)),
+        }) = first
+        else {
+            return Err(Status::unauthenticated("Stream must start with a proof"));
+        };
+        if !crypto::verify_proof(&proof, nonce, &self.addr, hint) {
+            return Err(Status::unauthenticated("Proof of identity is invalid"));
+        }
+        if self.bans.is_key_banned(&proof.public_key) {
+            return Err(Status::permission_denied("Host is banned"));
+        }
+        let graph = self.graph.read().await;
+        match graph.find(hint).map(|node| &graph[node].public_key) {
+            Some(key) if *key == proof.public_key => Ok((hint.to_string(), hint.to_string())),
+            Some(key) if !key.is_empty() => {
+                Err(Status::unauthenticated("Key is not the one of the host"))
+            }
+            _ => {
+                let account = match remote {
+                    Some(remote) => format!("ip:{}", remote.ip()),
+                    None => format!("conn:{}", crypto::encode_key(nonce)),
+                };
+                Ok((
+                    format!("key:{}", crypto::encode_key(&proof.public_key)),
+                    account,
+                ))
+            }
+        }
+    }
+
+    /// The server listening on the addr of an introduction proves it holds the key told,
+    /// nobody binds a key to the addr of another server, see `Identity::prove_addr`
+    async fn verify_introduction(&self, host: &Host) -> Result<(), Status> {
+        let mut nonce = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let challenge = Challenge {
+            nonce: nonce.clone(),
+            addr: self.addr.clone(),
+        };
+        let prove = async {
+            let mut client =
+                FederationServiceClient::connect(format!("http://{}", host.addr)).await?;
+            eyre::Ok(client.prove(challenge).await?.into_inner())
+        };
+        let proof = tokio::time::timeout(HANDSHAKE_TIMEOUT, prove)
+            .await
+            .map_err(|_| Status::deadline_exceeded("Host didn't prove its addr in time"))?
+            .map_err(|err| {
+                Status::unavailable(format!(
+                    "Failed to reach {} for a proof, Report: {:#?}",
+                    host.addr, err
+                ))
+            })?;
+        if proof.public_key != host.public_key
+            || !crypto::verify_addr_proof(&proof, &nonce, &self.addr, &host.addr)
+        {
+            return Err(Status::unauthenticated(
+                "Host didn't prove its key on its addr",
+            ));
+        }
+        Ok(())
+    }
+
+    /// What we tell a peer when dialing it
+    pub(crate) async fn introduction(&self) -> Introduction {
+        Introduction {
+            host: Some(self.graph.read().await[self.me].clone()),
+            attestations: self.trust.attestations(),
+            rekey: self.rekey.clone(),
+        }
+    }
+
+    /// Hosts we have a direct edge to
+    pub(crate) async fn neighbours(&self) -> Vec<String> {
+        let graph = self.graph.read().await;
+        graph
+            .neighbors(self.me)
+            .map(|node| graph[node].addr.clone())
+            .collect()
+    }
+
+    /// Merge hosts known by the seed into our graph, the seed can reach all of them
+    pub(crate) async fn merge(&self, seed: &str, hosts: Hosts) -> eyre::Result<()> {
+        if self.bans.is_banned(seed) {
+            return Err(eyre::eyre!("{} is banned", seed));
+        }
+        self.trust.ingest(hosts.attestations);
+        let mut graph = self.graph.write().await;
+        let seed = graph.upsert(Host {
+            addr: seed.to_string(),
+            ..Default::default()
+        });
+        graph.link(self.me, seed);
+        for host in hosts.hosts {
+            if host.addr == self.addr || self.bans.is_banned(&host.addr) {
+                continue;
+            }
+            let node = graph.upsert(host);
+            if node != seed {
+                graph.link(seed, node);
+            }
+        }
+        self.paths.invalidate();
+        if let Some(store) = &self.store {
+            store.compact(&graph)?;
+        }
+        Ok(())
+    }
+
+    /// Heartbeat neighbours forever, see `Liveness`
+    pub fn liveness(&self, interval: Duration, stale_timeout: Duration) -> Liveness {
+        Liveness::new(
+            self.graph.clone(),
+            self.paths.clone(),
+            self.me,
+            interval,
+            stale_timeout,
+        )
+    }
+
+    /// Exchange topology with a random neighbour every interval, runs forever
+    pub fn gossip(&self, interval: Duration) -> impl std::future::Future<Output = ()> {
+        self.gossip.clone().run(interval)
+    }
+
+    /// Snapshot the graph into the store every interval, runs forever
+    pub fn compaction(&self, interval: Duration) -> impl std::future::Future<Output = ()> {
+        let graph = self.graph.clone();
+        let store = self.store.clone();
+        async move {
+            let Some(store) = store else {
+                return;
+            };
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let graph = graph.read().await;
+                if let Err(err) = store.compact(&graph) {
+                    eprintln!("Failed to compact topology, Report: {:#?}", err);
+                }
+            }
+        }
+    }
+}
+
+type ForwardStream = Pin<Box<dyn Stream<Item = Result<Packet, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl IFederationService for FederationService {
+    type ForwardStream = ForwardStream;
+
+    /// Acknowledge a new host once its addr proved its key, return all known hosts to it
+    async fn acknowledge(&self, request: Request<Introduction>) -> Result<Response<Hosts>, Status> {
+        let introduction = request.into_inner();
+        let host = introduction
+            .host
+            .ok_or_else(|| Status::invalid_argument("Introduction without host"))?;
+        if host.addr == self.addr {
+            return Err(Status::invalid_argument("Host claims our address"));
+        }
+        if self.bans.is_banned(&host.addr) {
+            return Err(Status::permission_denied("Host is banned"));
+        }
+        if self.bans.is_key_banned(&host.public_key) {
+            return Err(Status::permission_denied("Host is banned"));
+        }
+        protocol::negotiate(&host).map_err(|err| Status::failed_precondition(err.to_string()))?;
+        self.verify_introduction(&host).await?;
+        self.trust.ingest(introduction.attestations);
+        let mut graph = self.graph.write().await;
+        // A known host keeps its key, unless the old one signed the new one or we trust it
+        let rekey = graph.find(&host.addr).filter(|node| {
+            let known = &graph[*node].public_key;
+            !known.is_empty() && !host.public_key.is_empty() && *known != host.public_key
+        });
+        if let Some(node) = rekey {
+            let known = &graph[node].public_key;
+            if !crypto::verify_rekey(known, &host.addr, &host.public_key, &introduction.rekey)
+                && !self.trust.is_trusted(&host.public_key)
+            {
+                return Err(Status::permission_denied("Host is known with another key"));
+            }
+            graph.rekey(node, host.public_key.clone());
+        }
+        // Peers introduce themselves again on every start
+        let new_node = graph.upsert(host);
+        graph.link(self.me, new_node);
+        self.paths.invalidate();
+        if let Some(store) = &self.store {
+            let weight = graph[graph.find_edge(self.me, new_node).unwrap()];
+            store
+                .save_host(&graph[new_node])
+                .and_then(|_| store.save_edge(&graph[self.me], &graph[new_node], weight))
+                .map_err(|err| {
+                    Status::internal(format!("Failed to persist host, Report: {:#?}", err))
+                })?;
+        }
+        let knowledged = graph.hosts();
+        Ok(Response::new(Hosts {
+            hosts: knowledged,
+            attestations: self.trust.attestations(),
+        }))
+    }
+
+    /// Request a path to a host knowledged by this host
+    /// Used by the client before actuall forwarding
+    async fn request_path(&self, request: Request<Host>) -> Result<Response<Hosts>, Status> {
+        let hosts = self.path_to(&request.into_inner().addr).await?;
+        Ok(Response::new(Hosts {
+            hosts,
+            ..Default::default()
+        }))
+    }
+
+    /// Neighbour is alive, answer with our host
+    /// Anyone can claim an addr here, `last_seen` only moves with our own answered heartbeats
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatPacket>,
+    ) -> Result<Response<HeartbeatPacket>, Status> {
+        let heartbeat = request.into_inner();
+        let sender = heartbeat
+            .host
+            .ok_or_else(|| Status::invalid_argument("Heartbeat without host"))?;
+        if self.bans.is_banned(&sender.addr) {
+            return Err(Status::permission_denied("Host is banned"));
+        }
+        let graph = self.graph.read().await;
+        Ok(Response::new(HeartbeatPacket {
+            host: Some(graph[self.me].clone()),
+        }))
+    }
+
+    /// Prove to the asker that we listen on our addr, see `verify_introduction`
+    async fn prove(&self, request: Request<Challenge>) -> Result<Response<Proof>, Status> {
+        let challenge = request.into_inner();
+        Ok(Response::new(self.identity.prove_addr(
+            &challenge.nonce,
+            &challenge.addr,
+            &self.addr,
+        )))
+    }
+
+    /// Anti-entropy with a peer, see `Gossip`
+    async fn gossip(
+        &self,
+        request: Request<GossipPacket>,
+    ) -> Result<Response<GossipPacket>, Status> {
+        Ok(Response::new(
+            self.gossip.receive(request.into_inner()).await,
+        ))
+    }
+
+    /// Forward a packet to the next hop in path
+    /// If the last hop is reached, ack packet will be returned
+    async fn forward(
+        &self,
+        request: Request<Streaming<Packet>>,
+    ) -> Result<tonic::Response<Self::ForwardStream>, tonic::Status> {
+        // Per request stream
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        // Dialing servers tell us where they listen, it is a hint until they prove it
+        let hint = request
+            .metadata()
+            .get(PEER_ADDR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if self.bans.is_banned(&hint) {
+            return Err(Status::permission_denied("Host is banned"));
+        }
+        let remote = request.remote_addr();
+        let mut nonce = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let challenge = Packet {
+            packet: Some(PacketType::Challenge(Challenge {
+                nonce: nonce.clone(),
+                ..Default::default()
+            })),
+        };
+        // Room for it, the channel is new
+        let _ = tx.try_send(Ok(challenge));
+        let service = self.clone();
+        let mut incoming = request.into_inner();
+        tokio::spawn(async move {
+            match service
+                .handshake(&hint, remote, &nonce, &mut incoming)
+                .await
+            {
+                Ok((peer, account)) if service.is_banned(&peer) || service.is_banned(&account) => {
+                    let _ = tx
+                        .send(Err(Status::permission_denied("Host is banned")))
+                        .await;
+                }
+                Ok((peer, account)) => service.run_link(peer, account, incoming, tx).await,
+                Err(status) => {
+                    let _ = tx.send(Err(status)).await;
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(Box::pin(output_stream) as Self::ForwardStream))
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Packets exchanged with a peer over its links
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+    pub received: u64,
+    pub sent: u64,
+    pub rejected: u64, // Failed verification or broke a limit, the link was closed on them
+    pub limited: u64,  // Over the rate limits of the peer, dropped
+}
+
+/// Packet counters of the peers, by what their link is accounted to, see `handshake`
+/// Peers idle for a while are forgotten along with their buckets, see `RateLimiter::prune`
+#[derive(Default)]
+pub struct PeerStats {
+    peers: Mutex<HashMap<String, (PacketCounts, Instant)>>, // Counts and the last update
+}
+
+impl PeerStats {
+    pub fn received(&self, peer: &str) {
+        self.update(peer, |counts| counts.received += 1);
+    }
+
+    pub fn sent(&self, peer: &str) {
+        self.update(peer, |counts| counts.sent += 1);
+    }
+
+    pub fn rejected(&self, peer: &str) {
+        self.update(peer, |counts| counts.rejected += 1);
+    }
+
+    pub fn limited(&self, peer: &str) {
+        self.update(peer, |counts| counts.limited += 1);
+    }
+
+    pub fn counts(&self) -> HashMap<String, PacketCounts> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, (counts, _))| (peer.clone(), counts.clone()))
+            .collect()
+    }
+
+    /// Forget the peers nothing was counted for during `idle`
+    pub fn prune(&self, idle: Duration) {
+        let now = Instant::now();
+        self.peers
+            .lock()
+            .unwrap()
+            .retain(|_, (_, at)| now.saturating_duration_since(*at) <= idle);
+    }
+
+    fn update(&self, peer: &str, update: impl FnOnce(&mut PacketCounts)) {
+        let mut peers = self.peers.lock().unwrap();
+        let now = Instant::now();
+        match peers.get_mut(peer) {
+            Some((counts, at)) => {
+                update(counts);
+                *at = now;
+            }
+            None => {
+                let mut counts = PacketCounts::default();
+                update(&mut counts);
+                peers.insert(peer.to_string(), (counts, now));
+            }
+        }
+    }
+}
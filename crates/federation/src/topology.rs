@@ -0,0 +1,99 @@
+use eyre::{Result, WrapErr};
+use petgraph::stable_graph::NodeIndex;
+use prost::Message;
+
+use entity::proto::{Edge, Host};
+
+use crate::graph::HostGraph;
+
+/// Embedded store of the topology graph, so a restarted node rejoins the mesh with what it knew
+/// Hosts are keyed by addr, edges by `from\0to`
+/// Changes are written as they happen, `compact` replaces everything with a snapshot of the graph
+pub struct TopologyStore {
+    db: sled::Db,
+    hosts: sled::Tree,
+    edges: sled::Tree,
+}
+
+impl TopologyStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let db = sled::open(path).wrap_err_with(|| format!("Failed to open {}", path))?;
+        Ok(Self {
+            hosts: db.open_tree("hosts")?,
+            edges: db.open_tree("edges")?,
+            db,
+        })
+    }
+
+    /// Queued packets live next to the topology, see `Outbox`
+    pub fn outbox(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree("outbox")?)
+    }
+
+    /// Rebuild the graph, `me` replaces the stored version of ourself
+    pub fn load(&self, me: Host) -> Result<(HostGraph, NodeIndex)> {
+        let mut graph = HostGraph::default();
+        for entry in self.hosts.iter() {
+            let (_, value) = entry?;
+            let host = Host::decode(&*value).wrap_err("Stored host is corrupted")?;
+            if host.addr != me.addr {
+                graph.upsert(host);
+            }
+        }
+        let me = graph.upsert(me);
+        for entry in self.edges.iter() {
+            let (_, value) = entry?;
+            let edge = Edge::decode(&*value).wrap_err("Stored edge is corrupted")?;
+            // Edges of hosts we don't know are leftovers of an interrupted compaction
+            if let (Some(from), Some(to)) = (graph.find(&edge.from), graph.find(&edge.to)) {
+                graph.set_weight(from, to, edge.weight);
+            }
+        }
+        Ok((graph, me))
+    }
+
+    pub fn save_host(&self, host: &Host) -> Result<()> {
+        self.hosts.insert(&host.addr, host.encode_to_vec())?;
+        Ok(())
+    }
+
+    pub fn save_edge(&self, from: &Host, to: &Host, weight: u32) -> Result<()> {
+        let edge = Edge {
+            from: from.addr.clone(),
+            to: to.addr.clone(),
+            weight,
+        };
+        self.edges
+            .insert(edge_key(&edge.from, &edge.to), edge.encode_to_vec())?;
+        Ok(())
+    }
+
+    /// Replace the stored topology with the graph, dropping removed hosts and duplicates
+    pub fn compact(&self, graph: &HostGraph) -> Result<()> {
+        let mut hosts = sled::Batch::default();
+        for entry in self.hosts.iter().keys() {
+            hosts.remove(entry?);
+        }
+        for host in graph.node_weights() {
+            hosts.insert(host.addr.as_bytes(), host.encode_to_vec());
+        }
+        let mut edges = sled::Batch::default();
+        for entry in self.edges.iter().keys() {
+            edges.remove(entry?);
+        }
+        for edge in graph.links() {
+            edges.insert(edge_key(&edge.from, &edge.to), edge.encode_to_vec());
+        }
+        self.hosts.apply_batch(hosts)?;
+        self.edges.apply_batch(edges)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+fn edge_key(from: &str, to: &str) -> Vec<u8> {
+    let mut key = from.as_bytes().to_vec();
+    key.push(0);
+    key.extend(to.as_bytes());
+    key
+}
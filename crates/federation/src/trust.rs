@@ -0,0 +1,316 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use eyre::{eyre, Result};
+use tonic::Status;
+
+use entity::proto::{packet::Packet as PacketType, Attestation, ForwardPacket, OnionLayer, Packet};
+
+use crate::{
+    crypto::{self, Identity},
+    protocol,
+};
+
+/// Bounds the memory peers can make us spend on their attestations
+const MAX_ATTESTATIONS: usize = 4096;
+
+/// Latest attestation of every (voucher, subject) pair
+type Attestations = HashMap<(Vec<u8>, Vec<u8>), Attestation>;
+
+/// Why a packet was not accepted from a peer
+#[derive(Debug)]
+pub enum Rejection {
+    Unsigned,
+    BadSignature,
+    Untrusted(Vec<u8>),
+    Malformed(&'static str),
+}
+
+impl From<Rejection> for Status {
+    fn from(value: Rejection) -> Self {
+        match value {
+            Rejection::Unsigned => Status::unauthenticated("Packet is not signed"),
+            Rejection::BadSignature => Status::unauthenticated("Packet signature is invalid"),
+            Rejection::Untrusted(key) => Status::permission_denied(format!(
+                "Server {} is not trusted",
+                crypto::encode_key(&key)
+            )),
+            Rejection::Malformed(reason) => Status::invalid_argument(reason),
+        }
+    }
+}
+
+/// Public keys of servers we accept packets from, `federation.trusted_servers` in the config
+/// With `federation.trust_iherit` servers vouched by trusted ones are trusted too,
+/// up to `federation.trust_depth` vouching servers away from the configured ones
+/// Servers we revoked, `federation.revoked_servers` or through the admin service, are never
+/// trusted again, whoever vouches for them
+pub struct TrustStore {
+    own: Vec<u8>,
+    direct: RwLock<HashSet<Vec<u8>>>,
+    revoked: RwLock<HashSet<Vec<u8>>>,
+    inherit: bool,
+    depth: u32,
+    attestations: RwLock<Attestations>,
+    inherited: RwLock<HashSet<Vec<u8>>>,
+}
+
+impl TrustStore {
+    /// We attest every server we trust directly and revoke the others, so peers can follow us
+    pub fn new(
+        identity: &Identity,
+        trusted_servers: &[String],
+        revoked_servers: &[String],
+        inherit: bool,
+        depth: u32,
+    ) -> Result<Self> {
+        let revoked = revoked_servers
+            .iter()
+            .map(|key| crypto::decode_key(key))
+            .collect::<Result<HashSet<_>>>()?;
+        let mut direct = trusted_servers
+            .iter()
+            .map(|key| crypto::decode_key(key))
+            .collect::<Result<HashSet<_>>>()?;
+        direct.retain(|key| !revoked.contains(key));
+        let own = identity.public_key();
+        let attestations = direct
+            .iter()
+            .filter(|key| **key != own)
+            .map(|key| identity.attest(key.clone(), false))
+            .chain(revoked.iter().map(|key| identity.attest(key.clone(), true)))
+            .map(|attestation| {
+                let pair = (attestation.voucher.clone(), attestation.subject.clone());
+                (pair, attestation)
+            })
+            .collect();
+        Ok(Self {
+            own,
+            direct: RwLock::new(direct),
+            revoked: RwLock::new(revoked),
+            inherit,
+            depth,
+            attestations: RwLock::new(attestations),
+            inherited: Default::default(),
+        })
+    }
+
+    pub fn is_trusted(&self, key: &[u8]) -> bool {
+        if self.revoked.read().unwrap().contains(key) {
+            return false;
+        }
+        self.direct.read().unwrap().contains(key)
+            || (self.inherit && self.inherited.read().unwrap().contains(key))
+    }
+
+    /// Keys we accept packets from, true for the configured ones
+    pub fn trusted(&self) -> Vec<(Vec<u8>, bool)> {
+        let direct = self.direct.read().unwrap();
+        let direct = direct.iter().map(|key| (key.clone(), true));
+        if !self.inherit {
+            return direct.collect();
+        }
+        let inherited = self.inherited.read().unwrap();
+        direct
+            .chain(inherited.iter().map(|key| (key.clone(), false)))
+            .collect()
+    }
+
+    /// Withdraw our trust from the subject, configured or inherited
+    /// Peers drop everything they inherited through us, they learn it with our attestations
+    /// Lasts until the restart unless the key is in `federation.revoked_servers` too
+    pub fn revoke(&self, identity: &Identity, subject: Vec<u8>) -> Result<()> {
+        if subject == self.own {
+            return Err(eyre!("Can't revoke this server"));
+        }
+        self.direct.write().unwrap().remove(&subject);
+        self.revoked.write().unwrap().insert(subject.clone());
+        let attestation = identity.attest(subject, true);
+        let mut attestations = self.attestations.write().unwrap();
+        let pair = (attestation.voucher.clone(), attestation.subject.clone());
+        attestations.insert(pair, attestation);
+        self.recompute(&mut attestations);
+        Ok(())
+    }
+
+    /// Everything we know is shared, including revocations
+    pub fn attestations(&self) -> Vec<Attestation> {
+        self.attestations
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Store valid attestations received from a peer and recompute inherited trust
+    /// Only attestations of servers we trust, directly or inherited, are kept, the others
+    /// couldn't change anything and would only take room
+    /// A batch can hold a whole chain, vouchers are reached level by level
+    pub fn ingest(&self, received: Vec<Attestation>) {
+        let mut attestations = self.attestations.write().unwrap();
+        let mut pending = received
+            .into_iter()
+            .filter(crypto::verify_attestation)
+            .collect::<Vec<_>>();
+        loop {
+            let reachable = self.reachable(&attestations);
+            let before = pending.len();
+            pending.retain(|attestation| {
+                if !reachable.contains(&attestation.voucher) {
+                    return true;
+                }
+                store(&mut attestations, attestation.clone());
+                false
+            });
+            if pending.len() == before {
+                break;
+            }
+        }
+        self.recompute(&mut attestations);
+    }
+
+    /// Recompute inherited trust and forget what vouchers we stopped trusting attested
+    fn recompute(&self, attestations: &mut Attestations) {
+        let reachable = self.reachable(attestations);
+        attestations.retain(|(voucher, _), _| *voucher == self.own || reachable.contains(voucher));
+        let inherited = self.inherit_from(attestations);
+        *self.inherited.write().unwrap() = inherited;
+    }
+
+    /// Configured servers and the ones they vouch for, whether we inherit their trust or not
+    fn reachable(&self, attestations: &Attestations) -> HashSet<Vec<u8>> {
+        let mut reachable = self.inherit_from(attestations);
+        reachable.extend(self.direct.read().unwrap().iter().cloned());
+        reachable
+    }
+
+    /// Walk the vouching graph from the configured servers, level by level
+    fn inherit_from(&self, attestations: &Attestations) -> HashSet<Vec<u8>> {
+        let direct = self.direct.read().unwrap();
+        let revoked = self.revoked.read().unwrap();
+        let mut inherited = HashSet::new();
+        let mut level = direct.clone();
+        for _ in 0..self.depth {
+            level = attestations
+                .values()
+                .filter(|attestation| !attestation.revoked && level.contains(&attestation.voucher))
+                .map(|attestation| attestation.subject.clone())
+                .filter(|subject| {
+                    !direct.contains(subject)
+                        && !revoked.contains(subject)
+                        && !inherited.contains(subject)
+                })
+                .collect();
+            if level.is_empty() {
+                break;
+            }
+            inherited.extend(level.iter().cloned());
+        }
+        inherited
+    }
+
+    /// Origin signature is required, hop signatures are optional but must be valid and trusted
+    pub fn verify(&self, packet: &ForwardPacket) -> Result<(), Rejection> {
+        if packet.id.is_empty() {
+            return Err(Rejection::Malformed("Packet without id"));
+        }
+        self.verify_origin(&packet.origin, &packet.signature, || {
+            crypto::verify_origin(packet)
+        })?;
+        for hop in &packet.hop_signatures {
+            if !crypto::verify_hop(packet, hop) {
+                return Err(Rejection::BadSignature);
+            }
+            if !self.is_trusted(&hop.key) {
+                return Err(Rejection::Untrusted(hop.key.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Last onion layer, only the last hop can see who sent it
+    /// Its origin goes through the checks of plain packets, relays only pass on what trusted
+    /// peers sent them, see `Link::peel`
+    pub fn verify_layer(&self, layer: &OnionLayer) -> Result<(), Rejection> {
+        if layer.id.is_empty() {
+            return Err(Rejection::Malformed("Packet without id"));
+        }
+        self.verify_origin(&layer.origin, &layer.signature, || {
+            crypto::verify_layer(layer)
+        })
+    }
+
+    fn verify_origin(
+        &self,
+        origin: &[u8],
+        signature: &[u8],
+        valid: impl FnOnce() -> bool,
+    ) -> Result<(), Rejection> {
+        if origin.is_empty() || signature.is_empty() {
+            return Err(Rejection::Unsigned);
+        }
+        if !valid() {
+            return Err(Rejection::BadSignature);
+        }
+        if !self.is_trusted(origin) {
+            return Err(Rejection::Untrusted(origin.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Acknowledgments are accepted only for genuine packets, successes only from the receiver
+    /// Onion packets can't be checked on the way, their last hop verifies them
+    pub fn verify_packet(&self, packet: &Packet) -> Result<(), Rejection> {
+        match &packet.packet {
+            Some(PacketType::Forward(forward)) => self.verify(forward),
+            Some(PacketType::Acknowledge(ack)) => {
+                let forward = ack
+                    .forward
+                    .as_ref()
+                    .ok_or(Rejection::Malformed("Acknowledge without forward packet"))?;
+                self.verify(forward)?;
+                // Any hop may give up on a packet, only the receiver can tell it arrived
+                // Receivers from before signing can't, their acks are taken as they are,
+                // as long as we know no key of them to check
+                let signed = forward
+                    .path
+                    .last()
+                    .is_some_and(|host| protocol::signs(host) || !host.public_key.is_empty());
+                if !signed {
+                    return Ok(());
+                }
+                if ack.success && ack.signature.is_empty() {
+                    return Err(Rejection::Unsigned);
+                }
+                if ack.success && !crypto::verify_ack(ack) {
+                    return Err(Rejection::BadSignature);
+                }
+                Ok(())
+            }
+            Some(PacketType::Onion(_)) | Some(PacketType::OnionAck(_)) => Ok(()),
+            Some(PacketType::Challenge(_)) | Some(PacketType::Proof(_)) => {
+                Err(Rejection::Malformed("Handshake is over"))
+            }
+            None => Err(Rejection::Malformed("Empty packet")),
+        }
+    }
+}
+
+/// Keep the attestation if it is newer than the one we know of the pair
+/// Revocations win over attestations issued the same second
+fn store(attestations: &mut Attestations, attestation: Attestation) {
+    let pair = (attestation.voucher.clone(), attestation.subject.clone());
+    let newer = match attestations.get(&pair) {
+        Some(known) => {
+            attestation.issued_at > known.issued_at
+                || (attestation.issued_at == known.issued_at && attestation.revoked)
+        }
+        None => attestations.len() < MAX_ATTESTATIONS,
+    };
+    if newer {
+        attestations.insert(pair, attestation);
+    }
+}
@@ -0,0 +1,62 @@
+use entity::proto::{Edge, Host, TopologySnapshot};
+use federation::{export, routing::UNMEASURED};
+
+fn host(addr: &str) -> Host {
+    Host {
+        addr: addr.to_string(),
+        ..Default::default()
+    }
+}
+
+fn edge(from: &str, to: &str, weight: u32) -> Edge {
+    Edge {
+        from: from.to_string(),
+        to: to.to_string(),
+        weight,
+    }
+}
+
+/// Same topology as two nodes would see it, listed in another order
+fn snapshots() -> (TopologySnapshot, TopologySnapshot) {
+    let a = TopologySnapshot {
+        hosts: vec![host("b"), host("a")],
+        edges: vec![edge("b", "a", UNMEASURED), edge("a", "b", 12)],
+    };
+    let b = TopologySnapshot {
+        hosts: vec![host("a"), host("b")],
+        edges: vec![edge("a", "b", 12), edge("b", "a", UNMEASURED)],
+    };
+    (a, b)
+}
+
+#[test]
+fn dot_is_sorted_and_dashes_unmeasured_links() {
+    let (a, b) = snapshots();
+    let dot = export::dot(&a);
+    assert_eq!(dot, export::dot(&b));
+    assert_eq!(
+        dot,
+        "digraph federation {\n    \"a\" [label=\"a\"];\n    \"b\" [label=\"b\"];\n    \
+         \"a\" -> \"b\" [label=\"12ms\"];\n    \"b\" -> \"a\" [style=dashed];\n}\n"
+    );
+}
+
+#[test]
+fn json_leaves_out_unmeasured_weights() {
+    let (a, b) = snapshots();
+    let json = export::json(&a).unwrap();
+    assert_eq!(json, export::json(&b).unwrap());
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["hosts"][0]["addr"], "a");
+    assert_eq!(value["edges"][0]["weight"], 12);
+    assert!(value["edges"][1]["weight"].is_null());
+}
+
+#[test]
+fn dot_escapes_addrs() {
+    let snapshot = TopologySnapshot {
+        hosts: vec![host("a\"]")],
+        edges: vec![],
+    };
+    assert!(export::dot(&snapshot).contains("\"a\\\"]\" [label=\"a\\\"]\"]"));
+}
@@ -0,0 +1,287 @@
+use petgraph::stable_graph::NodeIndex;
+
+use entity::proto::{Adjacency, Edge, Host};
+use federation::{
+    crypto::Identity,
+    gossip::{Adjacencies, MAX_HOSTS},
+    graph::HostGraph,
+    routing,
+};
+
+fn host(addr: &str) -> Host {
+    Host {
+        addr: addr.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Adjacency of the host, signed with the identity
+fn signed(identity: &Identity, addr: &str, version: u64, edges: Vec<Edge>) -> Adjacency {
+    let mut adjacency = Adjacency {
+        host: Some(Host {
+            public_key: identity.public_key(),
+            ..host(addr)
+        }),
+        version,
+        edges,
+        ..Default::default()
+    };
+    identity.sign_adjacency(&mut adjacency);
+    adjacency
+}
+
+fn edge(from: &str, to: &str, weight: u32) -> Edge {
+    Edge {
+        from: from.into(),
+        to: to.into(),
+        weight,
+    }
+}
+
+/// Simulated server, knowing only itself and the links it measured
+struct Node {
+    graph: HostGraph,
+    me: NodeIndex,
+    adjacencies: Adjacencies,
+    identity: Identity,
+}
+
+impl Node {
+    fn new(addr: &str, links: &[(&str, u32)]) -> Self {
+        let identity = Identity::generate();
+        let mut graph = HostGraph::default();
+        let me = graph.upsert(Host {
+            public_key: identity.public_key(),
+            ..host(addr)
+        });
+        for (to, weight) in links {
+            let to = graph.upsert(host(to));
+            graph.set_weight(me, to, *weight);
+        }
+        Self {
+            graph,
+            me,
+            adjacencies: Adjacencies::default(),
+            identity,
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.adjacencies
+            .refresh(&self.graph, self.me, &self.identity);
+    }
+
+    /// Pulled from a neighbour which proved its key
+    fn apply(&mut self, received: Vec<Adjacency>) -> bool {
+        self.adjacencies
+            .apply(&mut self.graph, self.me, received, true)
+    }
+
+    /// Pushed by whoever called
+    fn receive(&mut self, received: Vec<Adjacency>) -> bool {
+        self.adjacencies
+            .apply(&mut self.graph, self.me, received, false)
+    }
+
+    fn route(&self, to: &str) -> Option<(u64, Vec<String>)> {
+        let to = self.graph.find(to)?;
+        routing::shortest_path(&self.graph, self.me, to).map(|(cost, path)| {
+            let path = path
+                .iter()
+                .map(|node| self.graph[*node].addr.clone())
+                .collect();
+            (cost, path)
+        })
+    }
+}
+
+/// One gossip round initiated by `a`, the same exchange `Gossip` does over the network
+fn exchange(a: &mut Node, b: &mut Node) {
+    a.refresh();
+    let digest = a.adjacencies.digest();
+
+    b.refresh();
+    let answer = b.adjacencies.newer_than(&digest);
+    let versions = b.adjacencies.digest();
+
+    a.apply(answer);
+    let push = a.adjacencies.newer_than(&versions);
+    b.receive(push);
+}
+
+/// `a - b - c - d`, every node only knows its neighbours
+fn line() -> Vec<Node> {
+    vec![
+        Node::new("a", &[("b", 10)]),
+        Node::new("b", &[("a", 10), ("c", 20)]),
+        Node::new("c", &[("b", 20), ("d", 30)]),
+        Node::new("d", &[("c", 30)]),
+    ]
+}
+
+fn converge(nodes: &mut [Node]) {
+    for _ in 0..nodes.len() {
+        for i in 0..nodes.len() - 1 {
+            let (left, right) = nodes.split_at_mut(i + 1);
+            exchange(&mut left[i], &mut right[0]);
+            exchange(&mut right[0], &mut left[i]);
+        }
+    }
+}
+
+#[test]
+fn every_node_learns_the_full_topology() {
+    let mut nodes = line();
+    assert_eq!(nodes[0].route("d"), None);
+
+    converge(&mut nodes);
+    assert_eq!(
+        nodes[0].route("d"),
+        Some((60, vec!["a".into(), "b".into(), "c".into(), "d".into()]))
+    );
+    assert_eq!(
+        nodes[3].route("a"),
+        Some((60, vec!["d".into(), "c".into(), "b".into(), "a".into()]))
+    );
+}
+
+#[test]
+fn removed_links_are_gossiped_too() {
+    let mut nodes = line();
+    converge(&mut nodes);
+
+    // `c` loses `d`, its next adjacency doesn't have the link anymore
+    let c = &mut nodes[2];
+    let (b, d) = (c.graph.find("b").unwrap(), c.graph.find("d").unwrap());
+    c.graph.replace_links(c.me, &[(b, 20)]);
+    assert!(c.graph.find_edge(c.me, d).is_none());
+
+    converge(&mut nodes);
+    assert_eq!(nodes[0].route("d"), None);
+    assert!(nodes[0].route("c").is_some());
+}
+
+#[test]
+fn gossip_reroutes_around_slow_link() {
+    let mut a = Node::new("a", &[("b", 10), ("c", 10)]);
+    let mut b = Node::new("b", &[("d", 500), ("c", 5)]);
+    let mut c = Node::new("c", &[("d", 15)]);
+
+    exchange(&mut a, &mut b);
+    exchange(&mut a, &mut c);
+    assert_eq!(
+        a.route("d"),
+        Some((25, vec!["a".into(), "c".into(), "d".into()]))
+    );
+}
+
+#[test]
+fn older_versions_are_ignored() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    let b = Identity::generate();
+    let newer = signed(&b, "b", 2, vec![edge("b", "c", 40)]);
+    let older = signed(&b, "b", 1, vec![]);
+    assert!(a.apply(vec![newer]));
+    assert!(!a.apply(vec![older]));
+    assert_eq!(a.route("c").map(|(cost, _)| cost), Some(50));
+}
+
+#[test]
+fn told_weights_are_capped() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    let (b, c) = (Identity::generate(), Identity::generate());
+    assert!(a.apply(vec![
+        signed(&b, "b", 1, vec![edge("b", "c", u32::MAX)]),
+        signed(&c, "c", 1, vec![edge("c", "d", u32::MAX)]),
+    ]));
+    let cost = 10 + 2 * u64::from(routing::MAX_RTT);
+    assert_eq!(a.route("d").map(|(cost, _)| cost), Some(cost));
+}
+
+#[test]
+fn adjacencies_of_others_are_rejected() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    let (b, c) = (Identity::generate(), Identity::generate());
+    assert!(a.apply(vec![signed(&b, "b", 1, vec![edge("b", "c", 40)])]));
+
+    // `c` signs an adjacency of `b`, whose key we know by now
+    let forged = signed(&c, "b", 2, vec![edge("b", "x", 1)]);
+    assert!(!a.apply(vec![forged]));
+    let mut tampered = signed(&b, "b", 3, vec![edge("b", "c", 40)]);
+    tampered.edges[0].to = "x".into();
+    assert!(!a.apply(vec![tampered]));
+    assert_eq!(a.graph.find("x"), None);
+    assert_eq!(a.route("c").map(|(cost, _)| cost), Some(50));
+}
+
+#[test]
+fn versions_from_the_future_are_rejected() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    let b = Identity::generate();
+    let pinned = signed(&b, "b", u64::MAX, vec![edge("b", "c", 40)]);
+    assert!(!a.apply(vec![pinned]));
+    assert!(!a.adjacencies.digest().contains_key("b"));
+
+    // Nor do we take our own, the version would keep us from ever publishing again
+    a.refresh();
+    let ours = a.adjacencies.digest()["a"];
+    let pinned = signed(&a.identity, "a", u64::MAX, vec![]);
+    assert!(!a.apply(vec![pinned]));
+    assert_eq!(a.adjacencies.digest()["a"], ours);
+}
+
+#[test]
+fn nobody_else_can_publish_our_links() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    a.refresh();
+    let ours = a.adjacencies.digest()["a"];
+
+    let forged = signed(
+        &Identity::generate(),
+        "a",
+        ours + 1_000,
+        vec![edge("a", "x", 1)],
+    );
+    assert!(!a.apply(vec![forged]));
+    assert_eq!(a.graph.find("x"), None);
+    assert_eq!(a.graph.edge_count(), 1);
+    assert_eq!(a.adjacencies.digest()["a"], ours);
+
+    // Ours from before a restart, the next adjacency outruns it
+    let previous = signed(&a.identity, "a", ours + 1_000, vec![edge("a", "x", 1)]);
+    assert!(!a.apply(vec![previous]));
+    assert_eq!(a.graph.find("x"), None);
+    a.refresh();
+    assert!(a.adjacencies.digest()["a"] > ours + 1_000);
+}
+
+#[test]
+fn pushed_adjacencies_only_update_hosts_of_known_keys() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    let (b, c) = (Identity::generate(), Identity::generate());
+    assert!(!a.receive(vec![signed(&c, "c", 1, vec![edge("c", "x", 1)])]));
+    assert_eq!(a.graph.find("c"), None);
+    assert!(!a.adjacencies.digest().contains_key("c"));
+
+    // Keys pulled from a neighbour which proved itself are taken, later pushes are checked
+    assert!(a.apply(vec![signed(&b, "b", 1, vec![edge("b", "c", 40)])]));
+    assert!(a.receive(vec![signed(&b, "b", 2, vec![edge("b", "c", 20)])]));
+    assert!(!a.receive(vec![signed(&c, "b", 3, vec![])]));
+    assert_eq!(a.route("c").map(|(cost, _)| cost), Some(30));
+}
+
+#[test]
+fn gossip_grows_the_graph_up_to_a_limit() {
+    let mut a = Node::new("a", &[("b", 10)]);
+    let b = Identity::generate();
+    let edges = (0..MAX_HOSTS + 10)
+        .map(|at| edge("b", &at.to_string(), 10))
+        .collect();
+    assert!(a.apply(vec![signed(&b, "b", 1, edges)]));
+    assert_eq!(a.graph.node_count(), MAX_HOSTS);
+
+    // Nor is there room for the adjacency of another host
+    let c = Identity::generate();
+    assert!(!a.apply(vec![signed(&c, "c", 1, vec![])]));
+    assert_eq!(a.graph.find("c"), None);
+}
@@ -0,0 +1,430 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    runtime::{Builder, Runtime},
+    sync::{broadcast::Receiver, mpsc, watch},
+    time::Instant,
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use entity::proto::{
+    federation_service_server::{FederationService as IFederationService, FederationServiceServer},
+    Challenge, GossipPacket, HeartbeatPacket, Host, Hosts, Introduction, Packet, Proof,
+};
+use federation::{
+    client::FederationClient,
+    crypto::{self, Identity},
+    limits::Limits,
+    outbox::Outbox,
+    replay::Replay,
+    service::FederationService,
+    trust::TrustStore,
+};
+
+/// Intervals are short so a whole network settles within a second
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+pub const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+pub const RETRY: Duration = Duration::from_millis(100);
+
+/// Time for nodes to notice connections were cut
+pub const SETTLE: Duration = Duration::from_millis(250);
+
+/// Staleness is tracked in whole seconds, this is the shortest timeout there is
+pub const STALE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest a test waits for the network to do something
+pub const PATIENCE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub outbox_ttl: Duration,
+    pub outbox_capacity: usize,
+    pub onion: bool,
+    pub limits: Limits,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            outbox_ttl: Duration::from_secs(3600),
+            outbox_capacity: 1024,
+            onion: false,
+            limits: Limits {
+                packets: 10_000,
+                bytes: 64 * 1024 * 1024,
+                max_data: 64 * 1024,
+                max_path: 64,
+                strikes: 10,
+                ban: Duration::from_secs(60),
+            },
+        }
+    }
+}
+
+/// Federation nodes of one process, every node listens on an ephemeral port behind its link
+pub struct Network {
+    pub nodes: Vec<Node>,
+}
+
+/// Node and the link other nodes reach it through, its addr is the one of the link
+/// Every node runs on its own runtime, so crashing it takes all its connections down at once
+pub struct Node {
+    pub service: FederationService,
+    pub link: Link,
+    runtime: Mutex<Option<Runtime>>,
+}
+
+impl Network {
+    /// Start `size` nodes trusting each other, `(from, to)` makes `from` bootstrap from `to`
+    pub async fn start(size: usize, seeds: &[(usize, usize)], options: Options) -> Self {
+        let identities = (0..size).map(|_| Identity::generate()).collect::<Vec<_>>();
+        let keys = identities
+            .iter()
+            .map(|identity| crypto::encode_key(&identity.public_key()))
+            .collect::<Vec<_>>();
+
+        let mut nodes = vec![];
+        for identity in identities {
+            let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            server.set_nonblocking(true).unwrap();
+            let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let link = Link::new(front.local_addr().unwrap());
+            tokio::spawn(link.clone().proxy(front, server.local_addr().unwrap()));
+
+            let host = Host {
+                addr: link.addr().to_string(),
+                public_key: identity.public_key(),
+                ..Default::default()
+            };
+            let trust = TrustStore::new(&identity, &keys, &[], false, 1).unwrap();
+            let outbox =
+                Outbox::new(None, options.outbox_ttl, RETRY, options.outbox_capacity).unwrap();
+            let service = FederationService::new(
+                host,
+                identity,
+                trust,
+                false,
+                options.onion,
+                None,
+                outbox,
+                Replay::new(Duration::from_secs(600), 16),
+                options.limits.clone(),
+            )
+            .unwrap();
+            let runtime = Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()
+                .unwrap();
+            let server = {
+                let _runtime = runtime.enter();
+                TcpListener::from_std(server).unwrap()
+            };
+            runtime.spawn(
+                Server::builder()
+                    .add_service(FederationServiceServer::new(service.clone()))
+                    .serve_with_incoming(TcpListenerStream::new(server)),
+            );
+            nodes.push(Node {
+                service,
+                link,
+                runtime: Mutex::new(Some(runtime)),
+            });
+        }
+
+        for (at, node) in nodes.iter().enumerate() {
+            let service = &node.service;
+            let seeds = seeds
+                .iter()
+                .filter(|(from, _)| *from == at)
+                .map(|(_, to)| nodes[*to].addr().to_string())
+                .collect();
+            let runtime = node.runtime.lock().unwrap();
+            let runtime = runtime.as_ref().unwrap();
+            runtime.spawn(service.liveness(HEARTBEAT_INTERVAL, STALE_TIMEOUT).run());
+            runtime.spawn(service.gossip(GOSSIP_INTERVAL));
+            runtime.spawn(FederationClient::new(service.clone(), RETRY).run(seeds));
+        }
+        Self { nodes }
+    }
+
+    pub fn addr(&self, node: usize) -> &str {
+        self.nodes[node].addr()
+    }
+
+    pub fn inbox(&self, node: usize) -> Receiver<Packet> {
+        self.nodes[node].service.inbox()
+    }
+
+    /// Wait until the lowest latency path between the nodes goes through the hops, both ends included
+    pub async fn path(&self, from: usize, hops: &[usize]) -> Vec<Host> {
+        let to = self.addr(*hops.last().unwrap());
+        let expected = hops.iter().map(|hop| self.addr(*hop)).collect::<Vec<_>>();
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            let path = self.nodes[from]
+                .service
+                .path_to(to)
+                .await
+                .unwrap_or_default();
+            // Packets are sealed to every hop, keys come with introductions and gossip
+            let keyed = path.iter().skip(1).all(|host| !host.public_key.is_empty());
+            if keyed && addrs(&path) == expected {
+                return path;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Path {:?} never became {:?}",
+                addrs(&path),
+                expected
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Node {
+    pub fn addr(&self) -> &str {
+        self.link.addr()
+    }
+
+    /// Stop every task of the node, it neither answers nor dials anymore
+    pub fn crash(&self) {
+        if let Some(runtime) = self.runtime.lock().unwrap().take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.crash();
+    }
+}
+
+/// Access link of a node, a TCP proxy every connection to it goes through
+/// Taking it down cuts the open connections and refuses new ones, connections the node dialed
+/// itself go through the links of their peers and stay up, crash the node to cut both ways
+#[derive(Clone)]
+pub struct Link {
+    state: Arc<LinkState>,
+}
+
+struct LinkState {
+    addr: String,
+    down: watch::Sender<bool>,
+    latency: AtomicU64, // Micros added to every byte, both ways
+}
+
+impl Link {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            state: Arc::new(LinkState {
+                addr: addr.to_string(),
+                down: watch::Sender::new(false),
+                latency: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.state.addr
+    }
+
+    /// Sockets close at once, peers notice it within a moment
+    pub async fn down(&self) {
+        self.state.down.send_replace(true);
+        tokio::time::sleep(SETTLE).await;
+    }
+
+    pub fn up(&self) {
+        self.state.down.send_replace(false);
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.state
+            .latency
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::from_micros(self.state.latency.load(Ordering::Relaxed))
+    }
+
+    async fn proxy(self, front: TcpListener, server: SocketAddr) {
+        loop {
+            let Ok((inbound, _)) = front.accept().await else {
+                continue;
+            };
+            if *self.state.down.borrow() {
+                continue; // Closed right away
+            }
+            let Ok(outbound) = TcpStream::connect(server).await else {
+                continue;
+            };
+            let (inbound_rx, inbound_tx) = inbound.into_split();
+            let (outbound_rx, outbound_tx) = outbound.into_split();
+            tokio::spawn(self.clone().pipe(inbound_rx, outbound_tx));
+            tokio::spawn(self.clone().pipe(outbound_rx, inbound_tx));
+        }
+    }
+
+    /// Copy one direction of a connection, holding every chunk back for the latency
+    async fn pipe(self, mut from: OwnedReadHalf, mut to: OwnedWriteHalf) {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+        let read = async {
+            let mut buf = vec![0; 16 * 1024];
+            while let Ok(read @ 1..) = from.read(&mut buf).await {
+                if tx
+                    .send((Instant::now() + self.latency(), buf[..read].to_vec()))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            drop(tx);
+        };
+        let write = async {
+            while let Some((at, chunk)) = rx.recv().await {
+                tokio::time::sleep_until(at).await;
+                if to.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let mut down = self.state.down.subscribe();
+        tokio::select! {
+            _ = futures::future::join(read, write) => {}
+            _ = down.wait_for(|down| *down) => {}
+        }
+    }
+}
+
+/// Server which only proves it listens on its addr, under the identity it holds at the time
+/// Stands in for hosts introduced by hand, whose key changes or gets banned
+#[derive(Clone)]
+pub struct Claimant {
+    addr: String,
+    identity: Arc<Mutex<Identity>>,
+}
+
+impl Claimant {
+    pub async fn start(identity: Identity) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let claimant = Self {
+            addr: listener.local_addr().unwrap().to_string(),
+            identity: Arc::new(Mutex::new(identity)),
+        };
+        tokio::spawn(
+            Server::builder()
+                .add_service(FederationServiceServer::new(claimant.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        claimant
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Prove with the identity from now on, returns the handover signed by the previous one
+    pub fn rekey(&self, identity: Identity) -> Vec<u8> {
+        let handover = identity.public_key();
+        let previous = std::mem::replace(&mut *self.identity.lock().unwrap(), identity);
+        previous.rekey(&self.addr, &handover)
+    }
+}
+
+#[tonic::async_trait]
+impl IFederationService for Claimant {
+    type ForwardStream = tokio_stream::Empty<Result<Packet, Status>>;
+
+    async fn acknowledge(&self, _: Request<Introduction>) -> Result<Response<Hosts>, Status> {
+        Err(Status::unimplemented("Claimants only prove"))
+    }
+
+    async fn forward(
+        &self,
+        _: Request<Streaming<Packet>>,
+    ) -> Result<Response<Self::ForwardStream>, Status> {
+        Err(Status::unimplemented("Claimants only prove"))
+    }
+
+    async fn request_path(&self, _: Request<Host>) -> Result<Response<Hosts>, Status> {
+        Err(Status::unimplemented("Claimants only prove"))
+    }
+
+    async fn heartbeat(
+        &self,
+        _: Request<HeartbeatPacket>,
+    ) -> Result<Response<HeartbeatPacket>, Status> {
+        Err(Status::unimplemented("Claimants only prove"))
+    }
+
+    async fn gossip(&self, _: Request<GossipPacket>) -> Result<Response<GossipPacket>, Status> {
+        Err(Status::unimplemented("Claimants only prove"))
+    }
+
+    async fn prove(&self, request: Request<Challenge>) -> Result<Response<Proof>, Status> {
+        let challenge = request.into_inner();
+        let identity = self.identity.lock().unwrap();
+        Ok(Response::new(identity.prove_addr(
+            &challenge.nonce,
+            &challenge.addr,
+            &self.addr,
+        )))
+    }
+}
+
+/// First packet of the inbox the filter takes, others are skipped
+pub async fn expect<T>(
+    inbox: &mut Receiver<Packet>,
+    mut filter: impl FnMut(Packet) -> Option<T>,
+) -> T {
+    let wait = async {
+        loop {
+            if let Some(found) = filter(inbox.recv().await.unwrap()) {
+                return found;
+            }
+        }
+    };
+    tokio::time::timeout(PATIENCE, wait)
+        .await
+        .expect("Packet never arrived")
+}
+
+/// Nothing the filter takes reaches the inbox for the duration
+pub async fn expect_none(
+    inbox: &mut Receiver<Packet>,
+    duration: Duration,
+    mut filter: impl FnMut(Packet) -> bool,
+) {
+    let wait = async {
+        loop {
+            if filter(inbox.recv().await.unwrap()) {
+                return;
+            }
+        }
+    };
+    assert!(
+        tokio::time::timeout(duration, wait).await.is_err(),
+        "Packet arrived too soon"
+    );
+}
+
+pub fn addrs(path: &[Host]) -> Vec<&str> {
+    path.iter().map(|host| host.addr.as_str()).collect()
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use entity::proto::{packet::Packet as PacketType, ForwardPacket, Host, Packet};
+use federation::{
+    bans::Bans,
+    limits::{Limits, RateLimiter, Violation},
+    stats::PeerStats,
+};
+
+fn limits() -> Limits {
+    Limits {
+        packets: 3,
+        bytes: 1024 * 1024,
+        max_data: 64,
+        max_path: 4,
+        strikes: 3,
+        ban: Duration::from_secs(60),
+    }
+}
+
+fn forward(hops: usize, data: usize) -> Packet {
+    Packet {
+        packet: Some(PacketType::Forward(ForwardPacket {
+            path: (0..hops)
+                .map(|hop| Host {
+                    addr: format!("host-{}", hop),
+                    ..Default::default()
+                })
+                .collect(),
+            data: vec![0; data],
+            ..Default::default()
+        })),
+    }
+}
+
+#[test]
+fn bursts_over_the_rate_are_limited_per_peer() {
+    let limiter = RateLimiter::new(limits());
+    let packet = forward(2, 8);
+    for _ in 0..3 {
+        assert_eq!(limiter.check("a", &packet), Ok(()));
+    }
+    assert_eq!(limiter.check("a", &packet), Err(Violation::TooFast));
+    assert_eq!(limiter.check("b", &packet), Ok(()));
+
+    std::thread::sleep(Duration::from_millis(400));
+    assert_eq!(limiter.check("a", &packet), Ok(()));
+}
+
+#[test]
+fn oversized_packets_and_long_paths_are_refused() {
+    let limiter = RateLimiter::new(limits());
+    // Sealing adds a tag, data at the limit still fits
+    assert_eq!(limiter.check("a", &forward(2, 64 + 16)), Ok(()));
+    assert_eq!(
+        limiter.check("a", &forward(2, 64 + 17)),
+        Err(Violation::TooLarge)
+    );
+    assert_eq!(
+        limiter.check("a", &forward(5, 8)),
+        Err(Violation::PathTooLong)
+    );
+}
+
+#[test]
+fn packets_over_the_byte_rate_are_too_large() {
+    let limiter = RateLimiter::new(Limits {
+        bytes: 32,
+        ..limits()
+    });
+    // The bucket never holds as much, the packet would be too fast forever
+    assert_eq!(
+        limiter.check("a", &forward(2, 64)),
+        Err(Violation::TooLarge)
+    );
+    assert_eq!(limiter.check("a", &forward(1, 0)), Ok(()));
+}
+
+#[test]
+fn peers_are_banned_once_out_of_strikes() {
+    let limiter = RateLimiter::new(limits());
+    assert!(!limiter.strike("a"));
+    assert!(!limiter.strike("a"));
+    assert!(!limiter.strike("b"));
+    assert!(limiter.strike("a"));
+    // Strikes start over after the ban
+    assert!(!limiter.strike("a"));
+}
+
+#[test]
+fn idle_counters_and_lifted_bans_are_pruned() {
+    // Buckets are pruned once per strike window, along with the rest
+    assert!(!RateLimiter::new(limits()).prune());
+
+    let stats = PeerStats::default();
+    stats.received("idle");
+    std::thread::sleep(Duration::from_millis(50));
+    stats.sent("busy");
+    stats.prune(Duration::from_millis(25));
+    assert_eq!(stats.counts().into_keys().collect::<Vec<_>>(), ["busy"]);
+
+    let bans = Bans::default();
+    bans.ban("lifted", vec![1], Some(Duration::ZERO));
+    bans.ban("running", vec![2], None);
+    bans.prune();
+    assert!(!bans.unban("lifted"));
+    assert!(bans.unban("running"));
+}
@@ -0,0 +1,693 @@
+mod harness;
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Code, Request};
+
+use entity::proto::{
+    admin_service_server::AdminService as _, federation_service_client::FederationServiceClient,
+    federation_service_server::FederationService as _, packet::Packet as PacketType,
+    AcknowledgePacket, BanRequest, ForwardPacket, HeartbeatPacket, Host, Introduction, Packet,
+    PeerRequest, PeerState, RevokeRequest, TrustStatus,
+};
+use federation::{
+    admin::Admin,
+    client::{FederationClient, PEER_ADDR_HEADER},
+    crypto::Identity,
+};
+
+use harness::{expect, expect_none, Claimant, Network, Options, PATIENCE, RETRY};
+
+fn forward(packet: Packet) -> Option<ForwardPacket> {
+    match packet.packet {
+        Some(PacketType::Forward(forward)) => Some(forward),
+        _ => None,
+    }
+}
+
+fn acknowledge(packet: Packet) -> Option<AcknowledgePacket> {
+    match packet.packet {
+        Some(PacketType::Acknowledge(ack)) => Some(ack),
+        _ => None,
+    }
+}
+
+/// Two relays between 0 and 3, neither is slower than the other
+fn diamond() -> [(usize, usize); 4] {
+    [(0, 1), (0, 2), (3, 1), (3, 2)]
+}
+
+/// Send from the first host of the path to the last, which has to get it and acknowledge it
+async fn deliver(network: &Network, from: usize, to: usize, hops: &[usize]) {
+    let path = network.path(from, hops).await;
+    let mut inbox = network.inbox(to);
+    let mut acks = network.inbox(from);
+    let id = rand::random::<[u8; 16]>().to_vec();
+    network.nodes[from]
+        .service
+        .send(id.clone(), path, b"hello".to_vec())
+        .await
+        .unwrap();
+
+    let arrived = expect(&mut inbox, forward).await;
+    assert_eq!(arrived.data, b"hello");
+    assert_eq!(arrived.id, id);
+    let ack = expect(&mut acks, |packet| {
+        acknowledge(packet).filter(|ack| ack.forward.as_ref().is_some_and(|f| f.id == id))
+    })
+    .await;
+    assert!(ack.success);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_along_a_line() {
+    let network = Network::start(4, &[(0, 1), (1, 2), (2, 3)], Options::default()).await;
+    deliver(&network, 0, 3, &[0, 1, 2, 3]).await;
+    deliver(&network, 3, 0, &[3, 2, 1, 0]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn routes_around_a_slow_link() {
+    let network = Network::start(4, &diamond(), Options::default()).await;
+    network.nodes[1].link.set_latency(Duration::from_millis(20));
+    deliver(&network, 0, 3, &[0, 2, 3]).await;
+
+    network.nodes[1].link.set_latency(Duration::ZERO);
+    network.nodes[2].link.set_latency(Duration::from_millis(20));
+    deliver(&network, 0, 3, &[0, 1, 3]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reroutes_around_a_crashed_node() {
+    let network = Network::start(4, &diamond(), Options::default()).await;
+    network.nodes[1].link.set_latency(Duration::from_millis(20));
+    network.path(0, &[0, 2, 3]).await;
+
+    network.nodes[2].crash();
+    deliver(&network, 0, 3, &[0, 1, 3]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn routes_around_a_banned_host() {
+    let network = Network::start(4, &diamond(), Options::default()).await;
+    network.nodes[1].link.set_latency(Duration::from_millis(20));
+    network.path(0, &[0, 2, 3]).await;
+
+    let service = &network.nodes[0].service;
+    service.ban(network.addr(2), None).await.unwrap();
+    deliver(&network, 0, 3, &[0, 1, 3]).await;
+    let peers = service.peers().await;
+    let banned = peers
+        .iter()
+        .find(|peer| peer.addr == network.addr(2))
+        .unwrap();
+    assert!(banned.banned && !banned.neighbour);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn holds_packets_until_the_link_recovers() {
+    let network = Network::start(3, &[(0, 1), (1, 2)], Options::default()).await;
+    let path = network.path(0, &[0, 1, 2]).await;
+    let mut inbox = network.inbox(2);
+    let mut acks = network.inbox(0);
+
+    network.nodes[2].link.down().await;
+    network.nodes[0]
+        .service
+        .send(vec![1], path, b"later".to_vec())
+        .await
+        .unwrap();
+    expect_none(&mut inbox, Duration::from_secs(1), |packet| {
+        forward(packet).is_some()
+    })
+    .await;
+
+    network.nodes[2].link.up();
+    let arrived = expect(&mut inbox, forward).await;
+    assert_eq!(arrived.data, b"later");
+    let ack = expect(&mut acks, acknowledge).await;
+    assert!(ack.success);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nacks_packets_which_waited_too_long() {
+    let options = Options {
+        outbox_ttl: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let network = Network::start(3, &[(0, 1), (1, 2)], options).await;
+    let path = network.path(0, &[0, 1, 2]).await;
+    let mut acks = network.inbox(0);
+
+    network.nodes[2].link.down().await;
+    network.nodes[0]
+        .service
+        .send(vec![1], path, b"never".to_vec())
+        .await
+        .unwrap();
+    let ack = expect(&mut acks, acknowledge).await;
+    assert!(!ack.success);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn seals_only_to_bound_or_trusted_keys() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    let mut path = network.path(0, &[0, 1]).await;
+    let service = &network.nodes[0].service;
+
+    path[1].public_key = Identity::generate().public_key();
+    let refused = service.send(vec![1], path.clone(), b"leak".to_vec()).await;
+    assert!(refused.is_err());
+    // Left out, the key bound to the host is taken
+    path[1].public_key = vec![];
+    service
+        .send(vec![2], path, b"sealed".to_vec())
+        .await
+        .unwrap();
+    let arrived = expect(&mut network.inbox(1), forward).await;
+    assert_eq!(arrived.id, [2]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drops_the_oldest_packets_of_a_full_queue() {
+    let options = Options {
+        outbox_capacity: 2,
+        ..Default::default()
+    };
+    let network = Network::start(3, &[(0, 1), (1, 2)], options).await;
+    let path = network.path(0, &[0, 1, 2]).await;
+    let mut inbox = network.inbox(2);
+    let mut acks = network.inbox(0);
+
+    network.nodes[2].link.down().await;
+    // Packets on a link which is still open would wait in its channel instead
+    let relay = &network.nodes[1].service;
+    let deadline = std::time::Instant::now() + PATIENCE;
+    while relay
+        .peers()
+        .await
+        .iter()
+        .any(|peer| peer.addr == network.addr(2) && peer.links > 0)
+    {
+        assert!(std::time::Instant::now() < deadline, "Link never closed");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for id in 1..=3u8 {
+        network.nodes[0]
+            .service
+            .send(vec![id], path.clone(), vec![id])
+            .await
+            .unwrap();
+        // Links have room for as many packets, one at a time they all get to the queue
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    let nack = expect(&mut acks, acknowledge).await;
+    assert!(!nack.success);
+    assert_eq!(nack.forward.unwrap().id, [1]);
+
+    network.nodes[2].link.up();
+    for id in 2..=3u8 {
+        let arrived = expect(&mut inbox, forward).await;
+        assert_eq!(arrived.data, [id]);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_onions_along_a_line() {
+    let options = Options {
+        onion: true,
+        ..Default::default()
+    };
+    let network = Network::start(4, &[(0, 1), (1, 2), (2, 3)], options).await;
+    let mut relayed = network.inbox(1);
+    deliver(&network, 0, 3, &[0, 1, 2, 3]).await;
+    assert!(relayed.try_recv().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bans_a_flooding_peer() {
+    let mut options = Options::default();
+    options.limits.packets = 5;
+    options.limits.strikes = 3;
+    let network = Network::start(2, &[(0, 1)], options).await;
+    let path = network.path(0, &[0, 1]).await;
+
+    for id in 0..20u8 {
+        network.nodes[0]
+            .service
+            .send(vec![id], path.clone(), b"flood".to_vec())
+            .await
+            .unwrap();
+    }
+    let service = &network.nodes[1].service;
+    tokio::time::timeout(PATIENCE, async {
+        while !service.is_banned(network.addr(0)) {
+            tokio::time::sleep(RETRY).await;
+        }
+    })
+    .await
+    .unwrap();
+    let peers = service.peers().await;
+    let flooder = peers
+        .iter()
+        .find(|peer| peer.addr == network.addr(0))
+        .unwrap();
+    assert!(flooder.limited >= 3 && !flooder.neighbour);
+}
+
+/// Open a stream to the node as `claimed`, answering its challenge with the identity
+/// Returns how the node ended the stream
+async fn impersonate(network: &Network, node: usize, claimed: &str, identity: &Identity) -> Code {
+    let addr = network.addr(node);
+    let mut client = FederationServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let mut request = Request::new(ReceiverStream::new(rx));
+    request
+        .metadata_mut()
+        .insert(PEER_ADDR_HEADER, claimed.parse().unwrap());
+    let mut incoming = client.forward(request).await.unwrap().into_inner();
+    let Some(PacketType::Challenge(challenge)) = incoming.message().await.unwrap().unwrap().packet
+    else {
+        panic!("Stream didn't start with a challenge");
+    };
+    let proof = identity.prove(&challenge.nonce, addr, claimed);
+    tx.send(Packet {
+        packet: Some(PacketType::Proof(proof)),
+    })
+    .await
+    .unwrap();
+    let ended = tokio::time::timeout(PATIENCE, incoming.message())
+        .await
+        .unwrap();
+    drop(tx);
+    ended.unwrap_err().code()
+}
+
+/// Introduce the host to the node, as it tells about itself
+async fn introduce(
+    network: &Network,
+    node: usize,
+    addr: &str,
+    public_key: Vec<u8>,
+    rekey: Vec<u8>,
+) -> Result<(), Code> {
+    let introduction = Introduction {
+        host: Some(Host {
+            addr: addr.to_string(),
+            public_key,
+            ..Default::default()
+        }),
+        rekey,
+        ..Default::default()
+    };
+    network.nodes[node]
+        .service
+        .acknowledge(Request::new(introduction))
+        .await
+        .map(|_| ())
+        .map_err(|status| status.code())
+}
+
+/// Key the node knows for the host, if it knows the host at all
+async fn key_of(network: &Network, node: usize, addr: &str) -> Option<Vec<u8>> {
+    let topology = network.nodes[node].service.topology().await;
+    let host = topology.hosts.into_iter().find(|host| host.addr == addr);
+    host.map(|host| host.public_key)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn introductions_are_proven_by_their_addr() {
+    let network = Network::start(2, &[], Options::default()).await;
+    let (addr, stranger) = (network.addr(1), Identity::generate());
+    // Node 1 listens there and proves with its own key
+    let code = introduce(&network, 0, addr, stranger.public_key(), vec![]).await;
+    assert_eq!(code, Err(Code::Unauthenticated));
+    let code = introduce(&network, 0, "127.0.0.1:1", stranger.public_key(), vec![]).await;
+    assert_eq!(code, Err(Code::Unavailable));
+    assert_eq!(key_of(&network, 0, addr).await, None);
+    assert_eq!(key_of(&network, 0, "127.0.0.1:1").await, None);
+
+    let topology = network.nodes[1].service.topology().await;
+    let key = topology.hosts[0].public_key.clone();
+    introduce(&network, 0, addr, key.clone(), vec![])
+        .await
+        .unwrap();
+    assert_eq!(key_of(&network, 0, addr).await, Some(key));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_the_first_key_of_a_host() {
+    let network = Network::start(1, &[], Options::default()).await;
+    let (first, second) = (Identity::generate(), Identity::generate());
+    let (first_key, second_key) = (first.public_key(), second.public_key());
+    let claimant = Claimant::start(first).await;
+    let addr = claimant.addr();
+    introduce(&network, 0, addr, first_key.clone(), vec![])
+        .await
+        .unwrap();
+
+    let handover = claimant.rekey(second);
+    let code = introduce(&network, 0, addr, second_key.clone(), vec![]).await;
+    assert_eq!(code, Err(Code::PermissionDenied));
+    // Signed by the wrong key
+    let forged = Identity::generate().rekey(addr, &second_key);
+    assert!(introduce(&network, 0, addr, second_key.clone(), forged)
+        .await
+        .is_err());
+    assert_eq!(key_of(&network, 0, addr).await, Some(first_key));
+
+    introduce(&network, 0, addr, second_key.clone(), handover)
+        .await
+        .unwrap();
+    assert_eq!(key_of(&network, 0, addr).await, Some(second_key));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refuses_streams_of_impersonators() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    network.path(1, &[1, 0]).await;
+    let stranger = Identity::generate();
+    let claimed = network.addr(0).to_string();
+    assert_eq!(
+        impersonate(&network, 1, &claimed, &stranger).await,
+        Code::Unauthenticated
+    );
+    // Neither does a proof of another challenge, for another server
+    let mut client = FederationServiceClient::connect(format!("http://{}", network.addr(1)))
+        .await
+        .unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let mut incoming = client
+        .forward(Request::new(ReceiverStream::new(rx)))
+        .await
+        .unwrap()
+        .into_inner();
+    incoming.message().await.unwrap();
+    tx.send(Packet {
+        packet: Some(PacketType::Proof(stranger.prove(b"nonce", "elsewhere", ""))),
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        incoming.message().await.unwrap_err().code(),
+        Code::Unauthenticated
+    );
+    // Peers are still linked, nothing got through to them
+    deliver(&network, 0, 1, &[0, 1]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn banned_keys_are_refused_under_any_addr() {
+    let network = Network::start(1, &[], Options::default()).await;
+    // The claimant proves with the key we later impersonate it with
+    let seed = STANDARD.encode(rand::random::<[u8; 32]>());
+    let banned = Identity::from_config(&seed).unwrap();
+    let key = banned.public_key();
+    let claimant = Claimant::start(Identity::from_config(&seed).unwrap()).await;
+    introduce(&network, 0, claimant.addr(), key.clone(), vec![])
+        .await
+        .unwrap();
+    let service = &network.nodes[0].service;
+    service.ban(claimant.addr(), None).await.unwrap();
+
+    let code = impersonate(&network, 0, "127.0.0.1:2", &banned).await;
+    assert_eq!(code, Code::PermissionDenied);
+}
+
+/// Wait until the node's view of the peer passes the check, peers it forgot are `None`
+async fn until(
+    network: &Network,
+    node: usize,
+    of: usize,
+    what: &str,
+    check: impl Fn(Option<&PeerState>) -> bool,
+) -> Option<PeerState> {
+    let deadline = Instant::now() + PATIENCE;
+    loop {
+        let peers = network.nodes[node].service.peers().await;
+        let peer = peers.into_iter().find(|peer| peer.addr == network.addr(of));
+        if check(peer.as_ref()) {
+            return peer;
+        }
+        assert!(Instant::now() < deadline, "Peer never became {}", what);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+fn neighbour(peer: Option<&PeerState>) -> bool {
+    peer.is_some_and(|peer| peer.neighbour)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unanswered_hosts_go_stale_whoever_claims_them() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    let seen = until(&network, 0, 1, "seen", |peer| {
+        peer.is_some_and(|peer| peer.neighbour && peer.last_seen.is_some())
+    })
+    .await;
+    network.nodes[1].link.down().await;
+
+    // Anyone can heartbeat in the name of the host, it doesn't keep it fresh
+    let mut client = FederationServiceClient::connect(format!("http://{}", network.addr(0)))
+        .await
+        .unwrap();
+    let claim = HeartbeatPacket {
+        host: Some(Host {
+            addr: network.addr(1).to_string(),
+            ..Default::default()
+        }),
+    };
+    let deadline = Instant::now() + PATIENCE;
+    loop {
+        client.heartbeat(claim.clone()).await.unwrap();
+        let peers = network.nodes[0].service.peers().await;
+        let peer = peers.iter().find(|peer| peer.addr == network.addr(1));
+        if !neighbour(peer) {
+            break;
+        }
+        assert_eq!(peer.unwrap().last_seen, seen.as_ref().unwrap().last_seen);
+        assert!(Instant::now() < deadline, "Peer never went stale");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let topology = network.nodes[0].service.topology().await;
+    let host = topology
+        .hosts
+        .iter()
+        .find(|host| host.addr == network.addr(1))
+        .unwrap();
+    assert_eq!(host.last_seen, seen.unwrap().last_seen);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stale_hosts_are_linked_again_once_they_answer() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    until(&network, 0, 1, "a neighbour", neighbour).await;
+    network.nodes[1].link.down().await;
+    until(&network, 0, 1, "stale", |peer| !neighbour(peer)).await;
+
+    network.nodes[1].link.up();
+    until(&network, 0, 1, "a neighbour again", neighbour).await;
+    deliver(&network, 0, 1, &[0, 1]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn edges_are_weighted_by_heartbeat_round_trips() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    let weight = |below: u32| {
+        move |peer: Option<&PeerState>| {
+            peer.is_some_and(|peer| peer.neighbour && peer.weight < below)
+        }
+    };
+    until(&network, 0, 1, "measured", weight(20)).await;
+
+    network.nodes[1].link.set_latency(Duration::from_millis(20));
+    until(&network, 0, 1, "slower", |peer| {
+        peer.is_some_and(|peer| peer.weight >= 30)
+    })
+    .await;
+
+    network.nodes[1].link.set_latency(Duration::ZERO);
+    until(&network, 0, 1, "fast again", weight(20)).await;
+}
+
+/// Admin service of the node, as the operator reaches it
+fn admin(network: &Network, node: usize) -> Admin {
+    let service = network.nodes[node].service.clone();
+    Admin::new(service.clone(), FederationClient::new(service, RETRY))
+}
+
+fn peer(addr: &str) -> Request<PeerRequest> {
+    Request::new(PeerRequest {
+        addr: addr.to_string(),
+    })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_removes_peers() {
+    let network = Network::start(3, &[(0, 1), (1, 2)], Options::default()).await;
+    network.path(0, &[0, 1, 2]).await;
+    let admin = admin(&network, 0);
+
+    admin.remove_peer(peer(network.addr(2))).await.unwrap();
+    let peers = network.nodes[0].service.peers().await;
+    assert!(peers.iter().all(|peer| peer.addr != network.addr(2)));
+    let unknown = admin.remove_peer(peer("127.0.0.1:1")).await.unwrap_err();
+    assert_eq!(unknown.code(), Code::NotFound);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_bans_and_unbans_peers() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    until(&network, 0, 1, "a neighbour", neighbour).await;
+    let admin = admin(&network, 0);
+
+    let ban = |addr: &str| {
+        Request::new(BanRequest {
+            addr: addr.to_string(),
+            duration: 0,
+        })
+    };
+    let own = admin.ban(ban(network.addr(0))).await.unwrap_err();
+    assert_eq!(own.code(), Code::InvalidArgument);
+    admin.ban(ban(network.addr(1))).await.unwrap();
+    assert!(network.nodes[0].service.is_banned(network.addr(1)));
+    let refused = admin.add_peer(peer(network.addr(1))).await.unwrap_err();
+    assert_eq!(refused.code(), Code::FailedPrecondition);
+
+    admin.unban(peer(network.addr(1))).await.unwrap();
+    assert!(!network.nodes[0].service.is_banned(network.addr(1)));
+    let lifted = admin.unban(peer(network.addr(1))).await.unwrap_err();
+    assert_eq!(lifted.code(), Code::NotFound);
+    // Added by hand, the peer is linked again
+    admin.add_peer(peer(network.addr(1))).await.unwrap();
+    until(&network, 0, 1, "a neighbour again", neighbour).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_revokes_servers() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    until(&network, 0, 1, "a neighbour", neighbour).await;
+    let admin = admin(&network, 0);
+    let revoke = |public_key: Vec<u8>| Request::new(RevokeRequest { public_key });
+    let trusted = |status: TrustStatus, key: &[u8]| {
+        status.servers.iter().any(|server| server.public_key == key)
+    };
+
+    let short = admin.revoke(revoke(vec![7; 16])).await.unwrap_err();
+    assert_eq!(short.code(), Code::InvalidArgument);
+    let own = key_of(&network, 0, network.addr(0)).await.unwrap();
+    let refused = admin.revoke(revoke(own)).await.unwrap_err();
+    assert_eq!(refused.code(), Code::InvalidArgument);
+
+    let key = key_of(&network, 0, network.addr(1)).await.unwrap();
+    let status = admin.trust(Request::new(())).await.unwrap().into_inner();
+    assert!(trusted(status, &key));
+    admin.revoke(revoke(key.clone())).await.unwrap();
+    let status = admin.trust(Request::new(())).await.unwrap().into_inner();
+    assert!(!trusted(status, &key));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_recomputes_paths() {
+    let network = Network::start(4, &diamond(), Options::default()).await;
+    network.nodes[1].link.set_latency(Duration::from_millis(20));
+    network.path(0, &[0, 2, 3]).await;
+
+    admin(&network, 0)
+        .recompute(Request::new(()))
+        .await
+        .unwrap();
+    // Paths computed again come from the topology as it is
+    deliver(&network, 0, 3, &[0, 2, 3]).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_lists_queued_packets() {
+    let network = Network::start(2, &[(0, 1)], Options::default()).await;
+    let path = network.path(0, &[0, 1]).await;
+    let admin = admin(&network, 0);
+    let queued = || async {
+        admin
+            .queue(Request::new(()))
+            .await
+            .unwrap()
+            .into_inner()
+            .packets
+    };
+    assert!(queued().await.is_empty());
+
+    network.nodes[1].link.down().await;
+    network.nodes[0]
+        .service
+        .send(vec![1], path, b"later".to_vec())
+        .await
+        .unwrap();
+    let deadline = Instant::now() + PATIENCE;
+    let packets = loop {
+        let packets = queued().await;
+        if !packets.is_empty() {
+            break packets;
+        }
+        assert!(Instant::now() < deadline, "Packet never got queued");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].next_hop, network.addr(1));
+    assert!(forward(packets[0].packet.clone().unwrap()).is_some_and(|packet| packet.id == [1]));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unbound_peers_are_banned_whatever_key_they_prove() {
+    let mut options = Options::default();
+    options.limits.strikes = 3;
+    let network = Network::start(1, &[], options).await;
+    let addr = network.addr(0);
+    // Paths longer than the limit are a strike, each stream proves a fresh key
+    let abuse = Packet {
+        packet: Some(PacketType::Forward(ForwardPacket {
+            path: vec![Host::default(); 65],
+            ..Default::default()
+        })),
+    };
+    for _ in 0..3 {
+        let mut client = FederationServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let mut request = Request::new(ReceiverStream::new(rx));
+        request
+            .metadata_mut()
+            .insert(PEER_ADDR_HEADER, "127.0.0.1:2".parse().unwrap());
+        let mut incoming = client.forward(request).await.unwrap().into_inner();
+        let Some(PacketType::Challenge(challenge)) =
+            incoming.message().await.unwrap().unwrap().packet
+        else {
+            panic!("Stream didn't start with a challenge");
+        };
+        let proof = Identity::generate().prove(&challenge.nonce, addr, "127.0.0.1:2");
+        for packet in [PacketType::Proof(proof), abuse.packet.clone().unwrap()] {
+            tx.send(Packet {
+                packet: Some(packet),
+            })
+            .await
+            .unwrap();
+        }
+        let ended = tokio::time::timeout(PATIENCE, incoming.message())
+            .await
+            .unwrap();
+        assert_eq!(ended.unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    // Strikes added up where the streams came from, a new key doesn't start over
+    let code = impersonate(&network, 0, "127.0.0.1:2", &Identity::generate()).await;
+    assert_eq!(code, Code::PermissionDenied);
+    let peers = network.nodes[0].service.peers().await;
+    let banned = peers.iter().find(|peer| peer.banned).unwrap();
+    assert_eq!(banned.addr, "ip:127.0.0.1");
+    assert_eq!(banned.rejected, 3);
+}
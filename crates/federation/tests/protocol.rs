@@ -0,0 +1,95 @@
+use entity::proto::{Capabilities, Host, PacketKind};
+use federation::{
+    graph::HostGraph,
+    protocol::{self, VERSION},
+};
+
+/// Host as this build tells it
+fn current(addr: &str) -> Host {
+    let mut host = Host {
+        addr: addr.to_string(),
+        ..Default::default()
+    };
+    protocol::stamp(&mut host);
+    host
+}
+
+#[test]
+fn current_hosts_get_everything() {
+    let host = current("new");
+    assert_eq!(protocol::negotiate(&host).unwrap(), VERSION);
+    assert!(protocol::seals(&host) && protocol::signs(&host));
+    assert!(protocol::supports(&host, PacketKind::Onion));
+}
+
+#[test]
+fn legacy_hosts_get_plain_packets() {
+    // Nodes from before versions tell nothing, whatever their key
+    let legacy = Host {
+        addr: "old".to_string(),
+        public_key: vec![7; 32],
+        ..Default::default()
+    };
+    assert_eq!(protocol::negotiate(&legacy).unwrap(), 1);
+    assert!(!protocol::seals(&legacy) && !protocol::signs(&legacy));
+    assert!(protocol::supports(&legacy, PacketKind::Forward));
+    assert!(!protocol::supports(&legacy, PacketKind::Onion));
+
+    // Capabilities only count along with a version
+    let claimed = Host {
+        capabilities: current("old").capabilities,
+        ..legacy
+    };
+    assert!(!protocol::seals(&claimed) && !protocol::signs(&claimed));
+}
+
+#[test]
+fn incompatible_hosts_are_refused() {
+    let newer = Host {
+        min_version: VERSION + 1,
+        ..current("newer")
+    };
+    assert!(protocol::negotiate(&newer).is_err());
+    let relay_only = Host {
+        capabilities: Some(Capabilities {
+            packets: vec![PacketKind::Onion as i32],
+            ..protocol::capabilities()
+        }),
+        ..current("relay")
+    };
+    assert!(protocol::negotiate(&relay_only).is_err());
+    // Hosts which don't tell their capabilities get the baseline
+    let quiet = Host {
+        capabilities: None,
+        ..current("quiet")
+    };
+    assert!(protocol::negotiate(&quiet).is_ok());
+    assert!(!protocol::seals(&quiet));
+}
+
+#[test]
+fn only_the_host_lowers_what_it_speaks() {
+    let mut graph = HostGraph::default();
+    let node = graph.upsert(current("host"));
+    // Told by anyone else, the baseline doesn't replace what the host said itself
+    let baseline = Host {
+        capabilities: Some(protocol::baseline()),
+        ..current("host")
+    };
+    assert!(protocol::downgrades(&graph[node], &baseline));
+    graph.upsert(baseline.clone());
+    assert!(protocol::seals(&graph[node]) && protocol::signs(&graph[node]));
+    let unversioned = Host {
+        version: 0,
+        ..current("host")
+    };
+    graph.upsert(unversioned);
+    assert_eq!(graph[node].version, VERSION);
+
+    // What the host tells with its own key is taken
+    graph.restate(node, &baseline);
+    assert!(!protocol::seals(&graph[node]) && !protocol::signs(&graph[node]));
+    assert!(!protocol::downgrades(&graph[node], &current("host")));
+    graph.upsert(current("host"));
+    assert!(protocol::seals(&graph[node]));
+}
@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use entity::proto::{ForwardPacket, Host};
+use federation::replay::{DropCounts, DropReason, Replay};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Packet of the origin along the hosts, sent now
+fn packet(origin: &[u8], id: &[u8], hosts: &[&str]) -> ForwardPacket {
+    ForwardPacket {
+        path: hosts
+            .iter()
+            .map(|addr| Host {
+                addr: addr.to_string(),
+                ..Default::default()
+            })
+            .collect(),
+        origin: origin.to_vec(),
+        id: id.to_vec(),
+        sent_at: now(),
+        max_hops: 16,
+        ..Default::default()
+    }
+}
+
+fn replay() -> Replay {
+    Replay::new(Duration::from_secs(600), 16)
+}
+
+#[test]
+fn duplicates_are_dropped_per_origin() {
+    let replay = replay();
+    let sent = packet(b"origin", b"id", &["a", "b"]);
+    assert_eq!(replay.forward(&sent), Ok(()));
+    assert_eq!(replay.forward(&sent), Err(DropReason::Duplicate));
+
+    // Another origin picking the same id can't burn it, nor the ack of the packet
+    assert_eq!(
+        replay.forward(&packet(b"other", b"id", &["a", "b"])),
+        Ok(())
+    );
+    assert_eq!(replay.acknowledge(&sent), Ok(()));
+    assert_eq!(replay.acknowledge(&sent), Err(DropReason::Duplicate));
+    assert_eq!(
+        replay.drops(),
+        DropCounts {
+            duplicate: 2,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn loops_and_long_paths_are_dropped() {
+    let replay = replay();
+    let looped = packet(b"origin", b"looped", &["a", "b", "a", "c"]);
+    assert_eq!(replay.forward(&looped), Err(DropReason::Looped));
+
+    let hosts = (0..17).map(|at| at.to_string()).collect::<Vec<_>>();
+    let hosts = hosts.iter().map(String::as_str).collect::<Vec<_>>();
+    let long = packet(b"origin", b"long", &hosts);
+    assert_eq!(replay.forward(&long), Err(DropReason::TooManyHops));
+    // The origin may ask for fewer hops than we allow
+    let mut short = packet(b"origin", b"short", &["a", "b", "c"]);
+    short.max_hops = 2;
+    assert_eq!(replay.forward(&short), Err(DropReason::TooManyHops));
+    assert_eq!(
+        replay.drops(),
+        DropCounts {
+            looped: 1,
+            too_many_hops: 2,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn expired_and_future_packets_are_dropped() {
+    let replay = replay();
+    let mut old = packet(b"origin", b"old", &["a", "b"]);
+    old.sent_at -= 601;
+    assert_eq!(replay.forward(&old), Err(DropReason::Expired));
+    let mut future = packet(b"origin", b"future", &["a", "b"]);
+    future.sent_at += 3600;
+    assert_eq!(replay.forward(&future), Err(DropReason::Expired));
+    assert_eq!(replay.drops().expired, 2);
+
+    // Dropped before they are remembered, the genuine packet still gets through
+    old.sent_at = now();
+    assert_eq!(replay.forward(&old), Ok(()));
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use petgraph::stable_graph::NodeIndex;
+
+use entity::proto::Host;
+use federation::{
+    graph::HostGraph,
+    routing::{self, UNMEASURED},
+};
+
+fn host(addr: &str) -> Host {
+    Host {
+        addr: addr.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Graph with the hosts `a`, `b`, ... and the given latencies between them
+fn topology(hosts: &[&str], links: &[(&str, &str, u32)]) -> HostGraph {
+    let mut graph = HostGraph::default();
+    for addr in hosts {
+        graph.upsert(host(addr));
+    }
+    for (from, to, weight) in links {
+        let from = graph.find(from).unwrap();
+        let to = graph.find(to).unwrap();
+        graph.set_weight(from, to, *weight);
+    }
+    graph
+}
+
+fn addrs(graph: &HostGraph, path: &[NodeIndex]) -> Vec<String> {
+    path.iter().map(|node| graph[*node].addr.clone()).collect()
+}
+
+fn route(graph: &HostGraph, from: &str, to: &str) -> Option<(u64, Vec<String>)> {
+    let from = graph.find(from).unwrap();
+    let to = graph.find(to).unwrap();
+    routing::shortest_path(graph, from, to).map(|(cost, path)| (cost, addrs(graph, &path)))
+}
+
+#[test]
+fn lowest_latency_beats_fewest_hops() {
+    let graph = topology(
+        &["a", "b", "c", "d"],
+        &[
+            ("a", "d", 300),
+            ("a", "b", 20),
+            ("b", "c", 30),
+            ("c", "d", 40),
+        ],
+    );
+    assert_eq!(
+        route(&graph, "a", "d"),
+        Some((90, vec!["a".into(), "b".into(), "c".into(), "d".into()]))
+    );
+}
+
+#[test]
+fn measured_links_are_preferred_over_unmeasured() {
+    let graph = topology(
+        &["a", "b", "c", "d"],
+        &[
+            ("a", "b", UNMEASURED),
+            ("b", "d", 10),
+            ("a", "c", 200),
+            ("c", "d", 200),
+        ],
+    );
+    assert_eq!(
+        route(&graph, "a", "d").map(|(_, path)| path),
+        Some(vec!["a".into(), "c".into(), "d".into()])
+    );
+}
+
+#[test]
+fn costs_of_the_heaviest_links_add_up() {
+    let graph = topology(
+        &["a", "b", "c"],
+        &[("a", "b", u32::MAX), ("b", "c", u32::MAX)],
+    );
+    assert_eq!(
+        route(&graph, "a", "c").map(|(cost, _)| cost),
+        Some(2 * u64::from(u32::MAX))
+    );
+}
+
+#[test]
+fn no_route_to_disconnected_host() {
+    let graph = topology(&["a", "b", "c"], &[("a", "b", 10), ("c", "a", 10)]);
+    assert_eq!(route(&graph, "a", "c"), None);
+}
+
+#[test]
+fn rtt_replaces_unmeasured_and_is_smoothed() {
+    let mut graph = topology(&["a", "b"], &[("a", "b", UNMEASURED)]);
+    let (a, b) = (graph.find("a").unwrap(), graph.find("b").unwrap());
+
+    routing::record_rtt(&mut graph, a, b, Duration::from_millis(100));
+    assert_eq!(graph[graph.find_edge(a, b).unwrap()], 100);
+
+    routing::record_rtt(&mut graph, a, b, Duration::from_millis(20));
+    assert_eq!(graph[graph.find_edge(a, b).unwrap()], 80);
+
+    // Sub-millisecond links still cost something
+    let mut graph = topology(&["a", "b"], &[]);
+    let (a, b) = (graph.find("a").unwrap(), graph.find("b").unwrap());
+    routing::record_rtt(&mut graph, a, b, Duration::from_micros(300));
+    assert_eq!(graph[graph.find_edge(a, b).unwrap()], 1);
+}
+
+#[test]
+fn cached_paths_are_dropped_on_invalidate() {
+    let cache = routing::PathCache::default();
+    cache.insert("b".into(), vec![host("a"), host("b")]);
+    assert_eq!(cache.get("b"), Some(vec![host("a"), host("b")]));
+    assert_eq!(cache.get("c"), None);
+
+    cache.invalidate();
+    assert_eq!(cache.get("b"), None);
+}
+
+#[test]
+fn reconnecting_hosts_are_merged() {
+    let mut graph = topology(&["a", "b"], &[("a", "b", 40)]);
+    let b = graph.find("b").unwrap();
+    let seen = prost_types::Timestamp {
+        seconds: 100,
+        nanos: 0,
+    };
+    graph.seen(b, seen.clone());
+
+    let again = graph.upsert(Host {
+        addr: "b".into(),
+        forwarder: true,
+        public_key: vec![7; 32],
+        last_seen: Some(prost_types::Timestamp {
+            seconds: 50,
+            nanos: 0,
+        }),
+        ..Default::default()
+    });
+    assert_eq!(again, b);
+    assert_eq!(graph.node_count(), 2);
+    assert!(graph[b].forwarder);
+    // The first key we learn is taken
+    assert_eq!(graph[b].public_key, vec![7; 32]);
+    // Older news doesn't roll `last_seen` back
+    assert_eq!(graph[b].last_seen, Some(seen));
+    assert_eq!(route(&graph, "a", "b").map(|(cost, _)| cost), Some(40));
+}
+
+#[test]
+fn conflicting_keys_are_ignored() {
+    let mut graph = HostGraph::default();
+    let b = graph.upsert(Host {
+        public_key: vec![7; 32],
+        ..host("b")
+    });
+    for public_key in [vec![9; 32], vec![]] {
+        graph.upsert(Host {
+            public_key,
+            ..host("b")
+        });
+        assert_eq!(graph[b].public_key, vec![7; 32]);
+    }
+    graph.rekey(b, vec![9; 32]);
+    assert_eq!(graph[b].public_key, vec![9; 32]);
+}
+
+#[test]
+fn removing_a_host_keeps_other_indexes() {
+    let mut graph = topology(&["a", "b", "c"], &[("a", "b", 10), ("b", "c", 10)]);
+    let (a, c) = (graph.find("a").unwrap(), graph.find("c").unwrap());
+    graph.remove(graph.find("b").unwrap());
+
+    assert_eq!(graph.find("b"), None);
+    assert_eq!(graph.find("a"), Some(a));
+    assert_eq!(graph.find("c"), Some(c));
+    assert_eq!(graph[c].addr, "c");
+    assert_eq!(route(&graph, "a", "c"), None);
+}
@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use entity::proto::Host;
+use federation::{graph::HostGraph, topology::TopologyStore};
+use prost_types::Timestamp;
+
+/// Store in a directory of its own, removed again when dropped
+struct Store {
+    store: Option<TopologyStore>,
+    path: PathBuf,
+}
+
+impl Store {
+    fn open(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("topology-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        let store = TopologyStore::open(path.to_str().unwrap()).unwrap();
+        Self {
+            store: Some(store),
+            path,
+        }
+    }
+
+    fn get(&self) -> &TopologyStore {
+        self.store.as_ref().unwrap()
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // sled keeps the directory locked until the store is closed
+        self.store.take();
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn host(addr: &str, seen: i64) -> Host {
+    Host {
+        addr: addr.to_string(),
+        last_seen: Some(Timestamp {
+            seconds: seen,
+            nanos: 0,
+        }),
+        ..Default::default()
+    }
+}
+
+fn weight(graph: &HostGraph, from: &str, to: &str) -> Option<u32> {
+    let edge = graph.find_edge(graph.find(from)?, graph.find(to)?)?;
+    Some(graph[edge])
+}
+
+fn addrs(graph: &HostGraph) -> Vec<String> {
+    let mut addrs = graph
+        .hosts()
+        .into_iter()
+        .map(|host| host.addr)
+        .collect::<Vec<_>>();
+    addrs.sort();
+    addrs
+}
+
+#[test]
+fn saved_topology_is_reloaded_and_compacted() {
+    let store = Store::open("reload");
+    let (a, b, c) = (host("a", 1), host("b", 2), host("c", 3));
+    for host in [&a, &b, &c] {
+        store.get().save_host(host).unwrap();
+    }
+    store.get().save_edge(&a, &b, 10).unwrap();
+    store.get().save_edge(&b, &c, 20).unwrap();
+    // Edge of a host that never got saved, as an interrupted compaction leaves it
+    store.get().save_edge(&a, &host("ghost", 4), 30).unwrap();
+
+    // We are `a`, what we say about ourself wins over what was stored
+    let me = Host {
+        version: 2,
+        ..host("a", 5)
+    };
+    let (mut graph, node) = store.get().load(me.clone()).unwrap();
+    assert_eq!(graph[node], me);
+    assert_eq!(addrs(&graph), ["a", "b", "c"]);
+    assert_eq!(graph[graph.find("b").unwrap()], b);
+    assert_eq!(graph[graph.find("c").unwrap()].last_seen, c.last_seen);
+    assert_eq!(weight(&graph, "a", "b"), Some(10));
+    assert_eq!(weight(&graph, "b", "c"), Some(20));
+    assert_eq!(graph.edge_count(), 2);
+
+    graph.remove(graph.find("c").unwrap());
+    store.get().compact(&graph).unwrap();
+    let (graph, _) = store.get().load(me).unwrap();
+    assert_eq!(addrs(&graph), ["a", "b"]);
+    assert_eq!(weight(&graph, "a", "b"), Some(10));
+    assert_eq!(graph.edge_count(), 1);
+}
+
+#[test]
+fn reopened_store_keeps_the_topology() {
+    let (a, b) = (host("a", 1), host("b", 2));
+    let mut store = Store::open("reopen");
+    store.get().save_host(&a).unwrap();
+    store.get().save_host(&b).unwrap();
+    store.get().save_edge(&b, &a, 7).unwrap();
+    store.store.take();
+    store.store = Some(TopologyStore::open(store.path.to_str().unwrap()).unwrap());
+
+    let (graph, _) = store.get().load(a).unwrap();
+    assert_eq!(graph[graph.find("b").unwrap()], b);
+    assert_eq!(weight(&graph, "b", "a"), Some(7));
+}
@@ -0,0 +1,317 @@
+use std::time::Duration;
+
+use entity::proto::{
+    packet::Packet as PacketType, AcknowledgePacket, ForwardPacket, HopSignature, Host, OnionAck,
+    Packet,
+};
+use federation::{
+    crypto::{self, Identity},
+    onion::{self, Back},
+    outbox::Outbox,
+    protocol,
+    trust::{Rejection, TrustStore},
+};
+
+fn key(identity: &Identity) -> String {
+    crypto::encode_key(&identity.public_key())
+}
+
+/// Packet of the origin to the receiver, signed, both speak this version
+fn forward(origin: &Identity, receiver: &Identity) -> ForwardPacket {
+    let host = |addr: &str, identity: &Identity| {
+        let mut host = Host {
+            addr: addr.to_string(),
+            public_key: identity.public_key(),
+            ..Default::default()
+        };
+        protocol::stamp(&mut host);
+        host
+    };
+    let mut packet = ForwardPacket {
+        path: vec![host("origin", origin), host("receiver", receiver)],
+        data: b"sealed".to_vec(),
+        id: b"id".to_vec(),
+        max_hops: 16,
+        ..Default::default()
+    };
+    origin.sign(&mut packet);
+    packet
+}
+
+fn ack(forward: ForwardPacket, success: bool, signer: Option<&Identity>) -> Packet {
+    let mut ack = AcknowledgePacket {
+        success,
+        forward: Some(forward),
+        ..Default::default()
+    };
+    if let Some(signer) = signer {
+        signer.sign_ack(&mut ack);
+    }
+    Packet {
+        packet: Some(PacketType::Acknowledge(ack)),
+    }
+}
+
+#[test]
+fn unsigned_forged_and_untrusted_packets_are_rejected() {
+    let (us, origin, stranger) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let trust = TrustStore::new(&us, &[key(&us), key(&origin)], &[], false, 1).unwrap();
+    let packet = forward(&origin, &us);
+    assert!(trust.verify(&packet).is_ok());
+
+    let unsigned = ForwardPacket {
+        signature: vec![],
+        ..packet.clone()
+    };
+    assert!(matches!(trust.verify(&unsigned), Err(Rejection::Unsigned)));
+    let mut anonymous = ForwardPacket {
+        id: vec![],
+        ..packet.clone()
+    };
+    origin.sign(&mut anonymous);
+    assert!(matches!(
+        trust.verify(&anonymous),
+        Err(Rejection::Malformed(_))
+    ));
+    let tampered = ForwardPacket {
+        data: b"forged".to_vec(),
+        ..packet.clone()
+    };
+    assert!(matches!(
+        trust.verify(&tampered),
+        Err(Rejection::BadSignature)
+    ));
+    let mut hop = packet.clone();
+    hop.hop_signatures.push(HopSignature {
+        key: origin.public_key(),
+        signature: vec![0; 64],
+    });
+    assert!(matches!(trust.verify(&hop), Err(Rejection::BadSignature)));
+
+    let untrusted = forward(&stranger, &us);
+    assert!(matches!(
+        trust.verify(&untrusted),
+        Err(Rejection::Untrusted(key)) if key == stranger.public_key()
+    ));
+    let mut endorsed = packet;
+    stranger.endorse(&mut endorsed);
+    assert!(matches!(
+        trust.verify(&endorsed),
+        Err(Rejection::Untrusted(_))
+    ));
+}
+
+#[test]
+fn only_the_receiver_can_acknowledge_success() {
+    let (us, receiver, forwarder) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let keys = [key(&us), key(&receiver), key(&forwarder)];
+    let trust = TrustStore::new(&us, &keys, &[], false, 1).unwrap();
+    let packet = forward(&us, &receiver);
+
+    let signed = ack(packet.clone(), true, Some(&receiver));
+    assert!(trust.verify_packet(&signed).is_ok());
+    // Any hop may nack, nobody but the receiver may turn it into a success
+    assert!(trust
+        .verify_packet(&ack(packet.clone(), false, None))
+        .is_ok());
+    assert!(matches!(
+        trust.verify_packet(&ack(packet.clone(), true, None)),
+        Err(Rejection::Unsigned)
+    ));
+    assert!(matches!(
+        trust.verify_packet(&ack(packet.clone(), true, Some(&forwarder))),
+        Err(Rejection::BadSignature)
+    ));
+    let Some(PacketType::Acknowledge(mut flipped)) = ack(packet, false, Some(&receiver)).packet
+    else {
+        unreachable!()
+    };
+    flipped.success = true;
+    let flipped = Packet {
+        packet: Some(PacketType::Acknowledge(flipped)),
+    };
+    assert!(matches!(
+        trust.verify_packet(&flipped),
+        Err(Rejection::BadSignature)
+    ));
+
+    // A receiver whose key we know signs, whatever it is said to speak
+    let mut downgraded = forward(&us, &receiver);
+    let last = downgraded.path.last_mut().unwrap();
+    last.version = 0;
+    last.capabilities = None;
+    us.sign(&mut downgraded);
+    assert!(matches!(
+        trust.verify_packet(&ack(downgraded, true, None)),
+        Err(Rejection::Unsigned)
+    ));
+
+    // Receivers from before signing can't sign, what they say is taken but isn't a receipt
+    let mut legacy = forward(&us, &receiver);
+    let last = legacy.path.last_mut().unwrap();
+    last.version = 0;
+    last.capabilities = None;
+    last.public_key.clear();
+    us.sign(&mut legacy);
+    let Some(PacketType::Acknowledge(unsigned)) = ack(legacy, true, None).packet else {
+        unreachable!()
+    };
+    let packet = Packet {
+        packet: Some(PacketType::Acknowledge(unsigned.clone())),
+    };
+    assert!(trust.verify_packet(&packet).is_ok());
+    assert!(!crypto::verify_receipt(&unsigned));
+}
+
+#[test]
+fn trust_is_inherited_up_to_the_depth() {
+    let (us, a, b, c) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let attestations = vec![
+        a.attest(b.public_key(), false),
+        b.attest(c.public_key(), false),
+    ];
+    let store = |inherit, depth| {
+        let trust = TrustStore::new(&us, &[key(&a)], &[], inherit, depth).unwrap();
+        trust.ingest(attestations.clone());
+        trust
+    };
+
+    let trust = store(true, 1);
+    assert!(trust.is_trusted(&a.public_key()) && trust.is_trusted(&b.public_key()));
+    assert!(!trust.is_trusted(&c.public_key()));
+    let trust = store(true, 2);
+    assert!(trust.is_trusted(&c.public_key()));
+    assert!(trust.trusted().contains(&(c.public_key(), false)));
+    let trust = store(false, 2);
+    assert!(!trust.is_trusted(&b.public_key()));
+}
+
+#[test]
+fn attestations_of_untrusted_vouchers_are_not_kept() {
+    let (us, a, b, stranger) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let trust = TrustStore::new(&us, &[key(&a)], &[], true, 2).unwrap();
+    // The chain comes backwards, b is reached once a vouched for it
+    trust.ingest(vec![
+        b.attest(stranger.public_key(), false),
+        a.attest(b.public_key(), false),
+    ]);
+    assert!(trust.is_trusted(&stranger.public_key()));
+
+    let trust = TrustStore::new(&us, &[key(&a)], &[], true, 2).unwrap();
+    let mut forged = a.attest(b.public_key(), false);
+    forged.subject = stranger.public_key();
+    trust.ingest(vec![
+        forged,
+        stranger.attest(b.public_key(), false),
+        b.attest(stranger.public_key(), false),
+    ]);
+    assert!(!trust.is_trusted(&b.public_key()) && !trust.is_trusted(&stranger.public_key()));
+    assert!(trust
+        .attestations()
+        .iter()
+        .all(|attestation| attestation.voucher == us.public_key()));
+}
+
+#[test]
+fn revocations_take_inherited_trust_back() {
+    let (us, a, b, c) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let trust = TrustStore::new(&us, &[key(&a)], &[], true, 2).unwrap();
+    trust.ingest(vec![
+        a.attest(b.public_key(), false),
+        b.attest(c.public_key(), false),
+    ]);
+    assert!(trust.is_trusted(&c.public_key()));
+
+    // Attestations of b are forgotten along with the trust in b
+    trust.ingest(vec![a.attest(b.public_key(), true)]);
+    assert!(!trust.is_trusted(&b.public_key()) && !trust.is_trusted(&c.public_key()));
+    assert!(trust
+        .attestations()
+        .iter()
+        .all(|attestation| attestation.voucher != b.public_key()));
+
+    // Our own revocation beats whoever vouches, and is shared
+    trust.revoke(&us, a.public_key()).unwrap();
+    assert!(!trust.is_trusted(&a.public_key()));
+    assert!(trust.attestations().iter().any(|attestation| {
+        attestation.voucher == us.public_key()
+            && attestation.subject == a.public_key()
+            && attestation.revoked
+    }));
+    assert!(trust.revoke(&us, us.public_key()).is_err());
+}
+
+#[test]
+fn configured_revocations_win_over_trust() {
+    let (us, a, b) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let trust = TrustStore::new(&us, &[key(&a), key(&b)], &[key(&b)], true, 1).unwrap();
+    assert!(trust.is_trusted(&a.public_key()));
+    assert!(!trust.is_trusted(&b.public_key()));
+    trust.ingest(vec![a.attest(b.public_key(), false)]);
+    assert!(!trust.is_trusted(&b.public_key()));
+    assert!(trust
+        .attestations()
+        .iter()
+        .any(|attestation| attestation.subject == b.public_key() && attestation.revoked));
+}
+
+#[test]
+fn only_the_last_hop_can_acknowledge_an_onion() {
+    let (us, receiver, relay) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let outbox = Outbox::new(None, Duration::from_secs(60), Duration::from_secs(1), 16).unwrap();
+    let (inbox, mut acks) = tokio::sync::broadcast::channel(4);
+    let sent = forward(&us, &receiver);
+    let mut arrived = |ack: OnionAck| {
+        onion::acknowledge(Back::Origin(Box::new(sent.clone())), ack, &outbox, &inbox);
+        let Ok(Packet {
+            packet: Some(PacketType::Acknowledge(ack)),
+        }) = acks.try_recv()
+        else {
+            unreachable!()
+        };
+        ack.success
+    };
+
+    let mut ack = OnionAck {
+        success: true,
+        ..Default::default()
+    };
+    assert!(!arrived(ack.clone()));
+    relay.sign_onion_ack(&sent.id, &mut ack);
+    assert!(!arrived(ack.clone()));
+    receiver.sign_onion_ack(b"other", &mut ack);
+    assert!(!arrived(ack.clone()));
+    receiver.sign_onion_ack(&sent.id, &mut ack);
+    assert!(arrived(ack));
+}